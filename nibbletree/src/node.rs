@@ -1,12 +1,13 @@
 use std::ops::{Index, IndexMut};
 use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
 use thin_vec::ThinVec;
 use bitvec::prelude::*;
 
 pub type Key = BitVec<usize, Lsb0>;
 pub type KeyRef<'a> = &'a BitSlice<usize, Lsb0>;
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct Bitmap {
     bitmap: BitmapType,
 }
@@ -75,10 +76,12 @@ impl Bitmap {
     }
 }
 
-#[derive(Debug)]
+/// Children are shared between clones of a node and only copied when they are modified,
+/// so cloning a tree is cheap and leaves the clone unaffected by later changes
+#[derive(Debug, Clone)]
 pub struct Node<T> {
     results: Option<ThinVec<T>>,
-    children: Option<ThinVec<Node<T>>>,
+    children: Option<ThinVec<Arc<Node<T>>>>,
     bitmap: Bitmap,
 }
 
@@ -94,8 +97,8 @@ impl<T> Default for Node<T> {
     }
 }
 
-fn children_mut<'a, T>(bitmap: &'a Bitmap, children: &'a mut Option<ThinVec<Node<T>>>) -> impl Iterator<Item = (Key, &'a mut Node<T>)> {
-    let children_iter = children.iter_mut().flat_map(|children| children.iter_mut());
+fn children_mut<'a, T: Clone>(bitmap: &'a Bitmap, children: &'a mut Option<ThinVec<Arc<Node<T>>>>) -> impl Iterator<Item = (Key, &'a mut Node<T>)> {
+    let children_iter = children.iter_mut().flat_map(|children| children.iter_mut()).map(Arc::make_mut);
     bitmap.children_bits().iter_ones().map(|x| x.view_bits::<Lsb0>().iter().take(RESULTS_BITS_END_NODE).collect()).zip(children_iter)
 }
 fn results_mut<'a, T>(bitmap: &'a Bitmap, results: &'a mut Option<ThinVec<T>>) -> impl Iterator<Item = (Key, &'a mut T)> {
@@ -117,9 +120,9 @@ fn from_index(mut index: usize) -> Key {
     key
 }
 
-impl<T: Debug + Send + Sync> Node<T> {
+impl<T: Debug + Clone + Send + Sync> Node<T> {
     fn children(&self) -> impl Iterator<Item = (Key, &Node<T>)> {
-        let children_iter = self.children.iter().flat_map(|children| children.iter());
+        let children_iter = self.children.iter().flat_map(|children| children.iter()).map(|child| &**child);
         self.bitmap.children_bits().iter_ones().map(|x| x.view_bits::<Lsb0>().iter().take(RESULTS_BITS_END_NODE).collect()).zip(children_iter)
     }
 
@@ -133,7 +136,7 @@ impl<T: Debug + Send + Sync> Node<T> {
         let nibble: usize = key.load_le();
         self.bitmap.children_bits()[nibble].then(|| {
             let vec_index = self.bitmap.children_bits()[..nibble].count_ones();
-            &self.children.as_ref().unwrap()[vec_index]
+            self.children.as_ref().unwrap()[vec_index].as_ref()
         })
     }
     fn get_child_mut(&mut self, key: KeyRef) -> Option<&mut Node<T>> {
@@ -141,7 +144,7 @@ impl<T: Debug + Send + Sync> Node<T> {
         let nibble: usize = key.load_le();
         self.bitmap.children_bits()[nibble].then(|| {
             let vec_index = self.bitmap.children_bits()[..nibble].count_ones();
-            &mut self.children.as_mut().unwrap()[vec_index]
+            Arc::make_mut(&mut self.children.as_mut().unwrap()[vec_index])
         })
     }
 
@@ -167,7 +170,9 @@ impl<T: Debug + Send + Sync> Node<T> {
                 self.bitmap.children_bits_mut().set(nibble, true);
                 let children = self.children.get_or_insert(Default::default());
                 let vec_index = self.bitmap.children_bits()[..nibble].count_ones();
-                children.insert(vec_index, Node::default());
+                // most nodes only have a few children, so growing the vector exactly saves memory
+                children.reserve_exact(1);
+                children.insert(vec_index, Arc::new(Node::default()));
             }
         }
         self.get_child_mut(key).unwrap()
@@ -184,6 +189,7 @@ impl<T: Debug + Send + Sync> Node<T> {
                 Some(std::mem::replace(&mut results[vec_index], value))
             } else {
                 self.bitmap.results_bits_mut().set(index, true);
+                results.reserve_exact(1);
                 results.insert(vec_index, value);
                 None
             }
@@ -265,12 +271,30 @@ impl<T: Debug + Send + Sync> Node<T> {
         let results_iter = self.results.iter_mut().flat_map(|values| values.iter_mut());
         let children_iter = self.children.iter_mut()
             .flat_map(|children| children.iter_mut())
-            .flat_map(|child| child.values_mut());
+            .flat_map(|child| Arc::make_mut(child).values_mut());
         let children_iter: Box<dyn Iterator<Item = &mut T> + Send + Sync + '_> = Box::new(children_iter);
         results_iter.chain(children_iter)
     }
 
-    fn keys_with_prefix<'a>(&'a self, prefix: Key) -> impl Iterator<Item = Key> + Send + Sync + '_ {
+    /// Approximate number of bytes allocated by the tree below this node,
+    /// not counting memory owned by the values themselves
+    pub fn heap_size(&self) -> usize {
+        // a ThinVec allocation starts with its length and capacity
+        let header = 2 * std::mem::size_of::<usize>();
+        let results = self.results.as_ref()
+            .map(|results| header + results.capacity() * std::mem::size_of::<T>())
+            .unwrap_or(0);
+        let children = self.children.as_ref()
+            .map(|children| {
+                let arc_size = 2 * std::mem::size_of::<usize>() + std::mem::size_of::<Node<T>>();
+                header + children.capacity() * std::mem::size_of::<Arc<Node<T>>>()
+                    + children.iter().map(|child| arc_size + child.heap_size()).sum::<usize>()
+            })
+            .unwrap_or(0);
+        results + children
+    }
+
+    fn keys_with_prefix<'a>(&'a self, prefix: Key) -> impl Iterator<Item = Key> + Send + Sync + 'a {
         let results_keys_iter = self.bitmap.results_keys_with_prefix(prefix.clone());
         let children_keys_iter = self.children()
             .flat_map(move |(child_key, child)| {
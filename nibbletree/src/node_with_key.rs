@@ -182,7 +182,7 @@ impl ToKey for Key {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NodeWithKey<K, T> {
     node: Node<T>,
     _key_type: PhantomData<K>,
@@ -197,7 +197,7 @@ impl<K, T> Default for NodeWithKey<K, T> {
     }
 }
 
-impl<K: FromKey + ToKey + Debug, T: Debug + Send + Sync> NodeWithKey<K, T> {
+impl<K: FromKey + ToKey + Debug, T: Debug + Clone + Send + Sync> NodeWithKey<K, T> {
     pub fn insert(&mut self, key: &K, value: T) -> Option<T> {
         self.node.insert(&key.to_key(), value)
     }
@@ -221,6 +221,10 @@ impl<K: FromKey + ToKey + Debug, T: Debug + Send + Sync> NodeWithKey<K, T> {
         self.node.values_mut()
     }
 
+    pub fn heap_size(&self) -> usize {
+        self.node.heap_size()
+    }
+
     pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
         self.node.keys()
             .map(K::from_key_owned)
@@ -189,3 +189,26 @@ fn matches(len: usize, max_key_len: usize, seed: u8) {
         assert_eq!(should_match, is_match);
     }
 }
+
+#[apply(random_tree_template)]
+fn clone_is_unaffected_by_changes(len: usize, max_key_len: usize, seed: u8) {
+    let (mut data, mut tree, test_keys) = random_tree(len, max_key_len, seed);
+    let snapshot = tree.clone();
+
+    let to_be_removed = data.split_off(data.len() / 2);
+    for (key, _) in &to_be_removed {
+        tree.remove(key);
+    }
+    for key in &test_keys {
+        tree.insert(key, 0);
+    }
+    for value in tree.values_mut() {
+        *value = 0;
+    }
+
+    data.extend(to_be_removed);
+    data.sort();
+    let mut out = snapshot.iter().map(|(k, v)| (k, *v)).collect::<Vec<_>>();
+    out.sort();
+    assert_eq!(data, out);
+}
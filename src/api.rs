@@ -1,14 +1,21 @@
-use crate::store::{NetQuery, Query, QueryLimits, QueryResult, Store};
+use crate::prefix_limit;
+use crate::stats;
+use crate::store::{
+    NetQuery, Query, QueryError, QueryLimits, QueryResult, Store, SubscriptionLagged, TableQuery,
+};
 use axum::body::Body;
+use axum::extract::rejection::QueryRejection;
 use axum::extract::FromRef;
-use axum::extract::{Query as AxumQuery, State};
-use axum::http::StatusCode;
+use axum::extract::{FromRequestParts, Query as AxumQuery, State};
+use axum::http::{header, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
 use axum::routing::get;
 use axum::Router;
 use futures_util::{FutureExt, StreamExt};
 use hickory_resolver::config::LookupIpStrategy;
-use hickory_resolver::TokioAsyncResolver;
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::{Name, TokioAsyncResolver};
 use ipnet::IpNet;
 use log::*;
 use regex::Regex;
@@ -49,7 +56,7 @@ pub struct ApiServerConfig {
 
 #[derive(Debug, Clone, Serialize)]
 pub enum ApiResult {
-    Route(QueryResult),
+    Route(Box<QueryResult>),
     ReverseDns {
         nexthop: IpAddr,
         nexthop_resolved: String,
@@ -64,28 +71,108 @@ pub enum ApiResult {
     },
 }
 
-// Make our own error that wraps `anyhow::Error`.
-struct AppError(anyhow::Error);
+/// Error of a request, returned as a JSON object with the kind of error and a message
+#[derive(Debug)]
+enum AppError {
+    /// The query string could not be parsed, e.g. because a parameter is missing
+    InvalidQuery(QueryRejection),
+    /// The query parameters are rejected by the store
+    Query(QueryError),
+    /// The name in the query is not a valid hostname
+    InvalidName(String),
+    /// The name in the query does not resolve to an address
+    NameNotFound(String),
+    /// The name in the query could not be resolved, e.g. because the DNS server did not respond
+    NameResolutionFailed(String, ResolveError),
+    Internal(anyhow::Error),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: String,
+}
+
+impl AppError {
+    fn status_and_kind(&self) -> (StatusCode, &'static str) {
+        match self {
+            AppError::InvalidQuery(rejection) => (rejection.status(), "invalid_query"),
+            AppError::Query(QueryError::InvalidAsPathRegex(_)) => {
+                (StatusCode::BAD_REQUEST, "invalid_as_path_regex")
+            }
+            AppError::Query(QueryError::InvalidAgeRange { .. }) => {
+                (StatusCode::BAD_REQUEST, "invalid_age_range")
+            }
+            AppError::Query(QueryError::HistoryDisabled) => {
+                (StatusCode::NOT_FOUND, "history_disabled")
+            }
+            AppError::InvalidName(_) => (StatusCode::BAD_REQUEST, "invalid_name"),
+            AppError::NameNotFound(_) => (StatusCode::NOT_FOUND, "name_not_found"),
+            AppError::NameResolutionFailed(_, e) => match e.kind() {
+                ResolveErrorKind::Timeout => {
+                    (StatusCode::GATEWAY_TIMEOUT, "name_resolution_timeout")
+                }
+                _ => (StatusCode::BAD_GATEWAY, "name_resolution_failed"),
+            },
+            AppError::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AppError::InvalidQuery(rejection) => write!(f, "{}", rejection.body_text()),
+            AppError::Query(e) => write!(f, "{}", e),
+            AppError::InvalidName(name) => write!(f, "invalid hostname {:?}", name),
+            AppError::NameNotFound(name) => write!(f, "{} does not resolve to an address", name),
+            AppError::NameResolutionFailed(name, e) => {
+                write!(f, "failed to resolve {}: {}", name, e)
+            }
+            AppError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
 
-// Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        let (status, error) = self.status_and_kind();
+        if status.is_server_error() {
+            warn!("request failed: {}", self);
+        }
+        let body = ErrorBody {
+            error,
+            message: self.to_string(),
+        };
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
+            status,
+            [(header::CONTENT_TYPE, "application/json")],
+            serde_json::to_string(&body).unwrap(),
         )
             .into_response()
     }
 }
 
-// This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
-// `Result<_, AppError>`. That way you don't need to do that manually.
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+impl From<QueryError> for AppError {
+    fn from(err: QueryError) -> Self {
+        AppError::Query(err)
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        AppError::InvalidQuery(rejection)
+    }
+}
+
+/// Query string parameters, which are rejected with a JSON error like other errors
+#[derive(FromRequestParts)]
+#[from_request(via(AxumQuery), rejection(AppError))]
+struct QueryParams<T>(T);
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        AppError::Internal(err)
     }
 }
 
@@ -95,6 +182,7 @@ struct AppState<T: Clone> {
     resolver: TokioAsyncResolver,
     community_lists: Arc<CompiledCommunitiesLists>,
     store: T,
+    shutdown: tokio::sync::watch::Receiver<bool>,
 }
 
 impl<T: Clone> FromRef<AppState<T>> for Arc<ApiServerConfig> {
@@ -115,7 +203,7 @@ impl<T: Clone> FromRef<AppState<T>> for Arc<CompiledCommunitiesLists> {
     }
 }
 
-async fn parse_or_resolve(resolver: &TokioAsyncResolver, name: String) -> anyhow::Result<IpNet> {
+async fn parse_or_resolve(resolver: &TokioAsyncResolver, name: String) -> Result<IpNet, AppError> {
     if let Ok(net) = name.parse() {
         return Ok(net);
     }
@@ -123,13 +211,20 @@ async fn parse_or_resolve(resolver: &TokioAsyncResolver, name: String) -> anyhow
         return Ok(addr.into());
     }
 
-    Ok(resolver
-        .lookup_ip(&format!("{}.", name))
-        .await?
-        .iter()
-        .next()
-        .ok_or(anyhow::anyhow!("Name resolution failure"))?
-        .into())
+    let Ok(fqdn) = Name::from_utf8(format!("{}.", name)) else {
+        return Err(AppError::InvalidName(name));
+    };
+    let lookup = match resolver.lookup_ip(fqdn).await {
+        Ok(lookup) => lookup,
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+            return Err(AppError::NameNotFound(name))
+        }
+        Err(e) => return Err(AppError::NameResolutionFailed(name, e)),
+    };
+    match lookup.iter().next() {
+        Some(addr) => Ok(addr.into()),
+        None => Err(AppError::NameNotFound(name)),
+    }
 }
 
 #[derive(Deserialize)]
@@ -157,7 +252,7 @@ struct CommunitiesList(HashMap<String, String>);
 impl CommunitiesList {
     fn compile(self) -> anyhow::Result<CompiledCommunitiesList> {
         let mut sorted = self.0.into_iter().collect::<Vec<_>>();
-        sorted.sort_by(|a, b| a.0.len().cmp(&b.0.len()));
+        sorted.sort_by_key(|a| a.0.len());
         Ok(CompiledCommunitiesList {
             regex_set: RegexSet::new(sorted.iter().map(|(regex, _desc)| format!("^{}$", regex)))?,
             list: sorted
@@ -173,7 +268,7 @@ struct CompiledCommunitiesList {
     list: Vec<(Regex, String)>,
 }
 impl CompiledCommunitiesList {
-    fn lookup(&self, community: &str) -> Option<Cow<str>> {
+    fn lookup(&self, community: &str) -> Option<Cow<'_, str>> {
         self.regex_set
             .matches(community)
             .iter()
@@ -201,31 +296,43 @@ impl CompiledCommunitiesList {
     }
 }
 
+async fn resolve_net_query(
+    resolver: &TokioAsyncResolver,
+    net_query: NetQuery<String>,
+) -> Result<NetQuery, AppError> {
+    Ok(match net_query {
+        NetQuery::Contains(name) => NetQuery::Contains(parse_or_resolve(resolver, name).await?),
+        NetQuery::MostSpecific(name) => {
+            NetQuery::MostSpecific(parse_or_resolve(resolver, name).await?)
+        }
+        NetQuery::Exact(name) => NetQuery::Exact(parse_or_resolve(resolver, name).await?),
+        NetQuery::OrLonger(name) => NetQuery::OrLonger(parse_or_resolve(resolver, name).await?),
+    })
+}
+
 async fn query<T: Store>(
     State(AppState {
         cfg,
         resolver,
         store,
         community_lists,
+        ..
     }): State<AppState<T>>,
-    AxumQuery(query): AxumQuery<Query<String>>,
+    QueryParams(query): QueryParams<Query<String>>,
 ) -> Result<impl IntoResponse, AppError> {
     trace!("request: {}", serde_json::to_string_pretty(&query).unwrap());
 
-    let net_query = match query.net_query {
-        NetQuery::Contains(name) => NetQuery::Contains(parse_or_resolve(&resolver, name).await?),
-        NetQuery::MostSpecific(name) => {
-            NetQuery::MostSpecific(parse_or_resolve(&resolver, name).await?)
-        }
-        NetQuery::Exact(name) => NetQuery::Exact(parse_or_resolve(&resolver, name).await?),
-        NetQuery::OrLonger(name) => NetQuery::OrLonger(parse_or_resolve(&resolver, name).await?),
-    };
+    let net_query = resolve_net_query(&resolver, query.net_query).await?;
 
     let mut query = Query {
         table_query: query.table_query,
         net_query,
         limits: query.limits,
         as_path_regex: query.as_path_regex,
+        route_leak: query.route_leak,
+        min_age: query.min_age,
+        max_age: query.max_age,
+        at: query.at,
     };
 
     let mut limits = query.limits.take().unwrap_or(cfg.query_limits.clone());
@@ -243,14 +350,14 @@ async fn query<T: Store>(
     let mut have_large_community = HashSet::new();
 
     let stream = store
-        .get_routes(query)
+        .get_routes(query)?
         .flat_map_unordered(None, move |route| {
             let futures = futures_util::stream::FuturesUnordered::<
                 Pin<Box<dyn std::future::Future<Output = Option<ApiResult>> + Send>>,
             >::new();
 
             futures.push(Box::pin(futures_util::future::ready(Some(
-                ApiResult::Route(route.clone()),
+                ApiResult::Route(Box::new(route.clone())),
             ))));
 
             if let Some(nexthop) = route.attrs.nexthop {
@@ -270,7 +377,7 @@ async fn query<T: Store>(
                 }
             }
             if let Some(asn_dns_zone) = &cfg.asn_dns_zone {
-                for asn in route.attrs.as_path.into_iter().flat_map(|x| x) {
+                for asn in route.attrs.as_path.into_iter().flatten() {
                     if have_asn.insert(asn) {
                         let resolver = resolver.clone();
                         let asn_dns_zone = asn_dns_zone.clone();
@@ -285,10 +392,7 @@ async fn query<T: Store>(
                                             .next()
                                             .and_then(|data| std::str::from_utf8(data).ok())
                                             .and_then(|s| {
-                                                s.split(" | ")
-                                                    .skip(4)
-                                                    .next()
-                                                    .map(|name| name.to_string())
+                                                s.split(" | ").nth(4).map(|name| name.to_string())
                                             })
                                     })
                                 })
@@ -297,7 +401,7 @@ async fn query<T: Store>(
                     }
                 }
             }
-            for community in route.attrs.communities.into_iter().flat_map(|x| x) {
+            for community in route.attrs.communities.into_iter().flatten() {
                 if have_community.insert(community) {
                     let community_str = format!("{}:{}", community.0, community.1);
                     if let Some(lookup) = community_lists.regular.lookup(&community_str) {
@@ -310,7 +414,7 @@ async fn query<T: Store>(
                     }
                 }
             }
-            for large_community in route.attrs.large_communities.into_iter().flat_map(|x| x) {
+            for large_community in route.attrs.large_communities.into_iter().flatten() {
                 if have_large_community.insert(large_community) {
                     let large_community_str = format!(
                         "{}:{}:{}",
@@ -329,7 +433,7 @@ async fn query<T: Store>(
 
             futures
         })
-        .filter_map(|x| futures_util::future::ready(x))
+        .filter_map(futures_util::future::ready)
         .map(|result| {
             let json = serde_json::to_string(&result).unwrap();
             Ok::<_, Infallible>(format!("{}\n", json))
@@ -338,11 +442,72 @@ async fn query<T: Store>(
     Ok(Body::from_stream(stream))
 }
 
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// Prefix, address or hostname, resolved like in route queries
+    net: String,
+    #[serde(flatten)]
+    table_query: Option<TableQuery>,
+}
+
+async fn history<T: Store>(
+    State(AppState {
+        resolver, store, ..
+    }): State<AppState<T>>,
+    QueryParams(query): QueryParams<HistoryQuery>,
+) -> Result<Response, AppError> {
+    let net = parse_or_resolve(&resolver, query.net).await?;
+    match store.get_history(net, query.table_query) {
+        Some(events) => Ok(serde_json::to_string(&events).unwrap().into_response()),
+        None => Err(AppError::Query(QueryError::HistoryDisabled)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscribeQuery {
+    #[serde(flatten)]
+    table_query: Option<TableQuery>,
+    #[serde(flatten)]
+    net_query: NetQuery<String>,
+}
+
+async fn subscribe<T: Store>(
+    State(AppState {
+        resolver,
+        store,
+        mut shutdown,
+        ..
+    }): State<AppState<T>>,
+    QueryParams(query): QueryParams<SubscribeQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let net_query = resolve_net_query(&resolver, query.net_query).await?;
+    let events = store
+        .subscribe(net_query, query.table_query)
+        .map(|event| match event {
+            Ok(event) => {
+                let json = serde_json::to_string(&event).unwrap();
+                Event::default().event("route").data(json)
+            }
+            Err(SubscriptionLagged) => Event::default().event("lagged").data(""),
+        })
+        .take_until(async move { shutdown.wait_for(|shutdown| *shutdown).await.map(|_| ()) })
+        .map(Ok::<_, Infallible>);
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
 async fn routers<T: Store>(State(AppState { store, .. }): State<AppState<T>>) -> impl IntoResponse {
     serde_json::to_string(&store.get_routers()).unwrap()
 }
 
-async fn make_api<T: Store>(cfg: ApiServerConfig, store: T) -> anyhow::Result<Router> {
+async fn stats<T: Store>(State(AppState { store, .. }): State<AppState<T>>) -> impl IntoResponse {
+    serde_json::to_string(&store.get_stats()).unwrap()
+}
+
+async fn make_api<T: Store>(
+    cfg: ApiServerConfig,
+    store: T,
+    shutdown: tokio::sync::watch::Receiver<bool>,
+) -> anyhow::Result<Router> {
     let resolver = {
         let (rcfg, mut ropts) = hickory_resolver::system_conf::read_system_conf()?;
         ropts.ip_strategy = LookupIpStrategy::Ipv6thenIpv4; // strange people set strange default settings
@@ -361,25 +526,39 @@ async fn make_api<T: Store>(cfg: ApiServerConfig, store: T) -> anyhow::Result<Ro
     Ok(Router::new()
         .route("/query", get(query::<T>))
         .route("/routers", get(routers::<T>))
+        .route("/history", get(history::<T>))
+        .route("/subscribe", get(subscribe::<T>))
+        .route("/stats", get(stats::<T>))
         .with_state(AppState {
             cfg: Arc::new(cfg),
             resolver,
             store,
             community_lists,
+            shutdown,
         }))
 }
 
 /// This handler serializes the metrics into a string for Prometheus to scrape
-pub async fn get_metrics() -> (StatusCode, String) {
-    match autometrics::encode_global_metrics() {
+pub async fn get_metrics<T: Store>(store: T) -> (StatusCode, String) {
+    let routers = store.get_routers();
+    let store_stats = store.get_stats();
+    let metrics = autometrics::encode_global_metrics()
+        .map_err(|err| format!("{:?}", err))
+        .and_then(|metrics| {
+            let prefix_metrics =
+                prefix_limit::encode_metrics(&routers).map_err(|err| format!("{:?}", err))?;
+            let stats_metrics =
+                stats::encode_metrics(&store_stats).map_err(|err| format!("{:?}", err))?;
+            Ok(metrics + &prefix_metrics + &stats_metrics)
+        });
+    match metrics {
         Ok(metrics) => (StatusCode::OK, metrics),
-        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", err)),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err),
     }
 }
 
 #[cfg(feature = "embed-static")]
 async fn static_path(axum::extract::Path(path): axum::extract::Path<String>) -> impl IntoResponse {
-    use axum::http::header;
     use axum::http::header::HeaderValue;
 
     let path = path.trim_start_matches('/');
@@ -414,8 +593,11 @@ pub async fn run_api_server<T: Store>(
     }
 
     router = router
-        .nest("/api", make_api(cfg.clone(), store).await?)
-        .route("/metrics", get(get_metrics));
+        .nest(
+            "/api",
+            make_api(cfg.clone(), store.clone(), shutdown.clone()).await?,
+        )
+        .route("/metrics", get(move || get_metrics(store)));
 
     let make_service = router.into_make_service();
 
@@ -1,11 +1,18 @@
-use crate::bgpdumper::BgpDumper;
-use crate::store::{Client, RouteState, Store, TableSelector};
+use crate::bgpdumper::{
+    send_notification, BgpDumper, CAP_EXTENDED_NEXTHOP, CAP_ROLE, CEASE_MAX_PREFIXES,
+    NOTIFICATION_CEASE,
+};
+use crate::import_filter::ImportFilter;
+use crate::prefix_limit::{PrefixLimit, PrefixLimitAction, PrefixLimiter};
+use crate::store::{BgpRole, Client, RouteState, RouteUpdate, Session, Store, TableSelector};
+use chrono::Utc;
 use futures_util::future::join_all;
 use futures_util::{pin_mut, StreamExt};
 use log::*;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroU16;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 use zettabgp::prelude::BgpNotificationMessage;
@@ -33,11 +40,54 @@ pub async fn run_peer(
         ]));
     }
 
+    let transport_mode = match client_addr.ip().to_canonical() {
+        IpAddr::V4(_) => BgpTransportMode::IPv4,
+        IpAddr::V6(_) => BgpTransportMode::IPv6,
+    };
+
+    if cfg.hold_time == 1 || cfg.hold_time == 2 {
+        anyhow::bail!("hold time must be zero or at least three seconds");
+    }
     let mut dumper = BgpDumper::new(
-        BgpSessionParams::new(cfg.asn, 180, BgpTransportMode::IPv4, cfg.router_id, caps),
+        BgpSessionParams::new(cfg.asn, cfg.hold_time, transport_mode, cfg.router_id, caps),
         stream,
     );
+    dumper.keepalive_interval = cfg.keepalive_interval.map(NonZeroU16::get);
+    if let Some(fqdn) = &cfg.fqdn {
+        dumper.advertise_fqdn(fqdn);
+    }
+    if transport_mode == BgpTransportMode::IPv6 {
+        // IPv4 unicast routes with IPv6 next hops (RFC 8950)
+        dumper
+            .extra_caps
+            .push((CAP_EXTENDED_NEXTHOP, vec![0, 1, 0, 1, 0, 2]));
+    }
+    if let Some(role) = cfg.role {
+        dumper.extra_caps.push((CAP_ROLE, role.to_capability()));
+    }
     let open_message = dumper.start_active().await?;
+    let peer_role = dumper
+        .peer_capability(CAP_ROLE)
+        .and_then(BgpRole::from_capability);
+    if let (Some(role), Some(peer_role)) = (cfg.role, peer_role) {
+        if peer_role != role.counterpart() {
+            warn!(
+                "{}: role mismatch, we are {:?} and peer is {:?}",
+                client_addr, role, peer_role
+            );
+        }
+    }
+    let peer_asn = open_message
+        .caps
+        .iter()
+        .find_map(|cap| match cap {
+            BgpCapability::CapASN32(asn) => Some(*asn),
+            _ => None,
+        })
+        .unwrap_or(open_message.as_num);
+    let hold_time = dumper.params.hold_time;
+    let keepalive_interval = dumper.negotiated_keepalive_interval();
+    let write = dumper.write.clone();
     let stream = dumper.lifecycle();
     pin_mut!(stream);
     let client_name = cfg
@@ -45,7 +95,7 @@ pub async fn run_peer(
         .or(open_message.caps.iter().find_map(|x| {
             if let BgpCapability::CapFQDN(hostname, domainname) = x {
                 let mut name = hostname.to_string();
-                if domainname != "" {
+                if !domainname.is_empty() {
                     name = format!("{}.{}", name, domainname);
                 }
                 Some(name)
@@ -61,25 +111,45 @@ pub async fn run_peer(
             Client {
                 client_name,
                 router_id: open_message.router_id,
+                bgp_session: Some(Session {
+                    peer_asn: Some(peer_asn),
+                    local_role: cfg.role,
+                    peer_role,
+                    hold_time: Some(hold_time),
+                    keepalive_interval: Some(keepalive_interval),
+                    local_fqdn: cfg.fqdn,
+                    established: Some(Utc::now()),
+                    ..Default::default()
+                }),
+                ..Default::default()
             },
+            cfg.prefix_limit.clone(),
         )
         .await;
+    let limiter = PrefixLimiter::new(client_addr, cfg.prefix_limit.unwrap_or_default(), &store);
     loop {
-        let update = match stream.next().await {
+        let (update, raw) = match stream.next().await {
             Some(Ok(update)) => update,
             Some(Err(Ok(notification))) => break Ok(notification),
             Some(Err(Err(e))) => anyhow::bail!(e),
             None => panic!(),
         };
-        store
-            .insert_bgp_update(
-                TableSelector::LocRib {
-                    from_client: client_addr,
-                    route_state: cfg.route_state,
-                },
-                update,
-            )
-            .await;
+        let table = TableSelector::LocRib {
+            from_client: client_addr,
+            route_state: cfg.route_state,
+        };
+        let update = RouteUpdate::from_bgp_update(update, raw, &cfg.import_filter);
+        let end_of_rib = update.end_of_rib;
+        if let Some(PrefixLimitAction::Teardown) = limiter
+            .apply(&store, table.clone(), update, Utc::now())
+            .await
+        {
+            let _ = send_notification(&write, NOTIFICATION_CEASE, CEASE_MAX_PREFIXES).await;
+            anyhow::bail!("prefix limit exceeded");
+        }
+        if let Some(afi) = end_of_rib {
+            store.end_of_rib(table, afi).await;
+        }
     }
 }
 
@@ -90,6 +160,22 @@ pub struct PeerConfig {
     pub name_override: Option<String>,
     pub route_state: RouteState,
     pub add_path: bool,
+    /// BGP role advertised to the peer (RFC 9234)
+    pub role: Option<BgpRole>,
+    /// Hold time advertised to the peer, the lower one of both sides is used
+    #[serde(default = "default_hold_time")]
+    pub hold_time: u16,
+    /// Interval between keepalives, defaults to a third of the negotiated hold time
+    pub keepalive_interval: Option<NonZeroU16>,
+    /// Hostname advertised to the peer in the hostname capability
+    pub fqdn: Option<String>,
+    pub prefix_limit: Option<PrefixLimit>,
+    #[serde(default)]
+    pub import_filter: ImportFilter,
+}
+
+fn default_hold_time() -> u16 {
+    180
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -98,6 +184,9 @@ pub struct BgpCollectorConfig {
     #[serde(default)]
     pub peers: HashMap<IpAddr, PeerConfig>,
     pub default_peer_config: Option<PeerConfig>,
+    /// Evaluated for the routes of all peers, after the import filter of the peer config
+    #[serde(default)]
+    pub import_filter: ImportFilter,
 }
 
 pub async fn run(
@@ -113,7 +202,8 @@ pub async fn run(
                 let (io, client_addr) = new_conn?;
                 info!("connected {:?}", client_addr);
 
-                if let Some(peer_cfg) = cfg.peers.get(&client_addr.ip()).or(cfg.default_peer_config.as_ref()).cloned() {
+                if let Some(mut peer_cfg) = cfg.peers.get(&client_addr.ip()).or(cfg.default_peer_config.as_ref()).cloned() {
+                    peer_cfg.import_filter = peer_cfg.import_filter.then(&cfg.import_filter);
                     let store = store.clone();
                     let mut shutdown = shutdown.clone();
                     running_tasks.push(tokio::spawn(async move {
@@ -6,6 +6,7 @@ use futures_util::Stream;
 use futures_util::StreamExt;
 use log::*;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
@@ -15,11 +16,75 @@ use tokio_util::codec::FramedRead;
 use tokio_util::codec::LengthDelimitedCodec;
 use zettabgp::prelude::*;
 
+use crate::raw_update::RawUpdate;
+
+/// Maximum message size without the extended message capability
+const MAX_MESSAGE_LEN: usize = 4096;
+/// Maximum message size with the extended message capability (RFC 8654)
+const MAX_EXTENDED_MESSAGE_LEN: usize = 65535;
+
+const OPT_PARAM_CAPABILITIES: u8 = 2;
+pub const CAP_EXTENDED_NEXTHOP: u8 = 5;
+pub const CAP_EXTENDED_MESSAGE: u8 = 6;
+pub const CAP_ROLE: u8 = 9;
+pub const CAP_FQDN: u8 = 73;
+
+pub const NOTIFICATION_HOLD_TIMER_EXPIRED: u8 = 4;
+pub const NOTIFICATION_CEASE: u8 = 6;
+/// Cease subcode for exceeding the maximum number of prefixes (RFC 4486)
+pub const CEASE_MAX_PREFIXES: u8 = 1;
+
+/// Capability codes and values from the optional parameters of an OPEN message body.
+///
+/// zettabgp silently drops capabilities it does not know about, so this is
+/// used to look at them directly.
+pub fn raw_capabilities(open: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut caps = vec![];
+    let mut params = match open.get(9) {
+        Some(&len) => open.get(10..10 + len as usize).unwrap_or(&[]),
+        None => return caps,
+    };
+    while let [param_type, len, rest @ ..] = params {
+        let (value, rest) = rest.split_at(std::cmp::min(*len as usize, rest.len()));
+        params = rest;
+        if *param_type != OPT_PARAM_CAPABILITIES {
+            continue;
+        }
+        let mut value = value;
+        while let [code, len, rest @ ..] = value {
+            let (data, rest) = rest.split_at(std::cmp::min(*len as usize, rest.len()));
+            value = rest;
+            caps.push((*code, data));
+        }
+    }
+    caps
+}
+
+/// Sends a NOTIFICATION message without data, e.g. before closing the session
+pub async fn send_notification(
+    write: &Mutex<OwnedWriteHalf>,
+    code: u8,
+    subcode: u8,
+) -> std::io::Result<()> {
+    let mut buf = [255_u8; 21];
+    buf[16..18].copy_from_slice(&21u16.to_be_bytes());
+    buf[18] = 3; //notification
+    buf[19] = code;
+    buf[20] = subcode;
+    write.lock().await.write_all(&buf).await
+}
+
 pub struct BgpDumper {
     pub params: BgpSessionParams,
     pub read: FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
     pub write: Arc<Mutex<OwnedWriteHalf>>,
     pub stop_keepalives: Option<oneshot::Sender<()>>,
+    /// Capabilities zettabgp can not encode, advertised in addition to `params.caps`
+    pub extra_caps: Vec<(u8, Vec<u8>)>,
+    /// All capabilities received from the peer, including the ones zettabgp ignores
+    pub peer_caps: Vec<(u8, Vec<u8>)>,
+    /// Interval between keepalives, used if shorter than a third of the negotiated hold time
+    pub keepalive_interval: Option<u16>,
 }
 
 impl BgpDumper {
@@ -33,20 +98,57 @@ impl BgpDumper {
                 .length_field_type::<u16>()
                 .length_adjustment(0)
                 .num_skip(0)
-                .max_frame_length(4096)
+                .max_frame_length(MAX_MESSAGE_LEN)
                 .new_read(read),
             stop_keepalives: None,
+            extra_caps: vec![(CAP_EXTENDED_MESSAGE, vec![])],
+            peer_caps: vec![],
+            keepalive_interval: None,
         }
     }
+    /// Advertises the hostname capability (draft-walton-bgp-hostname-capability)
+    ///
+    /// zettabgp encodes the length of the hostname as the capability length,
+    /// so it is sent as an extra capability instead.
+    pub fn advertise_fqdn(&mut self, fqdn: &str) {
+        let (hostname, domainname) = fqdn.split_once('.').unwrap_or((fqdn, ""));
+        let mut data = vec![];
+        for part in [hostname, domainname] {
+            let part = &part.as_bytes()[..std::cmp::min(part.len(), 64)];
+            data.push(part.len() as u8);
+            data.extend_from_slice(part);
+        }
+        self.extra_caps.push((CAP_FQDN, data));
+    }
     pub async fn start_active(&mut self) -> Result<BgpOpenMessage, BgpError> {
         let mut bom = self.params.open_message();
-        let mut buf = [255 as u8; 4096];
-        let messagelen = match bom.encode_to(&self.params, &mut buf[19..]) {
+        let mut buf = [255_u8; MAX_MESSAGE_LEN];
+        let mut messagelen = match bom.encode_to(&self.params, &mut buf[19..]) {
             Err(e) => {
                 return Err(e);
             }
             Ok(sz) => sz,
         };
+        // Each capability goes into its own optional parameter, appended to the ones zettabgp wrote
+        for (code, data) in &self.extra_caps {
+            let param = [
+                &[
+                    OPT_PARAM_CAPABILITIES,
+                    data.len() as u8 + 2,
+                    *code,
+                    data.len() as u8,
+                ][..],
+                data,
+            ]
+            .concat();
+            let start = 19 + messagelen;
+            if start + param.len() > buf.len() || buf[19 + 9] as usize + param.len() > 255 {
+                return Err(BgpError::insufficient_buffer_size());
+            }
+            buf[start..start + param.len()].copy_from_slice(&param);
+            buf[19 + 9] += param.len() as u8;
+            messagelen += param.len();
+        }
         let blen = self
             .params
             .prepare_message_buf(&mut buf, BgpMessageType::Open, messagelen)?;
@@ -57,18 +159,56 @@ impl BgpDumper {
         }
         bom.decode_from(&self.params, &buf[..])?;
         debug!("{:?}", bom);
-        self.params.hold_time = bom.hold_time;
+        self.peer_caps = raw_capabilities(&buf)
+            .into_iter()
+            .map(|(code, data)| (code, data.to_vec()))
+            .collect();
+        if self.negotiated(CAP_EXTENDED_MESSAGE) {
+            debug!("extended messages negotiated");
+            self.read
+                .decoder_mut()
+                .set_max_frame_length(MAX_EXTENDED_MESSAGE_LEN);
+        }
+        // a hold time of zero disables keepalives and the hold timer (RFC 4271, section 4.2)
+        if bom.hold_time == 1 || bom.hold_time == 2 {
+            return Err(BgpError::static_str("Unacceptable hold time"));
+        }
+        self.params.hold_time = std::cmp::min(self.params.hold_time, bom.hold_time);
         self.params.caps = bom.caps.clone();
         self.params.check_caps();
         Ok(bom)
     }
-    fn start_keepalives(&self) -> oneshot::Sender<()> {
+    /// Whether both sides advertised the capability
+    pub fn negotiated(&self, code: u8) -> bool {
+        self.extra_caps.iter().any(|(c, _)| *c == code)
+            && self.peer_caps.iter().any(|(c, _)| *c == code)
+    }
+    /// Value of a capability received from the peer
+    pub fn peer_capability(&self, code: u8) -> Option<&[u8]> {
+        self.peer_caps
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, data)| &data[..])
+    }
+    /// Keepalive interval for the negotiated hold time, zero if no keepalives are sent
+    pub fn negotiated_keepalive_interval(&self) -> u16 {
+        let max = self.params.hold_time / 3;
+        match self.keepalive_interval {
+            Some(interval) => std::cmp::min(interval, max),
+            None => max,
+        }
+    }
+    fn start_keepalives(&self) -> Option<oneshot::Sender<()>> {
+        let interval = self.negotiated_keepalive_interval();
+        if interval == 0 {
+            return None;
+        }
         let (tx, mut rx) = oneshot::channel();
-        let slp = std::time::Duration::new((self.params.hold_time / 3) as u64, 0);
+        let slp = Duration::from_secs(interval as u64);
         let write = self.write.clone();
         tokio::task::spawn(async move {
-            let mut buf = [255 as u8; 19];
-            buf[0..16].clone_from_slice(&[255 as u8; 16]);
+            let mut buf = [255_u8; 19];
+            buf[0..16].clone_from_slice(&[255_u8; 16]);
             buf[16] = 0;
             buf[17] = 19;
             buf[18] = 4; //keepalive
@@ -82,7 +222,7 @@ impl BgpDumper {
                 }
             }
         });
-        tx
+        Some(tx)
     }
     async fn next_message(&mut self) -> Result<(BgpMessageType, BytesMut), BgpError> {
         let mut buf = self
@@ -97,13 +237,31 @@ impl BgpDumper {
     }
     pub fn lifecycle(
         mut self,
-    ) -> impl Stream<Item = Result<BgpUpdateMessage, Result<BgpNotificationMessage, BgpError>>> + Send
-    {
-        self.stop_keepalives = Some(self.start_keepalives());
+    ) -> impl Stream<
+        Item = Result<(BgpUpdateMessage, RawUpdate), Result<BgpNotificationMessage, BgpError>>,
+    > + Send {
+        self.stop_keepalives = self.start_keepalives();
+        let hold_time = Duration::from_secs(self.params.hold_time as u64);
+        // zettabgp decodes the NLRI and NEXT_HOP fields outside of the
+        // multiprotocol attributes according to the transport mode, but they
+        // are IPv4 regardless of the transport (RFC 4271)
+        let mut update_params = self.params.clone();
+        update_params.peer_mode = BgpTransportMode::IPv4;
 
         async_stream::try_stream! {
             loop {
-                let (msgtype, buf) = self.next_message().await.map_err(Err)?;
+                let next_message = if hold_time.is_zero() {
+                    Some(self.next_message().await)
+                } else {
+                    tokio::time::timeout(hold_time, self.next_message()).await.ok()
+                };
+                let (msgtype, buf) = match next_message {
+                    Some(res) => res.map_err(Err)?,
+                    None => {
+                        let _ = send_notification(&self.write, NOTIFICATION_HOLD_TIMER_EXPIRED, 0).await;
+                        Err(Err(BgpError::static_str("Hold timer expired")))?
+                    }
+                };
                 if msgtype == BgpMessageType::Keepalive {
                     continue;
                 }
@@ -119,12 +277,12 @@ impl BgpDumper {
                     }
                     BgpMessageType::Update => {
                         let mut msgupdate = BgpUpdateMessage::new();
-                        if let Err(e) = msgupdate.decode_from(&self.params, &buf[..]) {
+                        if let Err(e) = msgupdate.decode_from(&update_params, &buf[..]) {
                             warn!("BGP update decode error: {:?}", e);
                             warn!("{:x?}", &buf[..]);
                             continue;
                         }
-                        yield msgupdate;
+                        yield (msgupdate, RawUpdate::parse(&buf[..], self.params.has_as32bit));
                     }
                 }
             }
@@ -1,20 +1,54 @@
-use crate::store::{Client, RouteState, Session, SessionId, Store, TableSelector};
+use crate::bgpdumper::{raw_capabilities, CAP_ROLE};
+use crate::bmp_relay::{BmpRelay, RelayTargetConfig};
+use crate::import_filter::ImportFilter;
+use crate::prefix_limit::{PrefixLimit, PrefixLimiter};
+use crate::raw_update::RawUpdate;
+use crate::store::{
+    BgpRole, Client, ClientTermination, RouteState, RouteUpdate, Session, SessionId, Store,
+    TableSelector,
+};
 use bitvec::prelude::Msb0;
 use bitvec::view::BitView;
+use chrono::{DateTime, Utc};
 use futures_util::future::join_all;
 use futures_util::{pin_mut, StreamExt};
+use ipnet::IpNet;
 use log::*;
+use nibbletree::Node;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncRead;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
 use tokio_util::codec::length_delimited::LengthDelimitedCodec;
 use zettabgp::bmp::prelude::{
     BmpMessagePeerDown, BmpMessagePeerHeader, BmpMessageRouteMonitoring, BmpMessageTermination,
 };
 use zettabgp::bmp::BmpMessage;
 
+/// Backoff between attempts to connect to routers and relay targets
+pub(crate) const MIN_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+pub(crate) const MAX_RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
+/// Connection attempts taking longer than this are considered failed
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects to a router or relay target, giving up after [`CONNECT_TIMEOUT`]
+pub(crate) async fn connect(target: SocketAddr) -> std::io::Result<TcpStream> {
+    tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(target))
+        .await
+        .unwrap_or_else(|_| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "connection timed out",
+            ))
+        })
+}
+
 fn table_selector_for_peer(
     client_addr: SocketAddr,
     peer: &BmpMessagePeerHeader,
@@ -40,6 +74,9 @@ async fn process_route_monitoring(
     store: &impl Store,
     client_addr: SocketAddr,
     rm: BmpMessageRouteMonitoring,
+    raw: RawUpdate,
+    import_filter: &ImportFilter,
+    limiter: &PrefixLimiter,
 ) {
     let session = match table_selector_for_peer(client_addr, &rm.peer) {
         Some(session) => session,
@@ -53,29 +90,183 @@ async fn process_route_monitoring(
         }
     };
 
-    store.insert_bgp_update(session, rm.update).await;
+    let timestamp = peer_header_time(&rm.peer);
+    let update = RouteUpdate::from_bgp_update(rm.update, raw, import_filter);
+    let end_of_rib = update.end_of_rib;
+    // exceeding a limit with the Teardown action ends the client connection
+    limiter
+        .apply(store, session.clone(), update, timestamp)
+        .await;
+    if let Some(afi) = end_of_rib {
+        store.end_of_rib(session, afi).await;
+    }
+}
+
+/// Time from the per-peer header, or the current time if the router did not set it
+fn peer_header_time(peer: &BmpMessagePeerHeader) -> DateTime<Utc> {
+    // seconds and microseconds, decoded as a single number
+    let secs = (peer.timestamp >> 32) as i64;
+    let micros = (peer.timestamp & 0xffff_ffff) as u32;
+    match DateTime::from_timestamp(secs, micros.saturating_mul(1000)) {
+        Some(time) if peer.timestamp != 0 => time,
+        _ => Utc::now(),
+    }
+}
+
+/// Body of the BGP UPDATE message in a Route Monitoring frame
+fn route_monitoring_update(frame: &[u8]) -> &[u8] {
+    // common header, per-peer header and BGP message header
+    const UPDATE_START: usize = 6 + 42 + 19;
+    let len = match frame.get(UPDATE_START - 3..UPDATE_START - 1) {
+        Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+        None => return &[],
+    };
+    frame
+        .get(UPDATE_START..UPDATE_START - 19 + len)
+        .unwrap_or(&[])
+}
+
+/// Bodies of the sent and received OPEN messages in a Peer Up frame
+fn peer_up_opens(frame: &[u8]) -> Option<(&[u8], &[u8])> {
+    fn open(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+        let len = u16::from_be_bytes(buf.get(16..18)?.try_into().unwrap()) as usize;
+        Some((buf.get(19..len)?, &buf[len..]))
+    }
+    // common header, per-peer header, local address and ports
+    let (sent, rest) = open(frame.get(6 + 42 + 16 + 4..)?)?;
+    let (received, _) = open(rest)?;
+    Some((sent, received))
+}
+
+fn advertised_role(open: &[u8]) -> Option<BgpRole> {
+    raw_capabilities(open)
+        .into_iter()
+        .find(|(code, _)| *code == CAP_ROLE)
+        .and_then(|(_, data)| BgpRole::from_capability(data))
+}
+
+/// Session information from a Peer Up frame
+fn peer_up_session(frame: &[u8], peer: &BmpMessagePeerHeader) -> Session {
+    let (local_role, peer_role) = match peer_up_opens(frame) {
+        Some((sent, received)) => (advertised_role(sent), advertised_role(received)),
+        None => (None, None),
+    };
+    Session {
+        peer_asn: Some(peer.asnum),
+        local_role,
+        peer_role,
+        established: Some(peer_header_time(peer)),
+        ..Default::default()
+    }
+}
+
+type PeerMessage = Result<(BmpMessageRouteMonitoring, RawUpdate), BmpMessagePeerDown>;
+
+/// Inconsistencies in the message sequence of a single monitored peer.
+///
+/// These are logged and handled locally, the other peers of the client are
+/// not affected.
+#[derive(Debug)]
+pub enum PeerError {
+    /// Route Monitoring message for a peer that is not up
+    UnknownPeer(IpAddr),
+    /// Peer Up notification for a peer that is already up
+    DuplicatePeerUp(IpAddr),
+    /// Peer Down notification for a peer that is not up
+    UnknownPeerDown(IpAddr),
+    /// The task processing the peer's messages has terminated unexpectedly
+    PeerTaskFailed(IpAddr),
+}
+
+impl std::fmt::Display for PeerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PeerError::UnknownPeer(peer) => write!(f, "route monitoring for unknown peer {}", peer),
+            PeerError::DuplicatePeerUp(peer) => write!(f, "peer up for established peer {}", peer),
+            PeerError::UnknownPeerDown(peer) => write!(f, "peer down for unknown peer {}", peer),
+            PeerError::PeerTaskFailed(peer) => write!(f, "task for peer {} has failed", peer),
+        }
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+/// Errors which end the connection of a BMP client
+#[derive(Debug)]
+pub enum ClientError {
+    /// The first message was not an Initiation message
+    MissingInitiation(String),
+    /// The message following the Initiation was not a Peer Up notification
+    MissingPeerUp(String),
+    /// The connection was closed without a Termination message
+    UnexpectedEndOfStream,
+    /// A prefix limit with the Teardown action was exceeded
+    PrefixLimitExceeded,
 }
 
-pub fn run_peer(
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClientError::MissingInitiation(got) => {
+                write!(f, "expected initiation message, got: {}", got)
+            }
+            ClientError::MissingPeerUp(got) => {
+                write!(f, "expected initial peer up notification, got: {}", got)
+            }
+            ClientError::UnexpectedEndOfStream => write!(f, "unexpected end of stream"),
+            ClientError::PrefixLimitExceeded => write!(f, "prefix limit exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+fn session_id_for_peer(client_addr: SocketAddr, peer: &BmpMessagePeerHeader) -> Option<SessionId> {
+    table_selector_for_peer(client_addr, peer).and_then(|table| table.session_id().cloned())
+}
+
+struct PeerHandle {
+    tx: mpsc::Sender<PeerMessage>,
+    task: JoinHandle<()>,
+}
+
+/// Spawns the task processing the messages of one peer.
+///
+/// If the peer was up before, `previous` is the task of the last incarnation,
+/// which is waited for so that its cleanup can not race with the new session.
+fn run_peer(
     client_addr: SocketAddr,
     peer: BmpMessagePeerHeader,
     store: &impl Store,
-) -> mpsc::Sender<Result<BmpMessageRouteMonitoring, BmpMessagePeerDown>> {
+    import_filter: Arc<ImportFilter>,
+    limiter: Arc<PrefixLimiter>,
+    session: Session,
+    previous: Option<JoinHandle<()>>,
+) -> PeerHandle {
     let (tx, mut rx) = mpsc::channel(16);
     let store = store.clone();
 
-    tokio::task::spawn(async move {
+    let task = tokio::task::spawn(async move {
+        if let Some(previous) = previous {
+            let _ = previous.await;
+        }
         trace!("{} {:?}", client_addr, peer);
-        if let Some(session_id) = table_selector_for_peer(client_addr, &peer)
-            .and_then(|store| store.session_id().cloned())
-        {
-            store.session_up(session_id, Session {}).await;
+        if let Some(session_id) = session_id_for_peer(client_addr, &peer) {
+            store.session_up(session_id, session).await;
         }
 
         loop {
             match rx.recv().await {
-                Some(Ok(rm)) => {
-                    process_route_monitoring(&store, client_addr, rm).await;
+                Some(Ok((rm, raw))) => {
+                    process_route_monitoring(
+                        &store,
+                        client_addr,
+                        rm,
+                        raw,
+                        &import_filter,
+                        &limiter,
+                    )
+                    .await;
                 }
                 Some(Err(down_msg)) => {
                     trace!("{} {:?}", client_addr, down_msg);
@@ -87,26 +278,186 @@ pub fn run_peer(
                 }
             }
         }
-        if let Some(session_id) = table_selector_for_peer(client_addr, &peer)
-            .and_then(|store| store.session_id().cloned())
-        {
-            store.session_down(session_id, None).await;
+        if let Some(session_id) = session_id_for_peer(client_addr, &peer) {
+            store.session_down(session_id.clone(), None).await;
+            limiter.session_down(&session_id);
         }
     });
 
-    tx
+    PeerHandle { tx, task }
+}
+
+/// Per-peer tasks of one BMP client
+struct Peers<'a, T: Store> {
+    client_addr: SocketAddr,
+    store: &'a T,
+    import_filter: Arc<ImportFilter>,
+    limiter: Arc<PrefixLimiter>,
+    running: HashMap<IpAddr, PeerHandle>,
+    stopping: HashMap<IpAddr, JoinHandle<()>>,
+}
+
+impl<'a, T: Store> Peers<'a, T> {
+    fn new(
+        client_addr: SocketAddr,
+        store: &'a T,
+        import_filter: ImportFilter,
+        limiter: Arc<PrefixLimiter>,
+    ) -> Self {
+        Self {
+            client_addr,
+            store,
+            import_filter: Arc::new(import_filter),
+            limiter,
+            running: HashMap::new(),
+            stopping: HashMap::new(),
+        }
+    }
+
+    fn report(&self, err: PeerError) {
+        warn!("{}: {}", self.client_addr, err);
+    }
+
+    fn start(&mut self, peer: BmpMessagePeerHeader, session: Session) {
+        let peer_address = peer.peeraddress;
+        let previous = self.stopping.remove(&peer_address);
+        let handle = run_peer(
+            self.client_addr,
+            peer,
+            self.store,
+            self.import_filter.clone(),
+            self.limiter.clone(),
+            session,
+            previous,
+        );
+        self.running.insert(peer_address, handle);
+    }
+
+    fn stop(&mut self, peer_address: IpAddr) -> Option<mpsc::Sender<PeerMessage>> {
+        let PeerHandle { tx, task } = self.running.remove(&peer_address)?;
+        self.stopping.retain(|_, task| !task.is_finished());
+        self.stopping.insert(peer_address, task);
+        Some(tx)
+    }
+
+    /// Ends all peer tasks after they have processed their queued messages,
+    /// so that they can not add routes once the client is removed
+    async fn shutdown(self) {
+        let tasks = self
+            .running
+            .into_values()
+            .map(|PeerHandle { task, .. }| task)
+            .chain(self.stopping.into_values());
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+
+    /// Cleans up after a peer task which ended without removing its session
+    async fn recover(&mut self, peer: &BmpMessagePeerHeader) {
+        self.report(PeerError::PeerTaskFailed(peer.peeraddress));
+        self.stop(peer.peeraddress);
+        if let Some(session_id) = session_id_for_peer(self.client_addr, peer) {
+            self.store.session_down(session_id, None).await;
+        }
+    }
+
+    fn peer_up(&mut self, peer: BmpMessagePeerHeader, frame: &[u8]) {
+        if self.running.contains_key(&peer.peeraddress) {
+            self.report(PeerError::DuplicatePeerUp(peer.peeraddress));
+            self.stop(peer.peeraddress);
+        }
+        let session = peer_up_session(frame, &peer);
+        self.start(peer, session);
+    }
+
+    /// Starts a peer which was not announced in a Peer Up message
+    fn start_incomplete(&mut self, peer: BmpMessagePeerHeader, reason: &str) {
+        let session = Session {
+            incomplete_reason: Some(reason.to_string()),
+            peer_asn: Some(peer.asnum),
+            ..Default::default()
+        };
+        self.start(peer, session);
+    }
+
+    async fn peer_down(&mut self, down_msg: BmpMessagePeerDown) {
+        let peer = down_msg.peer.clone();
+        match self.stop(peer.peeraddress) {
+            Some(tx) => {
+                if tx.send(Err(down_msg)).await.is_err() {
+                    self.recover(&peer).await;
+                }
+            }
+            None => self.report(PeerError::UnknownPeerDown(peer.peeraddress)),
+        }
+    }
+
+    async fn route_monitoring(&mut self, rm: BmpMessageRouteMonitoring, raw: RawUpdate) {
+        let peer = rm.peer.clone();
+        if !self.running.contains_key(&peer.peeraddress) {
+            self.report(PeerError::UnknownPeer(peer.peeraddress));
+            self.start_incomplete(peer.clone(), "route monitoring received before peer up");
+        }
+        let tx = self.running[&peer.peeraddress].tx.clone();
+        if let Err(SendError(msg)) = tx.send(Ok((rm, raw))).await {
+            self.recover(&peer).await;
+            self.start_incomplete(peer.clone(), "processing of earlier messages has failed");
+            let tx = self.running[&peer.peeraddress].tx.clone();
+            if tx.send(msg).await.is_err() {
+                self.report(PeerError::PeerTaskFailed(peer.peeraddress));
+            }
+        }
+    }
+}
+
+/// Collects the free-form string TLVs (type 0) of a BMP Initiation message
+fn initiation_strings(mut buf: &[u8]) -> Vec<String> {
+    let mut strings = vec![];
+    while buf.len() >= 4 {
+        let info_type = u16::from_be_bytes([buf[0], buf[1]]);
+        let info_len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        let Some(info) = buf.get(4..4 + info_len) else {
+            break;
+        };
+        if info_type == 0 {
+            strings.push(String::from_utf8_lossy(info).into_owned());
+        }
+        buf = &buf[4 + info_len..];
+    }
+    strings
+}
+
+fn termination_reason(reason: Option<u16>) -> String {
+    match reason {
+        Some(0) => "administratively closed".to_string(),
+        Some(1) => "unspecified reason".to_string(),
+        Some(2) => "out of resources".to_string(),
+        Some(3) => "redundant connection".to_string(),
+        Some(4) => "permanently administratively closed".to_string(),
+        Some(n) => format!("unknown reason {}", n),
+        None => "no reason given".to_string(),
+    }
 }
+
 pub async fn run_client(
     cfg: PeerConfig,
-    io: TcpStream,
+    io: impl AsyncRead + Unpin,
     client_addr: SocketAddr,
     store: &impl Store,
+    relay: &BmpRelay,
+    last_termination: Option<ClientTermination>,
 ) -> anyhow::Result<BmpMessageTermination> {
     let read = LengthDelimitedCodec::builder()
         .length_field_offset(1)
         .length_field_type::<u32>()
         .num_skip(0)
         .new_read(io)
+        .map(|msg| {
+            let orig_msg = msg?.freeze();
+            relay.forward(&orig_msg);
+            Ok::<_, std::io::Error>(orig_msg)
+        })
         .filter_map(|msg| async move {
             let orig_msg = match msg {
                 Ok(v) => v,
@@ -116,7 +467,7 @@ pub async fn run_client(
                 }
             };
             match BmpMessage::decode_from(&orig_msg[5..]) {
-                Ok(v) => Some(v),
+                Ok(v) => Some((orig_msg, v)),
                 Err(e) => {
                     warn!("BMP Parse Error: {:?}", e);
                     warn!("{:x?}", &orig_msg);
@@ -126,16 +477,18 @@ pub async fn run_client(
         })
         .peekable();
     pin_mut!(read);
-    let init_msg = match read.next().await {
-        Some(BmpMessage::Initiation(i)) => i,
+    let (init_frame, init_msg) = match read.next().await {
+        Some((frame, BmpMessage::Initiation(i))) => (frame, i),
         other => {
-            anyhow::bail!("expected initiation message, got: {:?}", other);
+            let other = other.map(|(_, msg)| msg);
+            return Err(ClientError::MissingInitiation(format!("{:?}", other)).into());
         }
     };
-    let first_peer_up = match read.next().await {
-        Some(BmpMessage::PeerUpNotification(n)) => n,
+    let (first_peer_up_frame, first_peer_up) = match read.next().await {
+        Some((frame, BmpMessage::PeerUpNotification(n))) => (frame, n),
         other => {
-            anyhow::bail!("expected initial peer up notification, got: {:?}", other);
+            let other = other.map(|(_, msg)| msg);
+            return Err(ClientError::MissingPeerUp(format!("{:?}", other)).into());
         }
     };
     let client_name = cfg
@@ -149,86 +502,332 @@ pub async fn run_client(
             Client {
                 client_name,
                 router_id: first_peer_up.msg1.router_id,
+                sys_descr: init_msg.sys_descr,
+                info_strings: initiation_strings(&init_frame[6..]),
+                last_termination,
+                ..Default::default()
             },
+            cfg.prefix_limit.clone(),
         )
         .await;
 
-    let mut channels: HashMap<
-        IpAddr,
-        mpsc::Sender<Result<BmpMessageRouteMonitoring, BmpMessagePeerDown>>,
-    > = HashMap::new();
+    let limit = cfg.prefix_limit.unwrap_or_default();
+    let limiter = Arc::new(PrefixLimiter::new(client_addr, limit, store));
+    let mut peers = Peers::new(client_addr, store, cfg.import_filter, limiter.clone());
+    peers.peer_up(first_peer_up.peer, &first_peer_up_frame);
 
-    loop {
-        let msg = read
-            .next()
-            .await
-            .ok_or(anyhow::anyhow!("unexpected end of stream"))?;
+    let res = loop {
+        let (frame, msg) = tokio::select! {
+            msg = read.next() => match msg {
+                Some(msg) => msg,
+                None => break Err(ClientError::UnexpectedEndOfStream.into()),
+            },
+            _ = limiter.teardown() => break Err(ClientError::PrefixLimitExceeded.into()),
+        };
 
         match msg {
             BmpMessage::RouteMonitoring(rm) => {
-                let channel = channels.entry(rm.peer.peeraddress).or_insert_with(|| {
-                    warn!("the bmp device {} sent a message for a nonexisting peer, we'll initialize the table now: {:?}", &client_addr, &rm);
-                    run_peer(client_addr, rm.peer.clone(), store)
-                });
-                channel.send(Ok(rm)).await.unwrap();
-            }
-            BmpMessage::PeerUpNotification(n) => {
-                channels.insert(n.peer.peeraddress, run_peer(client_addr, n.peer, store));
+                // the A flag marks peers using 2-octet AS numbers in AS_PATH
+                let four_octet_as = rm.peer.flags & 0x20 == 0;
+                let raw = RawUpdate::parse(route_monitoring_update(&frame), four_octet_as);
+                peers.route_monitoring(rm, raw).await
             }
-            BmpMessage::PeerDownNotification(n) => match channels.remove(&n.peer.peeraddress) {
-                Some(channel) => channel.send(Err(n)).await.unwrap(),
-                None => warn!("message for nonexisting peer: {:?}", &n),
-            },
+            BmpMessage::PeerUpNotification(n) => peers.peer_up(n.peer, &frame),
+            BmpMessage::PeerDownNotification(n) => peers.peer_down(n).await,
             BmpMessage::Termination(n) => break Ok(n),
             msg => trace!("unknown message from {} {:#?}", client_addr, msg),
         }
-    }
+    };
+    peers.shutdown().await;
+    res
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct PeerConfig {
     pub name_override: Option<String>,
+    pub prefix_limit: Option<PrefixLimit>,
+    #[serde(default)]
+    pub import_filter: ImportFilter,
+}
+
+/// Accepts both plain addresses and networks in CIDR notation as keys
+fn deserialize_peers<'de, D>(deserializer: D) -> Result<HashMap<IpNet, PeerConfig>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let mut peers = HashMap::new();
+    for (key, peer_cfg) in HashMap::<String, PeerConfig>::deserialize(deserializer)? {
+        let net = key
+            .parse::<IpNet>()
+            .or_else(|_| key.parse::<IpAddr>().map(IpNet::from))
+            .map_err(|_| serde::de::Error::custom(format!("invalid address or network: {}", key)))?
+            .trunc();
+        if peers.insert(net, peer_cfg).is_some() {
+            return Err(serde::de::Error::custom(format!(
+                "multiple peer configs for network {}",
+                net
+            )));
+        }
+    }
+    Ok(peers)
 }
 
 #[derive(Debug, Clone, Deserialize)]
-pub struct BmpCollectorConfig {
-    pub bind: SocketAddr,
+pub struct ActiveTargetConfig {
+    /// Router acting as BMP server the collector connects to
+    pub target: SocketAddr,
     #[serde(default)]
-    pub peers: HashMap<IpAddr, PeerConfig>,
+    pub peer_config: PeerConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BmpCollectorConfig {
+    /// Address to listen on for incoming connections, can be omitted if only `connect` is used
+    pub bind: Option<SocketAddr>,
+    /// Per-client peer configs, the longest matching network wins
+    #[serde(default, deserialize_with = "deserialize_peers")]
+    pub peers: HashMap<IpNet, PeerConfig>,
     pub default_peer_config: Option<PeerConfig>,
+    /// Connections from these networks are always rejected
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+    /// Maximum number of concurrent connections from a single client address
+    pub max_connections_per_source: Option<usize>,
+    /// Downstream BMP stations all received messages are forwarded to
+    #[serde(default)]
+    pub relay: Vec<RelayTargetConfig>,
+    /// Routers the collector connects to instead of waiting for them to connect
+    #[serde(default)]
+    pub connect: Vec<ActiveTargetConfig>,
+    /// Evaluated for the routes of all clients, after the import filter of the peer config
+    #[serde(default)]
+    pub import_filter: ImportFilter,
+}
+
+/// Decides which connections are accepted and with which peer config
+pub struct AccessControl {
+    peers: Node<IpNet, PeerConfig>,
+    deny: Node<IpNet, ()>,
+    default_peer_config: Option<PeerConfig>,
+    max_connections_per_source: Option<usize>,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// Counts towards the connection limit of a client address until dropped
+pub struct ConnectionSlot {
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    addr: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.addr);
+            }
+        }
+    }
+}
+
+impl AccessControl {
+    pub fn new(cfg: &BmpCollectorConfig) -> Self {
+        let mut peers = Node::default();
+        for (net, peer_cfg) in &cfg.peers {
+            peers.insert(net, peer_cfg.clone());
+        }
+        let mut deny = Node::default();
+        for net in &cfg.deny {
+            deny.insert(&net.trunc(), ());
+        }
+        Self {
+            peers,
+            deny,
+            default_peer_config: cfg.default_peer_config.clone(),
+            max_connections_per_source: cfg.max_connections_per_source,
+            connections: Default::default(),
+        }
+    }
+
+    /// Peer config for a new connection, or `None` if it is rejected
+    pub fn accept(&self, client_addr: SocketAddr) -> Option<(PeerConfig, ConnectionSlot)> {
+        let addr = client_addr.ip();
+        let host = IpNet::from(addr);
+        if let Some((net, _)) = self.deny.longest_match(&host) {
+            info!(
+                "rejected connection from {}: denied by {}",
+                client_addr, net
+            );
+            return None;
+        }
+        let peer_cfg = match self
+            .peers
+            .longest_match(&host)
+            .map(|(_, peer_cfg)| peer_cfg)
+            .or(self.default_peer_config.as_ref())
+        {
+            Some(peer_cfg) => peer_cfg.clone(),
+            None => {
+                info!("unexpected connection from {}", client_addr);
+                return None;
+            }
+        };
+
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(addr).or_insert(0);
+        if let Some(max) = self.max_connections_per_source {
+            if *count >= max {
+                info!(
+                    "rejected connection from {}: {} connections already open",
+                    client_addr, count
+                );
+                return None;
+            }
+        }
+        *count += 1;
+        let slot = ConnectionSlot {
+            connections: self.connections.clone(),
+            addr,
+        };
+        Some((peer_cfg, slot))
+    }
+}
+
+/// Runs a client connection until it ends or the collector is shut down
+async fn handle_client(
+    peer_cfg: PeerConfig,
+    io: TcpStream,
+    client_addr: SocketAddr,
+    store: &impl Store,
+    relay: BmpRelay,
+    last_terminations: &Mutex<HashMap<IpAddr, ClientTermination>>,
+    shutdown: &mut watch::Receiver<bool>,
+) {
+    let last_termination = last_terminations
+        .lock()
+        .unwrap()
+        .get(&client_addr.ip())
+        .cloned();
+    tokio::select! {
+        res = run_client(peer_cfg, io, client_addr, store, &relay, last_termination) => {
+            let termination = match res {
+                Err(e) => {
+                    warn!("disconnected {} {}", client_addr, e);
+                    ClientTermination {
+                        time: Utc::now(),
+                        reason: e.to_string(),
+                        message: None,
+                    }
+                }
+                Ok(notification) => {
+                    info!("disconnected {} {:?}", client_addr, notification);
+                    ClientTermination {
+                        time: Utc::now(),
+                        reason: termination_reason(notification.reason),
+                        message: notification.str0,
+                    }
+                }
+            };
+            last_terminations.lock().unwrap().insert(client_addr.ip(), termination);
+        }
+        _ = shutdown.changed() => {
+        }
+    };
+    store.client_down(client_addr).await;
+}
+
+/// Keeps a connection to a router acting as BMP server, reconnecting with
+/// exponential backoff whenever it fails or ends
+async fn run_active(
+    target_cfg: ActiveTargetConfig,
+    store: impl Store,
+    relay_cfg: Vec<RelayTargetConfig>,
+    last_terminations: Arc<Mutex<HashMap<IpAddr, ClientTermination>>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let target = target_cfg.target;
+    let mut interval = MIN_RECONNECT_INTERVAL;
+    loop {
+        tokio::select! {
+            res = connect(target) => match res {
+                Ok(io) => {
+                    info!("connected {:?}", target);
+                    interval = MIN_RECONNECT_INTERVAL;
+                    let relay = BmpRelay::new(&relay_cfg, target);
+                    handle_client(
+                        target_cfg.peer_config.clone(),
+                        io,
+                        target,
+                        &store,
+                        relay,
+                        &last_terminations,
+                        &mut shutdown,
+                    )
+                    .await;
+                }
+                Err(e) => warn!("connecting to {} failed: {}", target, e),
+            },
+            _ = shutdown.changed() => return,
+        }
+        if *shutdown.borrow() {
+            return;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = shutdown.changed() => return,
+        }
+        interval = std::cmp::min(interval * 2, MAX_RECONNECT_INTERVAL);
+    }
 }
 
 pub async fn run(
-    cfg: BmpCollectorConfig,
+    mut cfg: BmpCollectorConfig,
     store: impl Store,
-    mut shutdown: tokio::sync::watch::Receiver<bool>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(cfg.bind).await?;
-    let mut running_tasks = vec![];
+    let peer_cfgs = cfg
+        .peers
+        .values_mut()
+        .chain(cfg.default_peer_config.iter_mut())
+        .chain(cfg.connect.iter_mut().map(|target| &mut target.peer_config));
+    for peer_cfg in peer_cfgs {
+        peer_cfg.import_filter = peer_cfg.import_filter.then(&cfg.import_filter);
+    }
+    let listener = match cfg.bind {
+        Some(bind) => Some(TcpListener::bind(bind).await?),
+        None => None,
+    };
+    let access_control = AccessControl::new(&cfg);
+    let last_terminations: Arc<Mutex<HashMap<IpAddr, ClientTermination>>> = Default::default();
+    let mut running_tasks = cfg
+        .connect
+        .iter()
+        .map(|target_cfg| {
+            tokio::spawn(run_active(
+                target_cfg.clone(),
+                store.clone(),
+                cfg.relay.clone(),
+                last_terminations.clone(),
+                shutdown.clone(),
+            ))
+        })
+        .collect::<Vec<_>>();
     loop {
         tokio::select! {
-            new_conn = listener.accept() => {
+            new_conn = async { listener.as_ref().unwrap().accept().await }, if listener.is_some() => {
                 let (io, client_addr) = new_conn?;
                 info!("connected {:?}", client_addr);
 
-                let store = store.clone();
-                let mut shutdown = shutdown.clone();
-                if let Some(peer_cfg) = cfg.peers.get(&client_addr.ip()).or(cfg.default_peer_config.as_ref()).cloned() {
+                if let Some((peer_cfg, slot)) = access_control.accept(client_addr) {
+                    let store = store.clone();
+                    let mut shutdown = shutdown.clone();
+                    let relay = BmpRelay::new(&cfg.relay, client_addr);
+                    let last_terminations = last_terminations.clone();
                     running_tasks.push(tokio::spawn(async move {
-                        tokio::select! {
-                            res = run_client(peer_cfg, io, client_addr, &store) => {
-                                match res {
-                                    Err(e) => warn!("disconnected {} {}", client_addr, e),
-                                    Ok(notification) => info!("disconnected {} {:?}", client_addr, notification),
-                                }
-                            }
-                            _ = shutdown.changed() => {
-                            }
-                        };
-                        store.client_down(client_addr).await;
+                        handle_client(peer_cfg, io, client_addr, &store, relay, &last_terminations, &mut shutdown).await;
+                        drop(slot);
                     }));
-                } else {
-                    info!("unexpected connection from {}", client_addr);
                 }
             }
             _ = shutdown.changed() => {
@@ -0,0 +1,257 @@
+use crate::bmp_collector::{self, MAX_RECONNECT_INTERVAL, MIN_RECONNECT_INTERVAL};
+use bytes::Bytes;
+use log::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+
+const BMP_MSG_PEER_DOWN: u8 = 2;
+const BMP_MSG_PEER_UP: u8 = 3;
+const BMP_MSG_INITIATION: u8 = 4;
+const BMP_MSG_TERMINATION: u8 = 5;
+
+const PEER_DOWN_BMP_DISABLED: u8 = 5;
+
+fn default_buffer_size() -> usize {
+    4096
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RelayTargetConfig {
+    /// Downstream BMP station the received messages are forwarded to
+    pub target: SocketAddr,
+    /// Number of messages buffered for this target before the connection is considered lagging
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+}
+
+/// State shared between the client connection and its relay targets
+#[derive(Default)]
+struct RelayState {
+    /// Sequence number of the last forwarded message
+    seq: u64,
+    /// Initiation message of the client with its sequence number, sent again on new
+    /// connections to a target
+    initiation: Option<(u64, Bytes)>,
+}
+
+/// Peer type, distinguisher and address from the per-peer header
+fn peer_key(frame: &Bytes) -> Option<Bytes> {
+    if frame.len() < 6 + 42 {
+        return None;
+    }
+    let mut key = Vec::with_capacity(25);
+    key.push(frame[6]);
+    key.extend_from_slice(&frame[8..32]);
+    Some(key.into())
+}
+
+/// Peer Down message with reason 5 ("information for this peer will no longer be
+/// sent to the monitoring station"), built from the per-peer header of the Peer Up
+fn peer_down(peer_up: &Bytes) -> Bytes {
+    let mut frame = Vec::with_capacity(6 + 42 + 1);
+    frame.push(3);
+    frame.extend_from_slice(&(6 + 42 + 1u32).to_be_bytes());
+    frame.push(BMP_MSG_PEER_DOWN);
+    frame.extend_from_slice(&peer_up[6..6 + 42]);
+    frame.push(PEER_DOWN_BMP_DISABLED);
+    frame.into()
+}
+
+/// Peers whose messages are relayed over one downstream connection.
+///
+/// A downstream station only sees a peer from its Peer Up message on, so every
+/// message it gets for the peer builds on a complete Route Monitoring stream.
+#[derive(Default)]
+struct RelayedPeers(HashMap<Bytes, Bytes>);
+
+impl RelayedPeers {
+    /// Whether the message is forwarded to the target
+    fn relay(&mut self, frame: &Bytes) -> bool {
+        match frame.get(5) {
+            Some(&BMP_MSG_INITIATION) | Some(&BMP_MSG_TERMINATION) => true,
+            Some(&BMP_MSG_PEER_UP) => {
+                if let Some(key) = peer_key(frame) {
+                    self.0.insert(key, frame.clone());
+                }
+                true
+            }
+            Some(&BMP_MSG_PEER_DOWN) => match peer_key(frame) {
+                Some(key) => self.0.remove(&key).is_some(),
+                None => true,
+            },
+            _ => match peer_key(frame) {
+                Some(key) => self.0.contains_key(&key),
+                None => true,
+            },
+        }
+    }
+
+    /// Peer Down messages for all relayed peers, after messages for them were lost
+    fn take_peer_downs(&mut self) -> Vec<Bytes> {
+        self.0
+            .drain()
+            .map(|(_, peer_up)| peer_down(&peer_up))
+            .collect()
+    }
+}
+
+struct RelayTarget {
+    tx: mpsc::Sender<(u64, Bytes)>,
+    lagged: Arc<AtomicBool>,
+}
+
+/// Forwards the raw BMP frames of one client connection to the configured
+/// downstream stations.
+///
+/// Every target gets its own connection and bounded queue. If a target can
+/// not keep up, its queue is dropped instead of blocking ingestion, and the
+/// peers affected by the lost messages are reported as down to the target
+/// until the router sends a new Peer Up for them. After a reconnect, only the
+/// Initiation message is sent again, so the target learns about peers with
+/// their next Peer Up as well.
+pub struct BmpRelay {
+    targets: Vec<RelayTarget>,
+    state: Arc<Mutex<RelayState>>,
+    _stop: watch::Sender<()>,
+}
+
+impl BmpRelay {
+    pub fn new(cfg: &[RelayTargetConfig], client_addr: SocketAddr) -> Self {
+        let state: Arc<Mutex<RelayState>> = Default::default();
+        let (stop_tx, stop_rx) = watch::channel(());
+        let targets = cfg
+            .iter()
+            .map(|target_cfg| {
+                let (tx, rx) = mpsc::channel(std::cmp::max(target_cfg.buffer_size, 1));
+                let lagged: Arc<AtomicBool> = Default::default();
+                tokio::task::spawn(run_target(
+                    target_cfg.target,
+                    client_addr,
+                    state.clone(),
+                    rx,
+                    lagged.clone(),
+                    stop_rx.clone(),
+                ));
+                RelayTarget { tx, lagged }
+            })
+            .collect();
+
+        Self {
+            targets,
+            state,
+            _stop: stop_tx,
+        }
+    }
+
+    pub fn forward(&self, frame: &Bytes) {
+        if self.targets.is_empty() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.seq += 1;
+        if frame.get(5) == Some(&BMP_MSG_INITIATION) {
+            state.initiation = Some((state.seq, frame.clone()));
+        }
+        for target in &self.targets {
+            if target.tx.try_send((state.seq, frame.clone())).is_err() {
+                target.lagged.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+async fn connect(
+    target: SocketAddr,
+    client_addr: SocketAddr,
+    stop: &mut watch::Receiver<()>,
+) -> Option<TcpStream> {
+    let mut interval = MIN_RECONNECT_INTERVAL;
+    loop {
+        tokio::select! {
+            res = bmp_collector::connect(target) => match res {
+                Ok(stream) => return Some(stream),
+                Err(e) => warn!("relay {} -> {}: connect failed: {}", client_addr, target, e),
+            },
+            _ = stop.changed() => return None,
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = stop.changed() => return None,
+        }
+        interval = std::cmp::min(interval * 2, MAX_RECONNECT_INTERVAL);
+    }
+}
+
+async fn run_target(
+    target: SocketAddr,
+    client_addr: SocketAddr,
+    state: Arc<Mutex<RelayState>>,
+    mut rx: mpsc::Receiver<(u64, Bytes)>,
+    lagged: Arc<AtomicBool>,
+    mut stop: watch::Receiver<()>,
+) {
+    // Sequence number of the last message taken from the queue
+    let mut last_seq = 0;
+    'reconnect: loop {
+        let mut stream = match connect(target, client_addr, &mut stop).await {
+            Some(stream) => stream,
+            None => return,
+        };
+        info!("relay {} -> {}: connected", client_addr, target);
+
+        // A new connection is a new BMP session for the target, which starts
+        // with the Initiation, unless that is still queued
+        let initiation = state.lock().unwrap().initiation.clone();
+        if let Some((seq, frame)) = initiation {
+            if seq <= last_seq {
+                if let Err(e) = stream.write_all(&frame).await {
+                    warn!("relay {} -> {}: {}", client_addr, target, e);
+                    continue 'reconnect;
+                }
+            }
+        }
+
+        let mut peers = RelayedPeers::default();
+        loop {
+            let (seq, frame) = match rx.recv().await {
+                Some(v) => v,
+                None => return,
+            };
+            let frames: Vec<Bytes> = if lagged.load(Ordering::Relaxed) {
+                warn!(
+                    "relay {} -> {}: target is lagging behind, dropping queued messages",
+                    client_addr, target
+                );
+                let state = state.lock().unwrap();
+                while rx.try_recv().is_ok() {}
+                lagged.store(false, Ordering::Relaxed);
+                let lost_initiation = state
+                    .initiation
+                    .clone()
+                    .filter(|(seq, _)| *seq > last_seq)
+                    .map(|(_, frame)| frame);
+                last_seq = state.seq;
+                drop(state);
+                lost_initiation
+                    .into_iter()
+                    .chain(peers.take_peer_downs())
+                    .collect()
+            } else {
+                last_seq = seq;
+                peers.relay(&frame).then_some(frame).into_iter().collect()
+            };
+            for frame in frames {
+                if let Err(e) = stream.write_all(&frame).await {
+                    warn!("relay {} -> {}: {}", client_addr, target, e);
+                    continue 'reconnect;
+                }
+            }
+        }
+    }
+}
@@ -1,79 +1,230 @@
-use std::hash::Hash;
-use std::net::IpAddr;
-use std::sync::{Arc, Weak};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use weak_table::traits::WeakKey;
 use weak_table::WeakHashSet;
 
+use crate::stats::CacheStats;
 use crate::store::*;
 
+pub type LargeCommunityList = Vec<Arc<(u32, u32, u32)>>;
+
 #[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct CompressedRouteAttrs {
     pub origin: Option<RouteOrigin>,
     pub as_path: Option<Arc<Vec<u32>>>,
     pub communities: Option<Arc<Vec<(u16, u16)>>>,
-    pub large_communities: Option<Arc<Vec<Arc<(u32, u32, u32)>>>>,
+    pub large_communities: Option<Arc<LargeCommunityList>>,
     pub med: Option<u32>,
     pub local_pref: Option<u32>,
-    pub nexthop: Option<IpAddr>,
+    pub nexthop: Option<Arc<NextHop>>,
+    pub aggregator: Option<(u32, Ipv4Addr)>,
+    pub otc: Option<u32>,
+}
+
+/// Next hop addresses, which many attribute sets differing in other attributes share
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NextHop {
+    pub addr: Option<IpAddr>,
+    pub link_local: Option<Ipv6Addr>,
 }
 
-#[derive(Default)]
+/// Number of independently locked parts of each interning set
+pub const SHARDS: usize = 64;
+
+/// Interning caches shared by all tables, safe to use from many collectors at once
 pub struct Caches {
-    large_communities_cache: WeakHashSet<Weak<(u32, u32, u32)>>,
-    large_communities_list_cache: WeakHashSet<Weak<Vec<Arc<(u32, u32, u32)>>>>,
-    communities_list_cache: WeakHashSet<Weak<Vec<(u16, u16)>>>,
-    as_path_cache: WeakHashSet<Weak<Vec<u32>>>,
-    route_attrs_cache: WeakHashSet<Weak<CompressedRouteAttrs>>,
+    large_communities_cache: ShardedSet<(u32, u32, u32)>,
+    large_communities_list_cache: ShardedSet<LargeCommunityList>,
+    communities_list_cache: ShardedSet<Vec<(u16, u16)>>,
+    as_path_cache: ShardedSet<Vec<u32>>,
+    nexthop_cache: ShardedSet<NextHop>,
+    route_attrs_cache: ShardedSet<CompressedRouteAttrs>,
+}
+
+impl Default for Caches {
+    fn default() -> Self {
+        Self {
+            large_communities_cache: ShardedSet::new(|_| 0),
+            large_communities_list_cache: ShardedSet::new(|list| {
+                list.capacity() * size_of::<Arc<(u32, u32, u32)>>()
+            }),
+            communities_list_cache: ShardedSet::new(|list| {
+                list.capacity() * size_of::<(u16, u16)>()
+            }),
+            as_path_cache: ShardedSet::new(|path| path.capacity() * size_of::<u32>()),
+            nexthop_cache: ShardedSet::new(|_| 0),
+            route_attrs_cache: ShardedSet::new(|_| 0),
+        }
+    }
+}
+
+struct Shard<T> {
+    set: WeakHashSet<Weak<T>>,
+    /// Bytes of the values, including ones which are no longer used until they are removed
+    value_bytes: usize,
 }
 
-trait WeakHashSetExt<T: Clone + 'static> {
-    fn get_or_insert(&mut self, val: T) -> Arc<T>;
+/// Set of weak references split into shards by the hash of the value,
+/// so equal values always end up in the same shard
+struct ShardedSet<T> {
+    shards: Vec<Mutex<Shard<T>>>,
+    hasher: RandomState,
+    /// Memory owned by a value outside of its own struct
+    heap_size: fn(&T) -> usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Values removed after they were no longer used
+    reclaimed: AtomicU64,
+}
+
+impl<T: Eq + Hash> ShardedSet<T> {
+    fn new(heap_size: fn(&T) -> usize) -> Self {
+        Self {
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        set: WeakHashSet::new(),
+                        value_bytes: 0,
+                    })
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            heap_size,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            reclaimed: AtomicU64::new(0),
+        }
+    }
 }
-impl<T: Clone + Eq + Hash + 'static> WeakHashSetExt<T> for WeakHashSet<Weak<T>>
+
+impl<T: Eq + Hash + 'static> ShardedSet<T>
 where
     Weak<T>: WeakKey<Key = T, Strong = Arc<T>>,
 {
-    fn get_or_insert(&mut self, val: T) -> Arc<T> {
-        self.get(&val).unwrap_or_else(|| {
-            let arc = Arc::new(val);
-            self.insert(arc.clone());
-            arc
-        })
+    /// Bytes of a value, with the reference counts stored next to it
+    fn value_size(&self, val: &T) -> usize {
+        2 * size_of::<usize>() + size_of::<T>() + (self.heap_size)(val)
+    }
+
+    fn get_or_insert(&self, val: T) -> Arc<T> {
+        let shard = self.hasher.hash_one(&val) as usize % SHARDS;
+        let mut shard = self.shards[shard].lock().unwrap();
+        match shard.set.get(&val) {
+            Some(arc) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                arc
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                shard.value_bytes += self.value_size(&val);
+                let arc = Arc::new(val);
+                shard.set.insert(arc.clone());
+                arc
+            }
+        }
+    }
+
+    fn stats(&self, name: &'static str) -> CacheStats {
+        let mut entries = 0;
+        let mut memory_bytes = 0;
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            entries += shard.set.len();
+            // each slot holds the weak reference and the hash code
+            memory_bytes += shard.set.capacity() * (size_of::<Weak<T>>() + size_of::<u64>());
+            memory_bytes += shard.value_bytes;
+        }
+        CacheStats::new(
+            name,
+            entries,
+            memory_bytes,
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.reclaimed.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Removes values which are no longer used from one shard, returns how many were removed
+    fn remove_expired(&self, shard: usize) -> usize {
+        let mut shard = self.shards[shard].lock().unwrap();
+        // includes expired values until they are removed
+        let len = shard.set.len();
+        shard.set.remove_expired();
+        let reclaimed = len - shard.set.len();
+        // the size of the removed values is unknown once they are dropped
+        if reclaimed > 0 {
+            shard.value_bytes = shard.set.iter().map(|val| self.value_size(&val)).sum();
+        }
+        // the set does not shrink on its own after route churn
+        if shard.set.capacity() > 4 * shard.set.len() {
+            shard.set.shrink_to_fit();
+        }
+        self.reclaimed
+            .fetch_add(reclaimed as u64, Ordering::Relaxed);
+        reclaimed
     }
 }
 
 impl Caches {
-    pub fn compress_route_attrs(&mut self, route: RouteAttrs) -> Arc<CompressedRouteAttrs> {
+    pub fn compress_route_attrs(&self, route: RouteAttrs) -> Arc<CompressedRouteAttrs> {
         let route = CompressedRouteAttrs {
-            as_path: route
-                .as_path
-                .map(|x| self.as_path_cache.get_or_insert(x).clone()),
+            as_path: route.as_path.map(|x| self.as_path_cache.get_or_insert(x)),
             communities: route
                 .communities
-                .map(|x| self.communities_list_cache.get_or_insert(x).clone()),
+                .map(|x| self.communities_list_cache.get_or_insert(x)),
             large_communities: route.large_communities.map(|x| {
                 let list = x
                     .into_iter()
                     .map(|c| self.large_communities_cache.get_or_insert(c))
                     .collect();
-                self.large_communities_list_cache
-                    .get_or_insert(list)
-                    .clone()
+                self.large_communities_list_cache.get_or_insert(list)
             }),
             local_pref: route.local_pref,
             med: route.med,
             origin: route.origin,
-            nexthop: route.nexthop,
+            nexthop: (route.nexthop.is_some() || route.nexthop_link_local.is_some()).then(|| {
+                self.nexthop_cache.get_or_insert(NextHop {
+                    addr: route.nexthop,
+                    link_local: route.nexthop_link_local,
+                })
+            }),
+            aggregator: route.aggregator,
+            otc: route.otc,
         };
         self.route_attrs_cache.get_or_insert(route)
     }
 
-    pub fn remove_expired(&mut self) {
-        self.large_communities_cache.remove_expired();
-        self.large_communities_list_cache.remove_expired();
-        self.communities_list_cache.remove_expired();
-        self.as_path_cache.remove_expired();
-        self.route_attrs_cache.remove_expired();
+    pub fn stats(&self) -> Vec<CacheStats> {
+        vec![
+            self.route_attrs_cache.stats("route_attrs"),
+            self.as_path_cache.stats("as_path"),
+            self.communities_list_cache.stats("communities"),
+            self.large_communities_list_cache
+                .stats("large_community_lists"),
+            self.large_communities_cache.stats("large_communities"),
+            self.nexthop_cache.stats("nexthops"),
+        ]
+    }
+
+    pub fn remove_expired(&self) -> usize {
+        (0..SHARDS)
+            .map(|shard| self.remove_expired_in_shard(shard))
+            .sum()
+    }
+
+    /// Removes values which are no longer used from one shard of each set, so the sets are
+    /// only locked briefly. Returns how many were removed.
+    pub fn remove_expired_in_shard(&self, shard: usize) -> usize {
+        self.route_attrs_cache.remove_expired(shard)
+            + self.as_path_cache.remove_expired(shard)
+            + self.communities_list_cache.remove_expired(shard)
+            + self.large_communities_list_cache.remove_expired(shard)
+            + self.large_communities_cache.remove_expired(shard)
+            + self.nexthop_cache.remove_expired(shard)
     }
 }
 
@@ -88,6 +239,12 @@ pub fn decompress_route_attrs(route: &CompressedRouteAttrs) -> RouteAttrs {
         local_pref: route.local_pref,
         med: route.med,
         origin: route.origin.clone(),
-        nexthop: route.nexthop,
+        nexthop: route.nexthop.as_ref().and_then(|nexthop| nexthop.addr),
+        nexthop_link_local: route
+            .nexthop
+            .as_ref()
+            .and_then(|nexthop| nexthop.link_local),
+        aggregator: route.aggregator,
+        otc: route.otc,
     }
 }
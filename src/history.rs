@@ -0,0 +1,474 @@
+use chrono::{DateTime, Duration, Utc};
+use ipnet::IpNet;
+use nibbletree::Node;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::mpsc;
+
+use crate::compressed_attrs::*;
+use crate::journal::JournalUpdate;
+use crate::store::*;
+
+/// How often events older than `max_age` are removed from all paths
+pub const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Number of independently locked parts of the history
+pub const SHARDS: usize = 16;
+
+fn default_max_events_per_path() -> usize {
+    100
+}
+
+fn default_max_age() -> u64 {
+    86400
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Number of events kept for each path, older ones are removed first
+    #[serde(default = "default_max_events_per_path")]
+    pub max_events_per_path: usize,
+    /// Seconds after which events are removed
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+    /// File the history is written to, to restore it after a restart, kept in memory only if unset
+    pub journal: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RouteEventKind {
+    /// A path which did not exist before was announced
+    Announce,
+    /// The attributes of an existing path changed
+    Change,
+    Withdraw,
+}
+
+/// Change of a path, as reported by `InMemoryTable`
+#[derive(Debug, Clone)]
+pub struct RouteEvent {
+    pub timestamp: DateTime<Utc>,
+    pub kind: RouteEventKind,
+    /// The new attributes, unset for withdrawals
+    pub attrs: Option<Arc<CompressedRouteAttrs>>,
+}
+
+impl RouteEvent {
+    pub fn to_history_event(
+        &self,
+        table: TableSelector,
+        net: IpNet,
+        path_id: PathId,
+    ) -> HistoryEvent {
+        HistoryEvent {
+            timestamp: self.timestamp,
+            kind: self.kind,
+            net,
+            path_id,
+            table,
+            attrs: self.attrs.as_deref().map(decompress_route_attrs),
+        }
+    }
+}
+
+/// Change of a route, as returned by the route history and live subscriptions
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEvent {
+    pub timestamp: DateTime<Utc>,
+    pub kind: RouteEventKind,
+    pub net: IpNet,
+    pub path_id: PathId,
+    #[serde(flatten)]
+    pub table: TableSelector,
+    #[serde(flatten)]
+    pub attrs: Option<RouteAttrs>,
+}
+
+type PathEvents = HashMap<(TableSelector, PathId), VecDeque<RouteEvent>>;
+
+/// Route of a table as it was at some time
+pub type RouteAt = (TableSelector, IpNet, Arc<CompressedRouteAttrs>, RouteTimes);
+
+/// One independently locked part of the history
+#[derive(Default)]
+struct Shard {
+    paths: Arc<Node<IpNet, PathEvents>>,
+    /// Number of events recorded in the shard, tells the journal which ones it already has
+    seq: u64,
+    journal: Option<mpsc::Sender<JournalUpdate>>,
+}
+
+/// Bounded timeline of route events per (table, prefix, path id).
+///
+/// The prefixes are split into shards by their hash, each holding a copy-on-write prefix tree,
+/// so recording events only locks one shard and readers work on snapshots without any lock.
+pub struct History {
+    cfg: HistoryConfig,
+    shards: Vec<Mutex<Shard>>,
+    hasher: RandomState,
+    /// Last known information about clients and sessions, kept after they are gone
+    pub clients: RwLock<HashMap<SocketAddr, Client>>,
+    pub sessions: RwLock<HashMap<SessionId, Session>>,
+    /// Receives changes of clients and sessions, the shards have their own senders for events
+    journal: Mutex<Option<mpsc::Sender<JournalUpdate>>>,
+    /// Set if updates were dropped because the journal writer could not keep up
+    journal_lagged: AtomicBool,
+}
+
+/// Clients and sessions which recorded paths belong to
+#[derive(Default)]
+pub struct TablesInUse {
+    clients: HashSet<SocketAddr>,
+    sessions: HashSet<SessionId>,
+}
+
+impl History {
+    pub fn new(cfg: HistoryConfig) -> Self {
+        Self {
+            cfg,
+            shards: (0..SHARDS).map(|_| Default::default()).collect(),
+            hasher: RandomState::new(),
+            clients: Default::default(),
+            sessions: Default::default(),
+            journal: Default::default(),
+            journal_lagged: AtomicBool::new(false),
+        }
+    }
+
+    fn shard_index(&self, net: &IpNet) -> usize {
+        self.hasher.hash_one(net) as usize % SHARDS
+    }
+
+    fn cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now - Duration::seconds(self.cfg.max_age as i64)
+    }
+
+    fn send_to_journal(&self, journal: &mpsc::Sender<JournalUpdate>, update: JournalUpdate) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = journal.try_send(update) {
+            self.journal_lagged.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Sends all following changes to `journal`
+    pub(crate) fn set_journal(&self, journal: mpsc::Sender<JournalUpdate>) {
+        for shard in &self.shards {
+            shard.lock().unwrap().journal = Some(journal.clone());
+        }
+        *self.journal.lock().unwrap() = Some(journal);
+    }
+
+    /// Stops sending changes to the journal, which lets the writer finish
+    pub(crate) fn close_journal(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().journal = None;
+        }
+        *self.journal.lock().unwrap() = None;
+    }
+
+    /// Whether updates were dropped since the last call
+    pub(crate) fn journal_lagged(&self) -> bool {
+        self.journal_lagged.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn record(&self, table: TableSelector, net: IpNet, path_id: PathId, event: RouteEvent) {
+        let cutoff = self.cutoff(Utc::now());
+        let index = self.shard_index(&net);
+        let mut shard = self.shards[index].lock().unwrap();
+        shard.seq += 1;
+        if let Some(journal) = &shard.journal {
+            let update = JournalUpdate::Event {
+                shard: index,
+                seq: shard.seq,
+                table: table.clone(),
+                net,
+                path_id,
+                event: event.clone(),
+            };
+            self.send_to_journal(journal, update);
+        }
+        let paths = Arc::make_mut(&mut shard.paths);
+        if paths.exact(&net).is_none() {
+            paths.insert(&net, Default::default());
+        }
+        let paths_of_net = paths.exact_mut(&net).unwrap();
+        let events = paths_of_net.entry((table.clone(), path_id)).or_default();
+        events.push_back(event);
+        while events.len() > self.cfg.max_events_per_path {
+            events.pop_front();
+        }
+        retain_newer(events, cutoff);
+        // e.g. a withdrawal with a timestamp from before `max_age`
+        if events.is_empty() {
+            paths_of_net.remove(&(table, path_id));
+            if paths_of_net.is_empty() {
+                paths.remove(&net);
+            }
+        }
+    }
+
+    pub fn update_client(&self, client_addr: SocketAddr, client: Client) {
+        self.clients
+            .write()
+            .unwrap()
+            .insert(client_addr, client.clone());
+        if let Some(journal) = &*self.journal.lock().unwrap() {
+            self.send_to_journal(journal, JournalUpdate::Client(client_addr, client));
+        }
+    }
+
+    pub fn update_session(&self, session_id: SessionId, session: Session) {
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(session_id.clone(), session.clone());
+        if let Some(journal) = &*self.journal.lock().unwrap() {
+            self.send_to_journal(journal, JournalUpdate::Session(session_id, session));
+        }
+    }
+
+    /// Removes expired events and paths without any events left from one of the shards,
+    /// returns the number of removed events.
+    ///
+    /// The clients and sessions of the paths in the shard are added to `in_use`.
+    pub fn remove_expired(&self, shard: usize, in_use: &mut TablesInUse) -> usize {
+        let cutoff = self.cutoff(Utc::now());
+        // the shard is only locked while changing the paths with expired events
+        let snapshot = self.shards[shard].lock().unwrap().paths.clone();
+        let mut expired = vec![];
+        for (net, paths) in snapshot.iter() {
+            for ((table, _), events) in paths {
+                in_use.clients.insert(*table.client_addr());
+                if let Some(session_id) = table.session_id() {
+                    in_use.sessions.insert(session_id.clone());
+                }
+                if has_expired(events, cutoff) {
+                    expired.push(net);
+                }
+            }
+        }
+        expired.dedup();
+        drop(snapshot);
+
+        let mut removed = 0;
+        let mut shard = self.shards[shard].lock().unwrap();
+        let paths = Arc::make_mut(&mut shard.paths);
+        for net in expired {
+            let Some(paths_of_net) = paths.exact_mut(&net) else {
+                continue;
+            };
+            paths_of_net.retain(|_, events| {
+                let len = events.len();
+                retain_newer(events, cutoff);
+                removed += len - events.len();
+                !events.is_empty()
+            });
+            if paths_of_net.is_empty() {
+                paths.remove(&net);
+            }
+        }
+        removed
+    }
+
+    /// Forgets clients and sessions which are gone and have no recorded paths left
+    pub fn remove_unused(
+        &self,
+        in_use: &TablesInUse,
+        is_up: impl Fn(&SocketAddr) -> bool,
+        session_is_up: impl Fn(&SessionId) -> bool,
+    ) {
+        // clients are added to the store before the history, so they are either up here or added later
+        self.clients
+            .write()
+            .unwrap()
+            .retain(|client_addr, _| in_use.clients.contains(client_addr) || is_up(client_addr));
+        self.sessions.write().unwrap().retain(|session_id, _| {
+            in_use.sessions.contains(session_id) || session_is_up(session_id)
+        });
+    }
+
+    /// View of all shards, which is not affected by later events
+    pub fn snapshot(&self) -> HistorySnapshot {
+        let (shards, seqs) = self
+            .shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock().unwrap();
+                (shard.paths.clone(), shard.seq)
+            })
+            .unzip();
+        HistorySnapshot { shards, seqs }
+    }
+
+    /// Events of all paths of exactly `net` in the tables matched by `filter`, oldest first
+    pub fn get(&self, net: &IpNet, filter: impl Fn(&TableSelector) -> bool) -> Vec<HistoryEvent> {
+        let cutoff = self.cutoff(Utc::now());
+        let paths = self.shards[self.shard_index(net)]
+            .lock()
+            .unwrap()
+            .paths
+            .clone();
+        let mut events: Vec<_> = paths
+            .exact(net)
+            .into_iter()
+            .flatten()
+            .filter(|((table, _), _)| filter(table))
+            .flat_map(|((table, path_id), events)| {
+                events
+                    .iter()
+                    .filter(|event| event.timestamp >= cutoff)
+                    .map(|event| event.to_history_event(table.clone(), *net, *path_id))
+            })
+            .collect();
+        events.sort_by_key(|event| event.timestamp);
+        events
+    }
+}
+
+/// Recorded events at one point in time
+pub struct HistorySnapshot {
+    shards: Vec<Arc<Node<IpNet, PathEvents>>>,
+    /// Number of events recorded in each shard at that time
+    seqs: Vec<u64>,
+}
+
+impl HistorySnapshot {
+    pub(crate) fn seqs(&self) -> &[u64] {
+        &self.seqs
+    }
+
+    /// All recorded events
+    pub(crate) fn events(
+        &self,
+    ) -> impl Iterator<Item = (&TableSelector, IpNet, PathId, &RouteEvent)> + '_ {
+        self.shards
+            .iter()
+            .flat_map(|paths| paths.iter())
+            .flat_map(|(net, paths)| {
+                paths.iter().flat_map(move |((table, path_id), events)| {
+                    events
+                        .iter()
+                        .map(move |event| (table, net, *path_id, event))
+                })
+            })
+    }
+
+    /// Paths in the tables matched by `filter` as they were at time `at`, found while iterating.
+    ///
+    /// Paths are only returned if an event at or before `at` is still recorded,
+    /// so the result is incomplete for times from which events were already removed.
+    pub fn routes_at<'a>(
+        &'a self,
+        net_query: &'a NetQuery,
+        at: DateTime<Utc>,
+        filter: impl Fn(&TableSelector) -> bool + 'a,
+    ) -> Box<dyn Iterator<Item = RouteAt> + 'a> {
+        let routes_of_net = move |(net, paths): (IpNet, &PathEvents)| {
+            paths
+                .iter()
+                .filter(|((table, _), _)| filter(table))
+                .filter_map(|((table, _), events)| {
+                    let (attrs, times) = state_at(events, at)?;
+                    Some((table.clone(), net, attrs, times))
+                })
+                .collect::<Vec<_>>()
+        };
+        let nets: Box<dyn Iterator<Item = (IpNet, &PathEvents)>> = match net_query {
+            NetQuery::Exact(net) => Box::new(
+                self.shards
+                    .iter()
+                    .filter_map(|paths| Some((*net, paths.exact(net)?))),
+            ),
+            NetQuery::Contains(net) => {
+                Box::new(self.shards.iter().flat_map(|paths| paths.matches(net)))
+            }
+            NetQuery::OrLonger(net) => {
+                Box::new(self.shards.iter().flat_map(|paths| paths.or_longer(net)))
+            }
+            // the most specific prefix with routes at that time, not the most specific one with events
+            NetQuery::MostSpecific(net) => {
+                let routes = self
+                    .shards
+                    .iter()
+                    .flat_map(|paths| paths.matches(net))
+                    .map(&routes_of_net)
+                    .filter(|routes| !routes.is_empty())
+                    .max_by_key(|routes| routes[0].1.prefix_len())
+                    .unwrap_or_default();
+                return Box::new(routes.into_iter());
+            }
+        };
+        Box::new(nets.flat_map(routes_of_net))
+    }
+}
+
+/// Attributes and times of a path at time `at`, or `None` if it did not exist or is unknown
+fn state_at(
+    events: &VecDeque<RouteEvent>,
+    at: DateTime<Utc>,
+) -> Option<(Arc<CompressedRouteAttrs>, RouteTimes)> {
+    let mut events: Vec<_> = events
+        .iter()
+        .filter(|event| event.timestamp <= at)
+        .collect();
+    events.sort_by_key(|event| event.timestamp);
+
+    let mut state: Option<(Arc<CompressedRouteAttrs>, RouteTimes)> = None;
+    for event in events {
+        state = match (event.kind, &event.attrs, state) {
+            (RouteEventKind::Withdraw, _, _) | (_, None, _) => None,
+            (RouteEventKind::Change, Some(attrs), Some((_, times))) => Some((
+                attrs.clone(),
+                RouteTimes {
+                    last_modified: event.timestamp,
+                    ..times
+                },
+            )),
+            // the announcement may have been removed by the retention limits
+            (_, Some(attrs), _) => Some((
+                attrs.clone(),
+                RouteTimes {
+                    first_seen: event.timestamp,
+                    last_modified: event.timestamp,
+                },
+            )),
+        };
+    }
+    state
+}
+
+/// Whether `retain_newer` would remove any of the events
+fn has_expired(events: &VecDeque<RouteEvent>, cutoff: DateTime<Utc>) -> bool {
+    let mut expired = events.iter().filter(|event| event.timestamp < cutoff);
+    match (expired.next(), expired.next()) {
+        (None, _) => false,
+        (Some(event), None) => event.kind == RouteEventKind::Withdraw,
+        (Some(_), Some(_)) => true,
+    }
+}
+
+/// Removes events older than `cutoff`, except for the last of them if the path still existed
+/// afterwards, which is needed to reconstruct the state of the path after `cutoff`.
+/// Once the withdrawal of a path is older than `cutoff`, all of its events are removed.
+fn retain_newer(events: &mut VecDeque<RouteEvent>, cutoff: DateTime<Utc>) {
+    // events are not necessarily in order, e.g. with timestamps from BMP
+    let last_expired = events
+        .iter()
+        .enumerate()
+        .filter(|(_, event)| event.timestamp < cutoff)
+        .max_by_key(|(_, event)| event.timestamp)
+        .filter(|(_, event)| event.kind != RouteEventKind::Withdraw)
+        .map(|(index, _)| index);
+    let mut index = 0;
+    events.retain(|event| {
+        let keep = event.timestamp >= cutoff || Some(index) == last_expired;
+        index += 1;
+        keep
+    });
+}
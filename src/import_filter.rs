@@ -0,0 +1,209 @@
+use ipnet::IpNet;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+use crate::store::{RouteAttrs, RouteOrigin};
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Regex::new(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_regex(deserializer).map(Some)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrefixMatch {
+    pub net: IpNet,
+    /// Shortest matching prefix length, defaults to the length of `net`
+    pub min_len: Option<u8>,
+    /// Longest matching prefix length, defaults to the length of `net`
+    pub max_len: Option<u8>,
+}
+
+impl PrefixMatch {
+    fn matches(&self, net: &IpNet) -> bool {
+        let min_len = self.min_len.unwrap_or(self.net.prefix_len());
+        let max_len = self.max_len.unwrap_or(self.net.prefix_len());
+        self.net.contains(net) && (min_len..=max_len).contains(&net.prefix_len())
+    }
+}
+
+/// Conditions of an import rule, all of which have to match
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteMatch {
+    /// Matches if any of the entries matches
+    #[serde(default)]
+    pub prefix: Vec<PrefixMatch>,
+    /// Matched against the AS path as space separated AS numbers
+    #[serde(default, deserialize_with = "deserialize_optional_regex")]
+    pub as_path_regex: Option<Regex>,
+    /// Matches if any community (`64496:1`) or large community (`64496:1:2`) matches
+    #[serde(default, deserialize_with = "deserialize_optional_regex")]
+    pub community_regex: Option<Regex>,
+    pub origin: Option<RouteOrigin>,
+}
+
+impl RouteMatch {
+    fn matches(&self, net: &IpNet, attrs: &RouteAttrs) -> bool {
+        if !self.prefix.is_empty() && !self.prefix.iter().any(|prefix| prefix.matches(net)) {
+            return false;
+        }
+        if let Some(regex) = &self.as_path_regex {
+            let as_path_text = attrs
+                .as_path
+                .iter()
+                .flatten()
+                .map(|asn| asn.to_string())
+                .collect::<Vec<_>>()
+                .join(" ");
+            if !regex.is_match(&as_path_text) {
+                return false;
+            }
+        }
+        if let Some(regex) = &self.community_regex {
+            if !community_texts(attrs).any(|community| regex.is_match(&community)) {
+                return false;
+            }
+        }
+        if let Some(origin) = &self.origin {
+            if attrs.origin.as_ref() != Some(origin) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn community_texts(attrs: &RouteAttrs) -> impl Iterator<Item = String> + '_ {
+    let communities = attrs.communities.iter().flatten();
+    let large_communities = attrs.large_communities.iter().flatten();
+    communities
+        .map(|(asn, value)| format!("{}:{}", asn, value))
+        .chain(
+            large_communities.map(|(asn, value1, value2)| format!("{}:{}:{}", asn, value1, value2)),
+        )
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommunityRewrite {
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    /// Replacement for the matched text, may refer to capture groups like `$1`.
+    /// Communities which are not valid after the replacement are removed.
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum ImportAction {
+    /// Import the route without evaluating further rules
+    Accept,
+    /// Do not import the route
+    Drop,
+    /// Continue with the next rule
+    #[default]
+    Continue,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportRule {
+    #[serde(rename = "match", default)]
+    pub matches: RouteMatch,
+    #[serde(default)]
+    pub action: ImportAction,
+    /// Removes communities and large communities matching the regex
+    #[serde(default, deserialize_with = "deserialize_optional_regex")]
+    pub strip_communities: Option<Regex>,
+    #[serde(default)]
+    pub rewrite_communities: Vec<CommunityRewrite>,
+}
+
+impl ImportRule {
+    fn modify(&self, attrs: &mut RouteAttrs) {
+        let rewrite = |text: String| -> Option<String> {
+            if self
+                .strip_communities
+                .as_ref()
+                .is_some_and(|regex| regex.is_match(&text))
+            {
+                return None;
+            }
+            let mut text = text;
+            for CommunityRewrite {
+                pattern,
+                replacement,
+            } in &self.rewrite_communities
+            {
+                text = pattern.replace_all(&text, replacement).into_owned();
+            }
+            Some(text)
+        };
+        if let Some(communities) = &mut attrs.communities {
+            *communities = std::mem::take(communities)
+                .into_iter()
+                .filter_map(|(asn, value)| rewrite(format!("{}:{}", asn, value)))
+                .filter_map(|text| {
+                    let (asn, value) = text.split_once(':')?;
+                    Some((asn.parse().ok()?, value.parse().ok()?))
+                })
+                .collect();
+        }
+        if let Some(large_communities) = &mut attrs.large_communities {
+            *large_communities = std::mem::take(large_communities)
+                .into_iter()
+                .filter_map(|(asn, value1, value2)| {
+                    rewrite(format!("{}:{}:{}", asn, value1, value2))
+                })
+                .filter_map(|text| {
+                    let mut parts = text.split(':').map(|part| part.parse().ok());
+                    let community = (parts.next()??, parts.next()??, parts.next()??);
+                    parts.next().is_none().then_some(community)
+                })
+                .collect();
+        }
+    }
+}
+
+/// Rules evaluated in order for each route before it is inserted into the store.
+///
+/// Routes not dropped by any rule are imported.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct ImportFilter {
+    pub rules: Vec<ImportRule>,
+}
+
+impl ImportFilter {
+    /// Filter evaluating the rules of `self` first, then the ones of `next`
+    pub fn then(&self, next: &ImportFilter) -> ImportFilter {
+        ImportFilter {
+            rules: self.rules.iter().chain(&next.rules).cloned().collect(),
+        }
+    }
+
+    /// Returns the attributes to import the route with, or `None` if it is dropped
+    pub fn apply(&self, net: &IpNet, mut attrs: RouteAttrs) -> Option<RouteAttrs> {
+        for rule in &self.rules {
+            if !rule.matches.matches(net, &attrs) {
+                continue;
+            }
+            rule.modify(&mut attrs);
+            match rule.action {
+                ImportAction::Accept => break,
+                ImportAction::Drop => return None,
+                ImportAction::Continue => {}
+            }
+        }
+        Some(attrs)
+    }
+}
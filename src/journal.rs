@@ -0,0 +1,368 @@
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use tokio::sync::mpsc;
+use weak_table::PtrWeakKeyHashMap;
+
+use crate::compressed_attrs::*;
+use crate::history::{History, RouteEvent, RouteEventKind};
+use crate::store::*;
+use crate::store_impl::InMemoryStore;
+
+/// Size the journal may grow to before it is rewritten, unless it was larger after the last rewrite
+const MIN_COMPACTION_SIZE: u64 = 64 << 20;
+
+/// Number of changes which may wait for the writer, further ones are dropped until it catches up
+const JOURNAL_BUFFER: usize = 65536;
+
+/// Number of changes written at once
+const JOURNAL_BATCH: usize = 4096;
+
+/// Change of the route history, sent to the journal writer
+pub enum JournalUpdate {
+    Event {
+        shard: usize,
+        /// Number of the event in its shard of the history
+        seq: u64,
+        table: TableSelector,
+        net: IpNet,
+        path_id: PathId,
+        event: RouteEvent,
+    },
+    Client(SocketAddr, Client),
+    Session(SessionId, Session),
+}
+
+/// Line of the journal. Tables and attributes are written once and referred to by id afterwards.
+#[derive(Serialize, Deserialize)]
+enum JournalEntry {
+    Table {
+        id: u32,
+        table: StoredTableSelector,
+    },
+    Attrs {
+        id: u64,
+        attrs: RouteAttrs,
+    },
+    Event {
+        table: u32,
+        net: IpNet,
+        path_id: PathId,
+        timestamp: DateTime<Utc>,
+        kind: RouteEventKind,
+        attrs: Option<u64>,
+    },
+    Client {
+        client_addr: SocketAddr,
+        client: Client,
+    },
+    Session {
+        session_id: SessionId,
+        session: Session,
+    },
+}
+
+struct JournalWriter {
+    file: BufWriter<File>,
+    tables: HashMap<TableSelector, u32>,
+    attrs: PtrWeakKeyHashMap<Weak<CompressedRouteAttrs>, u64>,
+    next_attrs_id: u64,
+    /// Events of each shard up to these numbers were written by the last compaction
+    seqs: Vec<u64>,
+    /// Bytes in the file
+    size: u64,
+    compacted_size: u64,
+}
+
+impl JournalWriter {
+    /// Writes the whole history to a new journal, which replaces the previous one once complete
+    fn compact(path: &Path, history: &History) -> anyhow::Result<Self> {
+        let snapshot = history.snapshot();
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = JournalWriter {
+            file: BufWriter::new(File::create(&tmp_path)?),
+            tables: HashMap::new(),
+            attrs: PtrWeakKeyHashMap::new(),
+            next_attrs_id: 0,
+            seqs: snapshot.seqs().to_vec(),
+            size: 0,
+            compacted_size: 0,
+        };
+        let clients = history.clients.read().unwrap().clone();
+        for (client_addr, client) in clients {
+            writer.write_entry(&JournalEntry::Client {
+                client_addr,
+                client,
+            })?;
+        }
+        let sessions = history.sessions.read().unwrap().clone();
+        for (session_id, session) in sessions {
+            writer.write_entry(&JournalEntry::Session {
+                session_id,
+                session,
+            })?;
+        }
+        for (table, net, path_id, event) in snapshot.events() {
+            writer.write_event(table, net, path_id, event)?;
+        }
+        writer.file.flush()?;
+        writer.file.get_ref().sync_all()?;
+        // the file stays open, so the writer continues after the snapshot
+        std::fs::rename(&tmp_path, path)?;
+        writer.compacted_size = writer.size;
+        debug!(
+            "compacted route history journal to {} bytes",
+            writer.compacted_size
+        );
+        Ok(writer)
+    }
+
+    fn write_entry(&mut self, entry: &JournalEntry) -> anyhow::Result<()> {
+        let line = serde_json::to_vec(entry)?;
+        self.file.write_all(&line)?;
+        self.file.write_all(b"\n")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn write_event(
+        &mut self,
+        table: &TableSelector,
+        net: IpNet,
+        path_id: PathId,
+        event: &RouteEvent,
+    ) -> anyhow::Result<()> {
+        let table_id = match self.tables.get(table) {
+            Some(id) => *id,
+            None => {
+                let id = self.tables.len() as u32;
+                self.write_entry(&JournalEntry::Table {
+                    id,
+                    table: table.into(),
+                })?;
+                self.tables.insert(table.clone(), id);
+                id
+            }
+        };
+        let attrs_id = match &event.attrs {
+            Some(attrs) => Some(match self.attrs.get(attrs) {
+                Some(id) => *id,
+                None => {
+                    let id = self.next_attrs_id;
+                    self.next_attrs_id += 1;
+                    self.write_entry(&JournalEntry::Attrs {
+                        id,
+                        attrs: decompress_route_attrs(attrs),
+                    })?;
+                    self.attrs.insert(attrs.clone(), id);
+                    id
+                }
+            }),
+            None => None,
+        };
+        self.write_entry(&JournalEntry::Event {
+            table: table_id,
+            net,
+            path_id,
+            timestamp: event.timestamp,
+            kind: event.kind,
+            attrs: attrs_id,
+        })
+    }
+
+    fn write_updates(&mut self, updates: &[JournalUpdate]) -> anyhow::Result<()> {
+        for update in updates {
+            match update {
+                JournalUpdate::Event {
+                    shard,
+                    seq,
+                    table,
+                    net,
+                    path_id,
+                    event,
+                } => {
+                    // already part of the last compaction
+                    if *seq <= self.seqs[*shard] {
+                        continue;
+                    }
+                    self.write_event(table, *net, *path_id, event)?;
+                }
+                JournalUpdate::Client(client_addr, client) => {
+                    self.write_entry(&JournalEntry::Client {
+                        client_addr: *client_addr,
+                        client: client.clone(),
+                    })?;
+                }
+                JournalUpdate::Session(session_id, session) => {
+                    self.write_entry(&JournalEntry::Session {
+                        session_id: session_id.clone(),
+                        session: session.clone(),
+                    })?;
+                }
+            }
+        }
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Whether most of the journal is probably made up of expired or replaced events
+    fn needs_compaction(&self) -> bool {
+        self.size > MIN_COMPACTION_SIZE.max(2 * self.compacted_size)
+    }
+}
+
+/// Restores the route history from the journal at `path`, if there is one
+pub async fn load(store: &InMemoryStore, path: &Path) -> anyhow::Result<()> {
+    let Some(history) = store.history.clone() else {
+        return Ok(());
+    };
+    let caches = store.caches.clone();
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("no route history journal found at {}", path.display());
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut tables = HashMap::new();
+        let mut attrs = HashMap::new();
+        let mut events = 0;
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            // the last line is incomplete if writing it was interrupted
+            let entry = match serde_json::from_str(&line?) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!(
+                        "ignoring route history journal from line {}: {}",
+                        number + 1,
+                        e
+                    );
+                    break;
+                }
+            };
+            match entry {
+                JournalEntry::Table { id, table } => {
+                    tables.insert(id, TableSelector::from(table));
+                }
+                JournalEntry::Attrs { id, attrs: value } => {
+                    attrs.insert(id, caches.compress_route_attrs(value));
+                }
+                JournalEntry::Event {
+                    table,
+                    net,
+                    path_id,
+                    timestamp,
+                    kind,
+                    attrs: attrs_id,
+                } => {
+                    let Some(table) = tables.get(&table) else {
+                        continue;
+                    };
+                    let event = RouteEvent {
+                        timestamp,
+                        kind,
+                        attrs: attrs_id.and_then(|id| attrs.get(&id).cloned()),
+                    };
+                    history.record(table.clone(), net, path_id, event);
+                    events += 1;
+                }
+                JournalEntry::Client {
+                    client_addr,
+                    client,
+                } => {
+                    history.clients.write().unwrap().insert(client_addr, client);
+                }
+                JournalEntry::Session {
+                    session_id,
+                    session,
+                } => {
+                    history
+                        .sessions
+                        .write()
+                        .unwrap()
+                        .insert(session_id, session);
+                }
+            }
+        }
+        info!(
+            "restored {} route history events from {}",
+            events,
+            path.display()
+        );
+        Ok(())
+    })
+    .await?
+}
+
+/// Appends changes of the route history to the journal at `path`.
+///
+/// The journal is rewritten from the history at startup and whenever it has grown to twice
+/// its size after the last rewrite, which drops the expired events. Runs until the journal
+/// is closed with `InMemoryStore::close_journal`.
+pub async fn run(path: PathBuf, store: InMemoryStore) -> anyhow::Result<()> {
+    let Some(history) = store.history.clone() else {
+        return Ok(());
+    };
+    let (tx, mut rx) = mpsc::channel(JOURNAL_BUFFER);
+    history.set_journal(tx);
+
+    let mut writer = write_updates(None, &path, &history, vec![]).await?.0;
+    let mut updates = Vec::with_capacity(JOURNAL_BATCH);
+    while rx.recv_many(&mut updates, JOURNAL_BATCH).await > 0 {
+        let (new_writer, returned) = write_updates(writer, &path, &history, updates).await?;
+        writer = new_writer;
+        updates = returned;
+        updates.clear();
+    }
+    Ok(())
+}
+
+/// Writes the changes with `writer`, or with a new one if there is none
+/// or changes were dropped, and returns the writer unless it failed
+async fn write_updates(
+    writer: Option<JournalWriter>,
+    path: &Path,
+    history: &Arc<History>,
+    updates: Vec<JournalUpdate>,
+) -> anyhow::Result<(Option<JournalWriter>, Vec<JournalUpdate>)> {
+    // the changes which were dropped are added by rewriting the journal from the history
+    let lagged = history.journal_lagged();
+    if lagged {
+        warn!("route history journal could not keep up, rewriting it");
+    }
+    let history = history.clone();
+    let path = path.to_owned();
+    let (res, updates) = tokio::task::spawn_blocking(move || {
+        let res = (|| {
+            let mut writer = match writer {
+                Some(writer) if !lagged => writer,
+                _ => JournalWriter::compact(&path, &history)?,
+            };
+            writer.write_updates(&updates)?;
+            if writer.needs_compaction() {
+                writer = JournalWriter::compact(&path, &history)?;
+            }
+            anyhow::Ok(writer)
+        })();
+        (res, updates)
+    })
+    .await?;
+    match res {
+        Ok(writer) => Ok((Some(writer), updates)),
+        Err(e) => {
+            // retried with the next changes
+            warn!("failed to write route history journal: {}", e);
+            Ok((None, updates))
+        }
+    }
+}
@@ -2,7 +2,16 @@ pub mod api;
 pub mod bgp_collector;
 mod bgpdumper;
 pub mod bmp_collector;
+pub mod bmp_relay;
 mod compressed_attrs;
+pub mod history;
+pub mod import_filter;
+pub mod journal;
+pub mod prefix_limit;
+pub mod raw_update;
+pub mod sled_store;
+pub mod snapshot;
+pub mod stats;
 pub mod store;
 pub mod store_impl;
 pub mod table_impl;
@@ -33,10 +42,29 @@ pub enum CollectorConfig {
     Bgp(bgp_collector::BgpCollectorConfig),
 }
 
+#[derive(Deserialize, Debug, Default)]
+#[serde(tag = "store_type")]
+pub enum StoreConfig {
+    #[default]
+    InMemory,
+    Sled(sled_store::SledStoreConfig),
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub collectors: HashMap<String, CollectorConfig>,
     pub api: api::ApiServerConfig,
+    /// Where routes are kept, in memory by default
+    #[serde(default)]
+    pub store: StoreConfig,
+    /// Keep a history of route changes, disabled if unset
+    pub history: Option<history::HistoryConfig>,
+    /// Save the routes to a file to restore them after a restart, disabled if unset
+    pub snapshot: Option<snapshot::SnapshotConfig>,
+    /// Removal of unused attributes from the caches of the in-memory store, defaults if unset
+    pub cache_gc: Option<store_impl::CacheGcConfig>,
+    /// Measurement of the memory used by the tables of the in-memory store, defaults if unset
+    pub stats: Option<store_impl::StatsConfig>,
     /// Only check config and exit
     #[serde(default)]
     pub config_check: bool,
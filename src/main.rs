@@ -27,8 +27,45 @@ async fn main() -> anyhow::Result<()> {
         std::process::exit(0);
     }
 
-    let store: store_impl::InMemoryStore = Default::default();
+    match cfg.store {
+        StoreConfig::InMemory => {
+            let store = match cfg.history.clone() {
+                Some(history_cfg) => store_impl::InMemoryStore::with_history(history_cfg),
+                None => store_impl::InMemoryStore::default(),
+            };
+            if let Some(journal) = cfg.history.as_ref().and_then(|cfg| cfg.journal.as_ref()) {
+                if let Err(e) = journal::load(&store, journal).await {
+                    warn!("failed to restore route history: {}", e);
+                }
+            }
+            if let Some(snapshot_cfg) = &cfg.snapshot {
+                if let Err(e) = snapshot::load(&store, snapshot_cfg).await {
+                    warn!("failed to restore snapshot: {}", e);
+                }
+            }
+            run(cfg, store.clone(), Some(store)).await
+        }
+        StoreConfig::Sled(ref sled_cfg) => {
+            if cfg.history.is_some() || cfg.snapshot.is_some() {
+                anyhow::bail!("history and snapshots are only supported by the in-memory store");
+            }
+            // the on-disk store removes unused attributes right away and has no table sizes
+            if cfg.cache_gc.is_some() || cfg.stats.is_some() {
+                anyhow::bail!("cache_gc and stats are only supported by the in-memory store");
+            }
+            let store = sled_store::SledStore::open(sled_cfg)?;
+            run(cfg, store, None).await
+        }
+    }
+}
 
+/// Runs the API server and collectors until shutdown, with the cache cleanup, history
+/// sweeps and snapshots if the routes are kept in memory
+async fn run(
+    cfg: Config,
+    store: impl store::Store,
+    in_memory_store: Option<store_impl::InMemoryStore>,
+) -> anyhow::Result<()> {
     let mut futures = vec![];
 
     let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
@@ -42,10 +79,45 @@ async fn main() -> anyhow::Result<()> {
         shutdown_rx.clone(),
     )));
 
+    let snapshot = cfg.snapshot.zip(in_memory_store.clone());
+    if let Some((snapshot_cfg, snapshot_store)) = snapshot.clone() {
+        futures.push(tokio::task::spawn(snapshot::run(
+            snapshot_cfg,
+            snapshot_store,
+            shutdown_rx.clone(),
+        )));
+    }
+
+    let journal_store = in_memory_store.clone();
+    if let Some(in_memory_store) = in_memory_store {
+        if let Some(history_cfg) = &cfg.history {
+            futures.push(tokio::task::spawn(store_impl::run_history_sweep(
+                in_memory_store.clone(),
+                shutdown_rx.clone(),
+            )));
+            if let Some(journal) = &history_cfg.journal {
+                futures.push(tokio::task::spawn(journal::run(
+                    journal.clone(),
+                    in_memory_store.clone(),
+                )));
+            }
+        }
+        futures.push(tokio::task::spawn(store_impl::run_stats_refresh(
+            cfg.stats.unwrap_or_default(),
+            in_memory_store.clone(),
+            shutdown_rx.clone(),
+        )));
+        futures.push(tokio::task::spawn(store_impl::run_cache_gc(
+            cfg.cache_gc.unwrap_or_default(),
+            in_memory_store,
+            shutdown_rx.clone(),
+        )));
+    }
+
     futures.extend(
         cfg.collectors
-            .into_iter()
-            .map(|(_, collector)| match collector {
+            .into_values()
+            .map(|collector| match collector {
                 CollectorConfig::Bmp(cfg) => {
                     tokio::task::spawn(bmp_collector::run(cfg, store.clone(), shutdown_rx.clone()))
                 }
@@ -72,6 +144,15 @@ async fn main() -> anyhow::Result<()> {
             result?
         }
     };
+    // the collectors remove their routes when shutting down
+    if let Some(in_memory_store) = &journal_store {
+        in_memory_store.close_journal();
+    }
+    if let Some((snapshot_cfg, snapshot_store)) = &snapshot {
+        if let Err(e) = snapshot::write(snapshot_store, &snapshot_cfg.path).await {
+            warn!("failed to write snapshot: {}", e);
+        }
+    }
     shutdown_tx.send(true)?;
     join_all(futures).await;
     res
@@ -0,0 +1,316 @@
+use chrono::{DateTime, Utc};
+use log::*;
+use prometheus::{Encoder, IntGaugeVec, Opts, Registry, TextEncoder};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+use crate::stats::table_labels;
+use crate::store::{PathCountChange, RouteUpdate, RouterInfo, SessionId, Store, TableSelector};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PrefixLimitAction {
+    /// Only log a warning
+    #[default]
+    Warn,
+    /// Ignore announcements until the number of prefixes is below the limit again
+    StopAccepting,
+    /// Close the connection to the client
+    Teardown,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PrefixLimit {
+    /// Maximum number of prefixes over all tables of the client
+    pub max_prefixes: Option<usize>,
+    /// Maximum number of prefixes in each table
+    pub max_prefixes_per_table: Option<usize>,
+    #[serde(default)]
+    pub action: PrefixLimitAction,
+}
+
+/// Number of prefixes in one table, as reported by `Store::get_routers`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableSize {
+    #[serde(flatten)]
+    pub table: TableSelector,
+    pub prefixes: usize,
+}
+
+/// Number of paths of one client, kept up to date by the limiter
+#[derive(Default)]
+struct PathCounts {
+    total: usize,
+    tables: HashMap<TableSelector, usize>,
+    /// Limits which are currently exceeded, `None` for the one over all tables
+    exceeded: HashSet<Option<TableSelector>>,
+}
+
+/// Applies the updates of one client to the store while enforcing its prefix limits.
+///
+/// The limiter counts the paths added and removed by its updates itself, so the
+/// limits are checked for every announced path without looking at the tables.
+/// It is shared by all peers of a client.
+pub struct PrefixLimiter {
+    client_addr: SocketAddr,
+    limit: PrefixLimit,
+    counts: Mutex<PathCounts>,
+    teardown: watch::Sender<bool>,
+}
+
+impl PrefixLimiter {
+    /// Starts counting from the current sizes of the tables of the client
+    pub fn new(client_addr: SocketAddr, limit: PrefixLimit, store: &impl Store) -> Self {
+        let mut counts = PathCounts::default();
+        if limit.max_prefixes.is_some() || limit.max_prefixes_per_table.is_some() {
+            counts.tables = store.get_table_sizes(&client_addr);
+            counts.total = counts.tables.values().sum();
+        }
+        Self {
+            client_addr,
+            limit,
+            counts: Mutex::new(counts),
+            teardown: watch::channel(false).0,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.limit.max_prefixes.is_some() || self.limit.max_prefixes_per_table.is_some()
+    }
+
+    /// Number of paths which may be stored under a limit. With Teardown, one more
+    /// is accepted to notice that the limit is exceeded.
+    fn capacity(&self, max: Option<usize>) -> usize {
+        match (max, self.limit.action) {
+            (None, _) | (Some(_), PrefixLimitAction::Warn) => usize::MAX,
+            (Some(max), PrefixLimitAction::StopAccepting) => max,
+            (Some(max), PrefixLimitAction::Teardown) => max.saturating_add(1),
+        }
+    }
+
+    /// Reserves room for up to `wanted` new paths in the table
+    fn reserve(&self, table: &TableSelector, wanted: usize) -> usize {
+        let mut counts = self.counts.lock().unwrap();
+        let table_count = counts.tables.get(table).copied().unwrap_or(0);
+        let room = std::cmp::min(
+            self.capacity(self.limit.max_prefixes)
+                .saturating_sub(counts.total),
+            self.capacity(self.limit.max_prefixes_per_table)
+                .saturating_sub(table_count),
+        );
+        let reserved = std::cmp::min(room, wanted);
+        counts.total += reserved;
+        *counts.tables.entry(table.clone()).or_insert(0) += reserved;
+        reserved
+    }
+
+    /// Replaces a reservation with the actual change of the table
+    fn release(&self, table: &TableSelector, reserved: usize, change: PathCountChange) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total = (counts.total + change.added).saturating_sub(reserved + change.removed);
+        let table_count = counts.tables.entry(table.clone()).or_insert(0);
+        *table_count = (*table_count + change.added).saturating_sub(reserved + change.removed);
+    }
+
+    /// Applies the update to the table. Announcements are only accepted as long as
+    /// the limits allow, returns the action taken if a limit is exceeded.
+    pub async fn apply(
+        &self,
+        store: &impl Store,
+        table: TableSelector,
+        update: RouteUpdate,
+        timestamp: DateTime<Utc>,
+    ) -> Option<PrefixLimitAction> {
+        if !self.enabled() {
+            store.insert_route_update(table, update, timestamp).await;
+            return None;
+        }
+        if !update.withdrawn.is_empty() {
+            let change = store
+                .update_routes(
+                    table.clone(),
+                    Default::default(),
+                    vec![],
+                    update.withdrawn,
+                    timestamp,
+                )
+                .await;
+            self.release(&table, 0, change);
+        }
+        let mut rejected = false;
+        'announced: for (attrs, mut paths) in update.announced {
+            let mut reserved = self.reserve(&table, paths.len());
+            if reserved < paths.len() {
+                // the limits only apply to new paths, stored ones may still change
+                let stored: HashSet<_> = store
+                    .stored_paths(table.clone(), paths.clone())
+                    .await
+                    .into_iter()
+                    .collect();
+                if !stored.is_empty() {
+                    let (replaced, new) = paths.into_iter().partition(|path| stored.contains(path));
+                    let change = store
+                        .update_routes(table.clone(), attrs.clone(), replaced, vec![], timestamp)
+                        .await;
+                    self.release(&table, 0, change);
+                    paths = new;
+                }
+            }
+            while !paths.is_empty() {
+                if reserved == 0 {
+                    rejected = true;
+                    break 'announced;
+                }
+                let rest = paths.split_off(std::cmp::min(reserved, paths.len()));
+                let change = store
+                    .update_routes(table.clone(), attrs.clone(), paths, vec![], timestamp)
+                    .await;
+                self.release(&table, reserved, change);
+                paths = rest;
+                reserved = self.reserve(&table, paths.len());
+            }
+            // room reserved for paths which turned out to be stored already
+            self.release(&table, reserved, Default::default());
+        }
+        self.check(&table, rejected)
+    }
+
+    fn check(&self, table: &TableSelector, rejected: bool) -> Option<PrefixLimitAction> {
+        let mut counts = self.counts.lock().unwrap();
+        let total = counts.total;
+        let table_count = counts.tables.get(table).copied().unwrap_or(0);
+        let client_exceeded = self.update(&mut counts, None, total, self.limit.max_prefixes);
+        let table_exceeded = self.update(
+            &mut counts,
+            Some(table.clone()),
+            table_count,
+            self.limit.max_prefixes_per_table,
+        );
+        let exceeded = client_exceeded || table_exceeded;
+        match self.limit.action {
+            PrefixLimitAction::StopAccepting if rejected => Some(PrefixLimitAction::StopAccepting),
+            PrefixLimitAction::Teardown if exceeded => {
+                self.teardown.send_replace(true);
+                Some(PrefixLimitAction::Teardown)
+            }
+            PrefixLimitAction::Warn if exceeded => Some(PrefixLimitAction::Warn),
+            _ => None,
+        }
+    }
+
+    fn update(
+        &self,
+        counts: &mut PathCounts,
+        key: Option<TableSelector>,
+        count: usize,
+        max: Option<usize>,
+    ) -> bool {
+        let Some(max) = max else {
+            return false;
+        };
+        // with StopAccepting the limit is the maximum number of prefixes stored
+        let exceeded = match self.limit.action {
+            PrefixLimitAction::StopAccepting => count >= max,
+            _ => count > max,
+        };
+        let what = match &key {
+            Some(table) => format!("table {:?}", table),
+            None => "all tables".to_string(),
+        };
+        if exceeded && counts.exceeded.insert(key.clone()) {
+            warn!(
+                "{}: prefix limit of {} exceeded in {}",
+                self.client_addr, max, what
+            );
+        } else if !exceeded && counts.exceeded.remove(&key) {
+            info!(
+                "{}: back below prefix limit of {} in {}",
+                self.client_addr, max, what
+            );
+        }
+        exceeded
+    }
+
+    /// Stops counting the tables of a session, after they were removed from the store
+    pub fn session_down(&self, session: &SessionId) {
+        let mut counts = self.counts.lock().unwrap();
+        let mut removed = 0;
+        counts.tables.retain(|table, count| {
+            let remove = table.session_id() == Some(session);
+            if remove {
+                removed += *count;
+            }
+            !remove
+        });
+        counts.total -= removed;
+        counts
+            .exceeded
+            .retain(|key| key.as_ref().and_then(|table| table.session_id()) != Some(session));
+    }
+
+    /// Completes once a limit with the Teardown action was exceeded
+    pub async fn teardown(&self) {
+        let _ = self
+            .teardown
+            .subscribe()
+            .wait_for(|teardown| *teardown)
+            .await;
+    }
+}
+
+/// Encodes the prefix counts and limits of all routers in the Prometheus text format
+pub fn encode_metrics(routers: &HashMap<SocketAddr, RouterInfo>) -> prometheus::Result<String> {
+    let labels = ["client_addr", "client_name", "table", "peer_address"];
+    let prefixes = IntGaugeVec::new(
+        Opts::new("fernglas_prefixes", "Number of prefixes in a table"),
+        &labels,
+    )?;
+    let limits = IntGaugeVec::new(
+        Opts::new("fernglas_prefix_limit", "Configured prefix limit"),
+        &labels,
+    )?;
+    for (client_addr, router) in routers {
+        let client_addr = client_addr.to_string();
+        let mut total = 0;
+        for TableSize {
+            table,
+            prefixes: count,
+        } in &router.table_sizes
+        {
+            let (table, peer_address) = table_labels(table);
+            let labels = [
+                &client_addr,
+                &router.client.client_name,
+                table,
+                &peer_address,
+            ];
+            prefixes.with_label_values(&labels).set(*count as i64);
+            if let Some(max) = router
+                .prefix_limit
+                .as_ref()
+                .and_then(|limit| limit.max_prefixes_per_table)
+            {
+                limits.with_label_values(&labels).set(max as i64);
+            }
+            total += count;
+        }
+        let labels = [&client_addr, &router.client.client_name, "all", ""];
+        prefixes.with_label_values(&labels).set(total as i64);
+        if let Some(max) = router
+            .prefix_limit
+            .as_ref()
+            .and_then(|limit| limit.max_prefixes)
+        {
+            limits.with_label_values(&labels).set(max as i64);
+        }
+    }
+
+    let registry = Registry::new();
+    registry.register(Box::new(prefixes))?;
+    registry.register(Box::new(limits))?;
+    let mut buf = vec![];
+    TextEncoder::new().encode(&registry.gather(), &mut buf)?;
+    Ok(String::from_utf8(buf).unwrap())
+}
@@ -0,0 +1,144 @@
+//! Parts of BGP UPDATE messages that zettabgp does not decode correctly,
+//! read directly from the message body.
+
+use std::net::{IpAddr, Ipv6Addr};
+
+/// Placeholder for 4-octet AS numbers in 2-octet fields (RFC 6793)
+pub const AS_TRANS: u32 = 23456;
+
+pub const AS_SET: u8 = 1;
+pub const AS_SEQUENCE: u8 = 2;
+pub const AS_CONFED_SEQUENCE: u8 = 3;
+pub const AS_CONFED_SET: u8 = 4;
+
+pub const AFI_IPV4: u16 = 1;
+pub const AFI_IPV6: u16 = 2;
+
+const ATTR_AS_PATH: u8 = 2;
+const ATTR_MP_REACH_NLRI: u8 = 14;
+const ATTR_MP_UNREACH_NLRI: u8 = 15;
+
+/// Segment type and AS numbers of one AS_PATH segment
+pub type AsPathSegment = (u8, Vec<u32>);
+
+/// Parses the segments of an AS_PATH or AS4_PATH attribute value
+pub fn parse_as_path(mut buf: &[u8], as_len: usize) -> Option<Vec<AsPathSegment>> {
+    let mut segments = vec![];
+    while let [segment_type, count, rest @ ..] = buf {
+        let asns = rest.get(..*count as usize * as_len)?;
+        buf = &rest[asns.len()..];
+        let asns = asns
+            .chunks(as_len)
+            .map(|asn| asn.iter().fold(0u32, |acc, byte| acc << 8 | *byte as u32))
+            .collect();
+        segments.push((*segment_type, asns));
+    }
+    if !buf.is_empty() {
+        return None;
+    }
+    Some(segments)
+}
+
+/// Type codes and values of the path attributes in the body of an UPDATE message
+fn path_attributes(update: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let withdrawn_len = u16::from_be_bytes(update.get(0..2)?.try_into().unwrap()) as usize;
+    let pos = 2 + withdrawn_len;
+    let attrs_len = u16::from_be_bytes(update.get(pos..pos + 2)?.try_into().unwrap()) as usize;
+    let mut attrs = update.get(pos + 2..pos + 2 + attrs_len)?;
+    let mut result = vec![];
+    while let [flags, type_code, rest @ ..] = attrs {
+        let (len, rest) = if flags & 0x10 != 0 {
+            let len = u16::from_be_bytes(rest.get(0..2)?.try_into().unwrap()) as usize;
+            (len, &rest[2..])
+        } else {
+            (*rest.first()? as usize, &rest[1..])
+        };
+        result.push((*type_code, rest.get(..len)?));
+        attrs = &rest[len..];
+    }
+    Some(result)
+}
+
+/// Address family of an End-of-RIB marker (RFC 4724, section 2)
+fn end_of_rib(update: &[u8], attrs: &[(u8, &[u8])]) -> Option<u16> {
+    // no withdrawn routes, path attributes or NLRI
+    if update == [0, 0, 0, 0] {
+        return Some(AFI_IPV4);
+    }
+    // only an MP_UNREACH_NLRI attribute without withdrawn routes
+    let [(ATTR_MP_UNREACH_NLRI, [afi_high, afi_low, _safi])] = attrs else {
+        return None;
+    };
+    let attrs_len = u16::from_be_bytes(update.get(2..4)?.try_into().unwrap()) as usize;
+    if update[..2] != [0, 0] || update.len() != 4 + attrs_len {
+        return None;
+    }
+    Some(u16::from_be_bytes([*afi_high, *afi_low]))
+}
+
+/// Next hop of an MP_REACH_NLRI attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MpReachNexthop {
+    pub nexthop: IpAddr,
+    pub link_local: Option<Ipv6Addr>,
+}
+
+impl MpReachNexthop {
+    /// zettabgp decodes the next hop based on the AFI of the NLRI, which loses
+    /// IPv6 next hops of IPv4 routes (RFC 8950) and the link-local address
+    /// following a global IPv6 next hop.
+    fn from_attr(value: &[u8]) -> Option<Self> {
+        let nexthop = value.get(4..4 + *value.get(3)? as usize)?;
+        let octets16 = |buf: &[u8]| <[u8; 16]>::try_from(buf).unwrap();
+        match nexthop.len() {
+            4 => Some(Self {
+                nexthop: IpAddr::from(<[u8; 4]>::try_from(nexthop).unwrap()),
+                link_local: None,
+            }),
+            16 => Some(Self {
+                nexthop: IpAddr::from(octets16(nexthop)),
+                link_local: None,
+            }),
+            32 => Some(Self {
+                nexthop: IpAddr::from(octets16(&nexthop[..16])),
+                link_local: Some(Ipv6Addr::from(octets16(&nexthop[16..]))),
+            }),
+            // e.g. VPN next hops with route distinguisher
+            _ => None,
+        }
+    }
+}
+
+/// Information from the raw UPDATE message, used alongside the message decoded by zettabgp
+#[derive(Debug, Clone)]
+pub struct RawUpdate {
+    pub mp_nexthop: Option<MpReachNexthop>,
+    /// zettabgp flattens the AS_PATH segments, and can not decode 2-octet AS_PATHs
+    pub as_path: Option<Vec<AsPathSegment>>,
+    /// Whether the sender uses 4-octet AS numbers in AS_PATH and AGGREGATOR
+    pub four_octet_as: bool,
+    /// Address family if the message is an End-of-RIB marker
+    pub end_of_rib: Option<u16>,
+}
+
+impl RawUpdate {
+    pub fn parse(update: &[u8], four_octet_as: bool) -> Self {
+        let attrs = path_attributes(update).unwrap_or_default();
+        let mut raw = RawUpdate {
+            mp_nexthop: None,
+            as_path: None,
+            four_octet_as,
+            end_of_rib: end_of_rib(update, &attrs),
+        };
+        for (type_code, value) in attrs {
+            match type_code {
+                ATTR_AS_PATH => {
+                    raw.as_path = parse_as_path(value, if four_octet_as { 4 } else { 2 })
+                }
+                ATTR_MP_REACH_NLRI => raw.mp_nexthop = MpReachNexthop::from_attr(value),
+                _ => {}
+            }
+        }
+        raw
+    }
+}
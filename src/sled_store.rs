@@ -0,0 +1,830 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::Stream;
+use futures_util::StreamExt;
+use ipnet::IpNet;
+use log::*;
+use rayon::iter::IntoParallelIterator;
+use rayon::iter::ParallelIterator;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::history::{HistoryEvent, RouteEventKind};
+use crate::prefix_limit::{PrefixLimit, TableSize};
+use crate::stats::{CacheStats, StoreStats, TableStats};
+use crate::store::*;
+use crate::store_impl::{
+    nets_filter_fn, result_limits, router_ids, subscription, table_query_fn, NetsFilterItem,
+    SUBSCRIPTION_BUFFER,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SledStoreConfig {
+    /// Directory of the database, its contents are replaced on startup
+    pub path: PathBuf,
+    /// Bytes of the database kept in memory, defaults to 1 GiB
+    pub cache_capacity: Option<u64>,
+}
+
+/// Stored value of a path, referring to its attributes by their key in the attribute tree
+#[derive(Serialize, Deserialize)]
+struct StoredRoute {
+    attrs: u64,
+    times: RouteTimes,
+}
+
+/// Usage of the attribute tree, whose values are the number of paths using
+/// the attributes followed by the encoded attributes
+#[derive(Default)]
+struct AttrsCounters {
+    entries: AtomicUsize,
+    bytes: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    reclaimed: AtomicU64,
+}
+
+fn attrs_value(uses: u64, encoded: &[u8]) -> Vec<u8> {
+    let mut value = uses.to_be_bytes().to_vec();
+    value.extend_from_slice(encoded);
+    value
+}
+
+fn attrs_uses(value: &[u8]) -> u64 {
+    u64::from_be_bytes(value[..8].try_into().unwrap())
+}
+
+/// Table in the database, whose keys all start with its `id`
+#[derive(Clone)]
+struct SledTable {
+    id: u32,
+    /// Number of paths in the table
+    route_count: Arc<AtomicUsize>,
+}
+
+/// Store keeping the routes in an embedded database on disk instead of in memory.
+///
+/// Routes are keyed by table, address family, prefix and path ID, so all prefixes covered
+/// by a prefix are next to each other. Each distinct set of attributes is stored once, keyed
+/// by its hash. Clients and sessions are kept in memory.
+#[derive(Clone)]
+pub struct SledStore {
+    clients: Arc<Mutex<HashMap<SocketAddr, Client>>>,
+    prefix_limits: Arc<Mutex<HashMap<SocketAddr, PrefixLimit>>>,
+    sessions: Arc<Mutex<HashMap<SessionId, Session>>>,
+    tables: Arc<Mutex<HashMap<TableSelector, SledTable>>>,
+    next_table_id: Arc<AtomicU32>,
+
+    routes: sled::Tree,
+    attrs: sled::Tree,
+    attrs_counters: Arc<AttrsCounters>,
+    updates: broadcast::Sender<HistoryEvent>,
+    /// Keeps a temporary database from being removed while the store is in use
+    _db: sled::Db,
+}
+
+/// Key of an address in a table, followed by prefix length and path ID in route keys
+fn addr_key(table_id: u32, addr: IpAddr) -> Vec<u8> {
+    let mut key = table_id.to_be_bytes().to_vec();
+    match addr {
+        IpAddr::V4(addr) => {
+            key.push(4);
+            key.extend(addr.octets());
+        }
+        IpAddr::V6(addr) => {
+            key.push(6);
+            key.extend(addr.octets());
+        }
+    }
+    key
+}
+
+/// Key of `net` in a table, which is a prefix of the keys of all paths of `net`
+fn net_key(table_id: u32, net: &IpNet) -> Vec<u8> {
+    let mut key = addr_key(table_id, net.network());
+    key.push(net.prefix_len());
+    key
+}
+
+fn route_key(table_id: u32, net: &IpNet, path_id: PathId) -> Vec<u8> {
+    let mut key = net_key(table_id, net);
+    key.extend(path_id.to_be_bytes());
+    key
+}
+
+fn parse_route_key(key: &[u8]) -> Option<(IpNet, PathId)> {
+    let (addr, rest): (IpAddr, _) = match key.get(4)? {
+        4 => (<[u8; 4]>::try_from(key.get(5..9)?).ok()?.into(), &key[9..]),
+        6 => (
+            <[u8; 16]>::try_from(key.get(5..21)?).ok()?.into(),
+            &key[21..],
+        ),
+        _ => return None,
+    };
+    let [prefix_len, path_id @ ..] = rest else {
+        return None;
+    };
+    let net = IpNet::new(addr, *prefix_len).ok()?;
+    Some((net, PathId::from_be_bytes(path_id.try_into().ok()?)))
+}
+
+type Routes = Box<dyn Iterator<Item = (IpNet, PathId, StoredRoute)> + Send>;
+
+impl SledTable {
+    fn routes_with_prefix(&self, tree: &sled::Tree, prefix: Vec<u8>) -> Routes {
+        Box::new(decode_routes(tree.scan_prefix(prefix)))
+    }
+
+    /// Paths of the prefixes matched by the query, or of all prefixes
+    fn get_routes(&self, tree: &sled::Tree, net_query: Option<&NetQuery>) -> Routes {
+        match net_query {
+            None => self.routes_with_prefix(tree, self.id.to_be_bytes().to_vec()),
+            Some(NetQuery::Exact(net)) => self.routes_with_prefix(tree, net_key(self.id, net)),
+            Some(NetQuery::OrLonger(net)) => {
+                // all prefixes with an address within `net`, including shorter ones with the same address
+                let start = addr_key(self.id, net.network());
+                let mut end = addr_key(self.id, net.broadcast());
+                end.extend([u8::MAX; 5]);
+                let net = *net;
+                Box::new(
+                    decode_routes(tree.range(start..=end))
+                        .filter(move |(route_net, _, _)| net.contains(route_net)),
+                )
+            }
+            Some(NetQuery::Contains(net)) => {
+                let routes: Vec<_> = (0..=net.prefix_len())
+                    .flat_map(|prefix_len| {
+                        let covering = IpNet::new(net.addr(), prefix_len).unwrap();
+                        self.routes_with_prefix(tree, net_key(self.id, &covering))
+                    })
+                    .collect();
+                Box::new(routes.into_iter())
+            }
+            Some(NetQuery::MostSpecific(net)) => {
+                for prefix_len in (0..=net.prefix_len()).rev() {
+                    let covering = IpNet::new(net.addr(), prefix_len).unwrap();
+                    let routes: Vec<_> = self
+                        .routes_with_prefix(tree, net_key(self.id, &covering))
+                        .collect();
+                    if !routes.is_empty() {
+                        return Box::new(routes.into_iter());
+                    }
+                }
+                Box::new(std::iter::empty())
+            }
+        }
+    }
+}
+
+fn decode_routes(iter: sled::Iter) -> impl Iterator<Item = (IpNet, PathId, StoredRoute)> {
+    iter.filter_map(|entry| {
+        let (key, value) = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("failed to read route: {}", e);
+                return None;
+            }
+        };
+        let (net, path_id) = parse_route_key(&key)?;
+        match bincode::deserialize(&value) {
+            Ok(route) => Some((net, path_id, route)),
+            Err(e) => {
+                warn!("failed to decode route {}: {}", net, e);
+                None
+            }
+        }
+    })
+}
+
+impl SledStore {
+    pub fn open(cfg: &SledStoreConfig) -> anyhow::Result<Self> {
+        let mut db_cfg = sled::Config::new().path(&cfg.path);
+        if let Some(cache_capacity) = cfg.cache_capacity {
+            db_cfg = db_cfg.cache_capacity(cache_capacity);
+        }
+        Self::with_db(db_cfg.open()?)
+    }
+
+    /// Store in a database which is removed once the store is dropped, e.g. for tests
+    pub fn temporary() -> anyhow::Result<Self> {
+        Self::with_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn with_db(db: sled::Db) -> anyhow::Result<Self> {
+        // routes are sent again by the routers after a restart, without the clients they belong to
+        db.drop_tree("routes")?;
+        db.drop_tree("attrs")?;
+        Ok(Self {
+            clients: Default::default(),
+            prefix_limits: Default::default(),
+            sessions: Default::default(),
+            tables: Default::default(),
+            next_table_id: Default::default(),
+            routes: db.open_tree("routes")?,
+            attrs: db.open_tree("attrs")?,
+            attrs_counters: Default::default(),
+            updates: broadcast::channel(SUBSCRIPTION_BUFFER).0,
+            _db: db,
+        })
+    }
+
+    fn get_table(&self, sel: TableSelector) -> SledTable {
+        self.tables
+            .lock()
+            .unwrap()
+            .entry(sel)
+            .or_insert_with(|| SledTable {
+                id: self.next_table_id.fetch_add(1, Ordering::Relaxed),
+                route_count: Default::default(),
+            })
+            .clone()
+    }
+
+    fn send_event(&self, event: HistoryEvent) {
+        if self.updates.receiver_count() > 0 {
+            // fails only if all subscribers are gone in the meantime
+            let _ = self.updates.send(event);
+        }
+    }
+
+    /// Runs `f` on a thread which may block, as database operations may wait for the disk
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&SledStore) -> T + Send + 'static,
+    ) -> T {
+        let store = self.clone();
+        match tokio::task::spawn_blocking(move || f(&store)).await {
+            Ok(res) => res,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    /// Adds `uses` paths to the attribute set and returns its key.
+    /// Keys of different attribute sets with the same hash are probed linearly.
+    fn intern_attrs(&self, attrs: &RouteAttrs, uses: u64) -> sled::Result<u64> {
+        let encoded = bincode::serialize(attrs).unwrap();
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&encoded);
+        let mut key = hasher.finish();
+        loop {
+            let mut found = None;
+            self.attrs
+                .update_and_fetch(key.to_be_bytes(), |value| match value {
+                    None => {
+                        found = Some(false);
+                        Some(attrs_value(uses, &encoded))
+                    }
+                    Some(value) if value[8..] == encoded[..] => {
+                        found = Some(true);
+                        Some(attrs_value(attrs_uses(value) + uses, &encoded))
+                    }
+                    Some(value) => {
+                        found = None;
+                        Some(value.to_vec())
+                    }
+                })?;
+            match found {
+                Some(true) => {
+                    self.attrs_counters.hits.fetch_add(1, Ordering::Relaxed);
+                }
+                Some(false) => {
+                    let counters = &self.attrs_counters;
+                    counters.misses.fetch_add(1, Ordering::Relaxed);
+                    counters.entries.fetch_add(1, Ordering::Relaxed);
+                    counters.bytes.fetch_add(encoded.len(), Ordering::Relaxed);
+                }
+                None => {
+                    key = key.wrapping_add(1);
+                    continue;
+                }
+            }
+            return Ok(key);
+        }
+    }
+
+    /// Removes `uses` paths from the attribute set, and the set once it is no longer used
+    fn release_attrs(&self, key: u64, uses: u64) {
+        if uses == 0 {
+            return;
+        }
+        let mut removed = None;
+        let res = self
+            .attrs
+            .update_and_fetch(key.to_be_bytes(), |value| match value {
+                Some(value) if attrs_uses(value) > uses => {
+                    removed = None;
+                    Some(attrs_value(attrs_uses(value) - uses, &value[8..]))
+                }
+                Some(value) => {
+                    removed = Some(value.len() - 8);
+                    None
+                }
+                None => {
+                    removed = None;
+                    None
+                }
+            });
+        match res {
+            Ok(_) => {
+                if let Some(bytes) = removed {
+                    let counters = &self.attrs_counters;
+                    counters.reclaimed.fetch_add(1, Ordering::Relaxed);
+                    counters.entries.fetch_sub(1, Ordering::Relaxed);
+                    counters.bytes.fetch_sub(bytes, Ordering::Relaxed);
+                }
+            }
+            Err(e) => warn!("failed to release attributes: {}", e),
+        }
+    }
+
+    fn release_all_attrs(&self, keys: impl IntoIterator<Item = u64>) {
+        let mut uses: HashMap<u64, u64> = HashMap::new();
+        for key in keys {
+            *uses.entry(key).or_default() += 1;
+        }
+        for (key, uses) in uses {
+            self.release_attrs(key, uses);
+        }
+    }
+
+    fn get_attrs(&self, key: u64) -> Option<RouteAttrs> {
+        match self.attrs.get(key.to_be_bytes()) {
+            Ok(Some(value)) => bincode::deserialize(&value[8..]).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("failed to read attributes: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Removes the tables matched by `remove`, with all their routes
+    fn remove_tables(&self, remove: impl Fn(&TableSelector) -> bool) {
+        let mut removed = vec![];
+        self.tables.lock().unwrap().retain(|k, v| {
+            let remove = remove(k);
+            if remove {
+                removed.push((k.clone(), v.clone()));
+            }
+            !remove
+        });
+        let timestamp = Utc::now();
+        for (table_sel, table) in removed {
+            let mut batch = sled::Batch::default();
+            let mut attrs = vec![];
+            for (net, path_id, route) in table.get_routes(&self.routes, None) {
+                batch.remove(route_key(table.id, &net, path_id));
+                attrs.push(route.attrs);
+                self.send_event(HistoryEvent {
+                    timestamp,
+                    kind: RouteEventKind::Withdraw,
+                    net,
+                    path_id,
+                    table: table_sel.clone(),
+                    attrs: None,
+                });
+            }
+            if let Err(e) = self.routes.apply_batch(batch) {
+                warn!("failed to remove routes of {:?}: {}", table_sel, e);
+                continue;
+            }
+            self.release_all_attrs(attrs);
+        }
+    }
+
+    fn update_routes_blocking(
+        &self,
+        table: TableSelector,
+        attrs: RouteAttrs,
+        announced: Vec<(PathId, IpNet)>,
+        withdrawn: Vec<(PathId, IpNet)>,
+        timestamp: DateTime<Utc>,
+    ) -> PathCountChange {
+        let sled_table = self.get_table(table.clone());
+        let mut change = PathCountChange::default();
+        let attrs_key = if announced.is_empty() {
+            None
+        } else {
+            match self.intern_attrs(&attrs, announced.len() as u64) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    warn!("failed to write attributes: {}", e);
+                    return change;
+                }
+            }
+        };
+        let mut batch = sled::Batch::default();
+        let mut events = vec![];
+        // attributes of the replaced and withdrawn paths
+        let mut released = vec![];
+        let mut unused = 0;
+        for (path_id, net) in announced {
+            let attrs_key = attrs_key.unwrap();
+            let key = route_key(sled_table.id, &net, path_id);
+            let previous: Option<StoredRoute> = match self.routes.get(&key) {
+                Ok(previous) => previous.and_then(|value| bincode::deserialize(&value).ok()),
+                Err(e) => {
+                    warn!("failed to read route {}: {}", net, e);
+                    unused += 1;
+                    continue;
+                }
+            };
+            let (kind, times) = match previous {
+                Some(previous) if previous.attrs == attrs_key => {
+                    unused += 1;
+                    continue;
+                }
+                Some(previous) => {
+                    released.push(previous.attrs);
+                    (
+                        RouteEventKind::Change,
+                        RouteTimes {
+                            last_modified: timestamp,
+                            ..previous.times
+                        },
+                    )
+                }
+                None => (
+                    RouteEventKind::Announce,
+                    RouteTimes {
+                        first_seen: timestamp,
+                        last_modified: timestamp,
+                    },
+                ),
+            };
+            let route = StoredRoute {
+                attrs: attrs_key,
+                times,
+            };
+            batch.insert(key, bincode::serialize(&route).unwrap());
+            events.push((kind, net, path_id));
+        }
+        for (path_id, net) in withdrawn {
+            let key = route_key(sled_table.id, &net, path_id);
+            let previous: StoredRoute = match self.routes.get(&key) {
+                Ok(Some(value)) => match bincode::deserialize(&value) {
+                    Ok(previous) => previous,
+                    Err(_) => continue,
+                },
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("failed to read route {}: {}", net, e);
+                    continue;
+                }
+            };
+            batch.remove(key);
+            released.push(previous.attrs);
+            events.push((RouteEventKind::Withdraw, net, path_id));
+        }
+        if let Some(attrs_key) = attrs_key {
+            self.release_attrs(attrs_key, unused);
+        }
+        if events.is_empty() {
+            return change;
+        }
+        if let Err(e) = self.routes.apply_batch(batch) {
+            warn!("failed to write {} routes: {}", events.len(), e);
+            if let Some(attrs_key) = attrs_key {
+                let used = events
+                    .iter()
+                    .filter(|(kind, _, _)| *kind != RouteEventKind::Withdraw)
+                    .count();
+                self.release_attrs(attrs_key, used as u64);
+            }
+            return change;
+        }
+        self.release_all_attrs(released);
+
+        for (kind, net, path_id) in events {
+            match kind {
+                RouteEventKind::Announce => {
+                    sled_table.route_count.fetch_add(1, Ordering::Relaxed);
+                    change.added += 1;
+                }
+                RouteEventKind::Withdraw => {
+                    sled_table.route_count.fetch_sub(1, Ordering::Relaxed);
+                    change.removed += 1;
+                }
+                _ => {}
+            }
+            self.send_event(HistoryEvent {
+                timestamp,
+                kind,
+                net,
+                path_id,
+                table: table.clone(),
+                attrs: (kind != RouteEventKind::Withdraw).then(|| attrs.clone()),
+            });
+        }
+        change
+    }
+}
+
+#[async_trait]
+impl Store for SledStore {
+    #[autometrics::autometrics]
+    async fn update_route(
+        &self,
+        path_id: PathId,
+        net: IpNet,
+        table: TableSelector,
+        attrs: RouteAttrs,
+        timestamp: DateTime<Utc>,
+    ) {
+        self.blocking(move |store| {
+            store.update_routes_blocking(table, attrs, vec![(path_id, net)], vec![], timestamp)
+        })
+        .await;
+    }
+
+    #[autometrics::autometrics]
+    async fn withdraw_route(
+        &self,
+        path_id: PathId,
+        net: IpNet,
+        table: TableSelector,
+        timestamp: DateTime<Utc>,
+    ) {
+        self.blocking(move |store| {
+            store.update_routes_blocking(
+                table,
+                Default::default(),
+                vec![],
+                vec![(path_id, net)],
+                timestamp,
+            )
+        })
+        .await;
+    }
+
+    #[autometrics::autometrics]
+    async fn update_routes(
+        &self,
+        table: TableSelector,
+        attrs: RouteAttrs,
+        announced: Vec<(PathId, IpNet)>,
+        withdrawn: Vec<(PathId, IpNet)>,
+        timestamp: DateTime<Utc>,
+    ) -> PathCountChange {
+        self.blocking(move |store| {
+            store.update_routes_blocking(table, attrs, announced, withdrawn, timestamp)
+        })
+        .await
+    }
+
+    async fn stored_paths(
+        &self,
+        table: TableSelector,
+        paths: Vec<(PathId, IpNet)>,
+    ) -> Vec<(PathId, IpNet)> {
+        let Some(table) = self.tables.lock().unwrap().get(&table).cloned() else {
+            return vec![];
+        };
+        self.blocking(move |store| {
+            paths
+                .into_iter()
+                .filter(|(path_id, net)| {
+                    match store
+                        .routes
+                        .contains_key(route_key(table.id, net, *path_id))
+                    {
+                        Ok(stored) => stored,
+                        Err(e) => {
+                            warn!("failed to read route {}: {}", net, e);
+                            false
+                        }
+                    }
+                })
+                .collect()
+        })
+        .await
+    }
+
+    fn get_routes(
+        &self,
+        query: Query,
+    ) -> Result<Pin<Box<dyn Stream<Item = QueryResult> + Send>>, QueryError> {
+        if query.at.is_some() {
+            return Err(QueryError::HistoryDisabled);
+        }
+
+        let nets_filter_fn = nets_filter_fn(&query, Utc::now(), || {
+            let clients = self.clients.lock().unwrap().clone();
+            let sessions = self.sessions.lock().unwrap().clone();
+            (clients, sessions)
+        })?;
+
+        let table_filter = table_query_fn(
+            query.table_query.clone(),
+            router_ids(self.clients.lock().unwrap().clone()),
+        );
+        let tables: Vec<_> = self
+            .tables
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(table_sel, _)| table_filter(table_sel))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(2);
+
+        let (max_results, max_results_per_table) = result_limits(query.limits.clone());
+
+        let store = self.clone();
+        rayon::spawn(move || {
+            tables
+                .into_par_iter()
+                .flat_map(move |(table_sel, table)| {
+                    // most paths of a table share few attribute sets
+                    let mut attrs_cache: HashMap<u64, Option<RouteAttrs>> = HashMap::new();
+                    table
+                        .get_routes(&store.routes, Some(&query.net_query))
+                        .filter_map(|(net, _path_id, route)| {
+                            let attrs = attrs_cache
+                                .entry(route.attrs)
+                                .or_insert_with(|| store.get_attrs(route.attrs))
+                                .clone()?;
+                            Some((table_sel.clone(), net, attrs, route.times))
+                        })
+                        .filter(|item: &NetsFilterItem<RouteAttrs>| nets_filter_fn(item))
+                        .take(max_results_per_table)
+                        .collect::<Vec<_>>()
+                        .into_par_iter()
+                })
+                .for_each_with(tx, |tx, res| drop(tx.blocking_send(res)));
+        });
+
+        let clients = self.clients.clone();
+        let sessions = self.sessions.clone();
+        Ok(Box::pin(
+            ReceiverStream::new(rx)
+                .filter_map(move |(table, net, attrs, times)| {
+                    let client = clients.lock().unwrap().get(table.client_addr()).cloned();
+                    let session = table
+                        .session_id()
+                        .and_then(|session_id| sessions.lock().unwrap().get(session_id).cloned());
+                    futures_util::future::ready(match client {
+                        Some(client) => Some(QueryResult {
+                            state: table.route_state(),
+                            net,
+                            table,
+                            attrs,
+                            client,
+                            session,
+                            times,
+                        }),
+                        None => {
+                            warn!("client is not connected");
+                            None
+                        }
+                    })
+                })
+                .take(max_results),
+        ))
+    }
+
+    fn subscribe(
+        &self,
+        net_query: NetQuery,
+        table_query: Option<TableQuery>,
+    ) -> Pin<Box<dyn Stream<Item = Result<HistoryEvent, SubscriptionLagged>> + Send>> {
+        let clients = self.clients.clone();
+        let table_filter = table_query_fn(table_query, move |client_addr| {
+            let clients = clients.lock().unwrap();
+            clients.get(client_addr).map(|client| client.router_id)
+        });
+        subscription(&self.updates, net_query, table_filter)
+    }
+
+    fn has_history(&self) -> bool {
+        false
+    }
+
+    fn get_history(
+        &self,
+        _net: IpNet,
+        _table_query: Option<TableQuery>,
+    ) -> Option<Vec<HistoryEvent>> {
+        None
+    }
+
+    fn get_routers(&self) -> HashMap<SocketAddr, RouterInfo> {
+        let prefix_limits = self.prefix_limits.lock().unwrap();
+        let mut routers: HashMap<_, _> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(client_addr, client)| {
+                let router = RouterInfo {
+                    client: client.clone(),
+                    prefix_limit: prefix_limits.get(client_addr).cloned(),
+                    table_sizes: vec![],
+                };
+                (*client_addr, router)
+            })
+            .collect();
+        for (table, inner) in self.tables.lock().unwrap().iter() {
+            if let Some(router) = routers.get_mut(table.client_addr()) {
+                router.table_sizes.push(TableSize {
+                    table: table.clone(),
+                    prefixes: inner.route_count.load(Ordering::Relaxed),
+                });
+            }
+        }
+        routers
+    }
+
+    fn get_table_sizes(&self, client_addr: &SocketAddr) -> HashMap<TableSelector, usize> {
+        self.tables
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(table, _)| table.client_addr() == client_addr)
+            .map(|(table, inner)| (table.clone(), inner.route_count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    fn get_stats(&self) -> StoreStats {
+        let tables = self.tables.lock().unwrap().clone();
+        StoreStats {
+            tables: tables
+                .into_iter()
+                .map(|(table, inner)| TableStats {
+                    table,
+                    routes: inner.route_count.load(Ordering::Relaxed),
+                    memory_bytes: None,
+                })
+                .collect(),
+            // the attributes are stored on disk, so their size is the size in the database
+            caches: vec![CacheStats::new(
+                "route_attrs",
+                self.attrs_counters.entries.load(Ordering::Relaxed),
+                self.attrs_counters.bytes.load(Ordering::Relaxed),
+                self.attrs_counters.hits.load(Ordering::Relaxed),
+                self.attrs_counters.misses.load(Ordering::Relaxed),
+                self.attrs_counters.reclaimed.load(Ordering::Relaxed),
+            )],
+        }
+    }
+
+    async fn client_up(
+        &self,
+        client_addr: SocketAddr,
+        _route_state: RouteState,
+        client_data: Client,
+        prefix_limit: Option<PrefixLimit>,
+    ) {
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(client_addr, client_data);
+        let mut prefix_limits = self.prefix_limits.lock().unwrap();
+        match prefix_limit {
+            Some(prefix_limit) => prefix_limits.insert(client_addr, prefix_limit),
+            None => prefix_limits.remove(&client_addr),
+        };
+    }
+    async fn client_down(&self, client_addr: SocketAddr) {
+        self.clients.lock().unwrap().remove(&client_addr);
+        self.prefix_limits.lock().unwrap().remove(&client_addr);
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|k, _| k.from_client != client_addr);
+        self.blocking(move |store| {
+            store.remove_tables(|table| table.client_addr() == &client_addr)
+        })
+        .await;
+    }
+
+    async fn session_up(&self, session: SessionId, new_state: Session) {
+        self.sessions.lock().unwrap().insert(session, new_state);
+    }
+    async fn session_down(&self, session: SessionId, new_state: Option<Session>) {
+        if let Some(new_state) = new_state {
+            self.sessions
+                .lock()
+                .unwrap()
+                .insert(session.clone(), new_state);
+        } else {
+            self.sessions.lock().unwrap().remove(&session);
+        }
+        self.blocking(move |store| {
+            store.remove_tables(|table| table.session_id() == Some(&session))
+        })
+        .await;
+    }
+}
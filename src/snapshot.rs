@@ -0,0 +1,156 @@
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::store::*;
+use crate::store_impl::InMemoryStore;
+
+/// Changes whenever the file format changes, snapshots of other versions are ignored
+const SNAPSHOT_VERSION: u32 = 2;
+
+/// How often restored data is checked for expiry
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+fn default_interval() -> NonZeroU64 {
+    NonZeroU64::new(300).unwrap()
+}
+
+fn default_stale_timeout() -> u64 {
+    600
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// File the snapshot is written to and restored from
+    pub path: PathBuf,
+    /// Seconds between snapshots, in addition to the one on shutdown
+    #[serde(default = "default_interval")]
+    pub interval: NonZeroU64,
+    /// Seconds after which restored routes of a router are removed, counted
+    /// from startup or from the time the router reconnected
+    #[serde(default = "default_stale_timeout")]
+    pub stale_timeout: u64,
+}
+
+/// Routes of one table, referring to attributes in `Snapshot::attrs` by index
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotTable {
+    pub table: StoredTableSelector,
+    pub routes: Vec<(IpNet, PathId, usize, RouteTimes)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub time: DateTime<Utc>,
+    pub clients: Vec<(SocketAddr, Client)>,
+    pub sessions: Vec<(SessionId, Session)>,
+    /// Distinct route attributes, shared by all routes with the same attributes
+    pub attrs: Vec<RouteAttrs>,
+    pub tables: Vec<SnapshotTable>,
+}
+
+impl Snapshot {
+    pub fn new(time: DateTime<Utc>) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            time,
+            clients: vec![],
+            sessions: vec![],
+            attrs: vec![],
+            tables: vec![],
+        }
+    }
+}
+
+/// Writes the store to `path`, replacing the previous snapshot only once the new one is complete
+pub async fn write(store: &InMemoryStore, path: &Path) -> anyhow::Result<()> {
+    let store = store.clone();
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let snapshot = store.snapshot();
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &snapshot)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+        info!(
+            "wrote snapshot of {} tables to {}",
+            snapshot.tables.len(),
+            path.display()
+        );
+        Ok(())
+    })
+    .await?
+}
+
+/// Restores the snapshot at `path` into the store, if there is one
+pub async fn load(store: &InMemoryStore, cfg: &SnapshotConfig) -> anyhow::Result<()> {
+    let path = cfg.path.clone();
+    let snapshot = tokio::task::spawn_blocking(move || -> anyhow::Result<Option<Snapshot>> {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_json::from_reader(BufReader::new(file))?))
+    })
+    .await??;
+    let Some(snapshot) = snapshot else {
+        info!("no snapshot found at {}", cfg.path.display());
+        return Ok(());
+    };
+    if snapshot.version != SNAPSHOT_VERSION {
+        warn!(
+            "ignoring snapshot of version {}, expected {}",
+            snapshot.version, SNAPSHOT_VERSION
+        );
+        return Ok(());
+    }
+    info!(
+        "restoring snapshot of {} tables from {}",
+        snapshot.tables.len(),
+        snapshot.time
+    );
+    store.restore(
+        snapshot,
+        chrono::Duration::seconds(cfg.stale_timeout as i64),
+    );
+    Ok(())
+}
+
+/// Writes snapshots periodically and removes restored routes which are no longer needed
+pub async fn run(
+    cfg: SnapshotConfig,
+    store: InMemoryStore,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut snapshot_interval = tokio::time::interval(Duration::from_secs(cfg.interval.get()));
+    snapshot_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick completes immediately
+    snapshot_interval.tick().await;
+    let mut stale_interval = tokio::time::interval(STALE_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = snapshot_interval.tick() => {
+                if let Err(e) = write(&store, &cfg.path).await {
+                    warn!("failed to write snapshot: {}", e);
+                }
+            }
+            _ = stale_interval.tick() => {
+                store.remove_stale();
+            }
+            // the snapshot on shutdown is written before the collectors remove their routes
+            _ = shutdown.changed() => break Ok(()),
+        }
+    }
+}
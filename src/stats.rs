@@ -0,0 +1,164 @@
+use prometheus::{Encoder, GaugeVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use serde::Serialize;
+
+use crate::store::TableSelector;
+
+/// Approximate resource usage of a store
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StoreStats {
+    pub tables: Vec<TableStats>,
+    pub caches: Vec<CacheStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TableStats {
+    #[serde(flatten)]
+    pub table: TableSelector,
+    pub routes: usize,
+    /// Bytes used by the prefix tree and the path lists, without the shared attributes.
+    /// Only known for tables kept in memory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<usize>,
+}
+
+/// Interning set for one kind of attribute, shared by all tables
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub cache: &'static str,
+    /// Distinct values, including ones which are no longer used but not yet removed
+    pub entries: usize,
+    pub memory_bytes: usize,
+    /// Lookups which found an existing value
+    pub hits: u64,
+    /// Lookups which inserted a new value
+    pub misses: u64,
+    pub hit_ratio: f64,
+    /// Values removed after they were no longer used
+    pub reclaimed: u64,
+}
+
+impl CacheStats {
+    pub fn new(
+        cache: &'static str,
+        entries: usize,
+        memory_bytes: usize,
+        hits: u64,
+        misses: u64,
+        reclaimed: u64,
+    ) -> Self {
+        let lookups = hits + misses;
+        CacheStats {
+            cache,
+            entries,
+            memory_bytes,
+            hits,
+            misses,
+            hit_ratio: if lookups == 0 {
+                0.0
+            } else {
+                hits as f64 / lookups as f64
+            },
+            reclaimed,
+        }
+    }
+}
+
+/// Table type and peer address of a table, as used in metric labels
+pub fn table_labels(table: &TableSelector) -> (&'static str, String) {
+    match table {
+        TableSelector::PrePolicyAdjIn(session) => {
+            ("PrePolicyAdjIn", session.peer_address.to_string())
+        }
+        TableSelector::PostPolicyAdjIn(session) => {
+            ("PostPolicyAdjIn", session.peer_address.to_string())
+        }
+        TableSelector::LocRib { .. } => ("LocRib", String::new()),
+    }
+}
+
+pub fn encode_metrics(stats: &StoreStats) -> prometheus::Result<String> {
+    let table_memory = IntGaugeVec::new(
+        Opts::new(
+            "fernglas_table_memory_bytes",
+            "Approximate memory used by a table, without the shared attributes",
+        ),
+        &["client_addr", "table", "peer_address"],
+    )?;
+    for table_stats in &stats.tables {
+        let Some(memory_bytes) = table_stats.memory_bytes else {
+            continue;
+        };
+        let client_addr = table_stats.table.client_addr().to_string();
+        let (table, peer_address) = table_labels(&table_stats.table);
+        table_memory
+            .with_label_values(&[&client_addr, table, &peer_address])
+            .set(memory_bytes as i64);
+    }
+
+    let cache_entries = IntGaugeVec::new(
+        Opts::new(
+            "fernglas_cache_entries",
+            "Distinct values in an attribute cache",
+        ),
+        &["cache"],
+    )?;
+    let cache_memory = IntGaugeVec::new(
+        Opts::new(
+            "fernglas_cache_memory_bytes",
+            "Approximate memory used by an attribute cache",
+        ),
+        &["cache"],
+    )?;
+    let cache_lookups = IntCounterVec::new(
+        Opts::new(
+            "fernglas_cache_lookups_total",
+            "Lookups in an attribute cache, by whether the value already existed",
+        ),
+        &["cache", "result"],
+    )?;
+    let cache_hit_ratio = GaugeVec::new(
+        Opts::new(
+            "fernglas_cache_hit_ratio",
+            "Share of lookups in an attribute cache which found an existing value",
+        ),
+        &["cache"],
+    )?;
+    let cache_reclaimed = IntCounterVec::new(
+        Opts::new(
+            "fernglas_cache_reclaimed_total",
+            "Values removed from an attribute cache after they were no longer used",
+        ),
+        &["cache"],
+    )?;
+    for cache in &stats.caches {
+        cache_entries
+            .with_label_values(&[cache.cache])
+            .set(cache.entries as i64);
+        cache_memory
+            .with_label_values(&[cache.cache])
+            .set(cache.memory_bytes as i64);
+        cache_lookups
+            .with_label_values(&[cache.cache, "hit"])
+            .inc_by(cache.hits);
+        cache_lookups
+            .with_label_values(&[cache.cache, "miss"])
+            .inc_by(cache.misses);
+        cache_hit_ratio
+            .with_label_values(&[cache.cache])
+            .set(cache.hit_ratio);
+        cache_reclaimed
+            .with_label_values(&[cache.cache])
+            .inc_by(cache.reclaimed);
+    }
+
+    let registry = Registry::new();
+    registry.register(Box::new(table_memory))?;
+    registry.register(Box::new(cache_entries))?;
+    registry.register(Box::new(cache_memory))?;
+    registry.register(Box::new(cache_lookups))?;
+    registry.register(Box::new(cache_hit_ratio))?;
+    registry.register(Box::new(cache_reclaimed))?;
+    let mut buf = vec![];
+    TextEncoder::new().encode(&registry.gather(), &mut buf)?;
+    Ok(String::from_utf8(buf).unwrap())
+}
@@ -1,23 +1,37 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use futures_util::Stream;
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use log::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::pin::Pin;
 
+use crate::history::HistoryEvent;
+use crate::import_filter::ImportFilter;
+use crate::prefix_limit::{PrefixLimit, TableSize};
+use crate::raw_update::{
+    parse_as_path, AsPathSegment, MpReachNexthop, RawUpdate, AS_CONFED_SEQUENCE, AS_CONFED_SET,
+    AS_SEQUENCE, AS_SET, AS_TRANS,
+};
+use crate::stats::StoreStats;
+
+const ATTR_AS4_PATH: u8 = 17;
+const ATTR_AS4_AGGREGATOR: u8 = 18;
+const ATTR_OTC: u8 = 35;
+
 pub type PathId = u32;
 pub type RouterId = Ipv4Addr;
 
-#[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum RouteOrigin {
     Igp,
     Egp,
     Incomplete,
 }
 
-#[derive(Debug, Clone, Serialize, Default)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
 pub struct RouteAttrs {
     pub origin: Option<RouteOrigin>,
     pub as_path: Option<Vec<u32>>,
@@ -26,6 +40,12 @@ pub struct RouteAttrs {
     pub med: Option<u32>,
     pub local_pref: Option<u32>,
     pub nexthop: Option<IpAddr>,
+    /// Link-local address sent alongside a global IPv6 next hop
+    pub nexthop_link_local: Option<Ipv6Addr>,
+    /// AS number and router ID of the AGGREGATOR attribute
+    pub aggregator: Option<(u32, Ipv4Addr)>,
+    /// AS number of the Only to Customer attribute (RFC 9234)
+    pub otc: Option<u32>,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
@@ -60,6 +80,53 @@ pub enum TableSelector {
     },
 }
 
+/// `TableSelector` including the route state of Loc-RIB tables, for the files written by fernglas
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StoredTableSelector {
+    PrePolicyAdjIn(SessionId),
+    PostPolicyAdjIn(SessionId),
+    LocRib {
+        from_client: SocketAddr,
+        route_state: RouteState,
+    },
+}
+
+impl From<&TableSelector> for StoredTableSelector {
+    fn from(table: &TableSelector) -> Self {
+        match table.clone() {
+            TableSelector::PrePolicyAdjIn(session) => StoredTableSelector::PrePolicyAdjIn(session),
+            TableSelector::PostPolicyAdjIn(session) => {
+                StoredTableSelector::PostPolicyAdjIn(session)
+            }
+            TableSelector::LocRib {
+                from_client,
+                route_state,
+            } => StoredTableSelector::LocRib {
+                from_client,
+                route_state,
+            },
+        }
+    }
+}
+
+impl From<StoredTableSelector> for TableSelector {
+    fn from(table: StoredTableSelector) -> Self {
+        match table {
+            StoredTableSelector::PrePolicyAdjIn(session) => TableSelector::PrePolicyAdjIn(session),
+            StoredTableSelector::PostPolicyAdjIn(session) => {
+                TableSelector::PostPolicyAdjIn(session)
+            }
+            StoredTableSelector::LocRib {
+                from_client,
+                route_state,
+            } => TableSelector::LocRib {
+                from_client,
+                route_state,
+            },
+        }
+    }
+}
+
 impl TableSelector {
     pub fn client_addr(&self) -> &SocketAddr {
         match self {
@@ -111,8 +178,45 @@ pub struct Query<T = IpNet> {
     pub limits: Option<QueryLimits>,
     #[serde(default)]
     pub as_path_regex: Option<String>,
+    /// Only return routes which the Only to Customer attribute marks as leaked
+    #[serde(default)]
+    pub route_leak: bool,
+    /// Only return routes first seen at least this many seconds ago
+    #[serde(default)]
+    pub min_age: Option<u64>,
+    /// Only return routes first seen at most this many seconds ago
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    /// Return the routes as they were at this time, reconstructed from the route history
+    #[serde(default)]
+    pub at: Option<DateTime<Utc>>,
 }
 
+/// Reasons a query is rejected before any routes are returned
+#[derive(Debug)]
+pub enum QueryError {
+    /// `as_path_regex` is not a valid regular expression
+    InvalidAsPathRegex(regex::Error),
+    /// `min_age` is larger than `max_age`
+    InvalidAgeRange { min_age: u64, max_age: u64 },
+    /// Routes at a past time or the history of a prefix were requested, but no route history is kept
+    HistoryDisabled,
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueryError::InvalidAsPathRegex(e) => write!(f, "invalid as_path_regex: {}", e),
+            QueryError::InvalidAgeRange { min_age, max_age } => {
+                write!(f, "min_age {} is larger than max_age {}", min_age, max_age)
+            }
+            QueryError::HistoryDisabled => write!(f, "route history is not enabled"),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
 #[derive(Debug, Clone, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct QueryResult {
@@ -126,6 +230,17 @@ pub struct QueryResult {
     pub session: Option<Session>,
     #[serde(flatten)]
     pub attrs: RouteAttrs,
+    #[serde(flatten)]
+    pub times: RouteTimes,
+}
+
+/// When a path was received
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteTimes {
+    /// When the path was announced, unchanged by later updates of its attributes
+    pub first_seen: DateTime<Utc>,
+    /// When the attributes of the path last changed
+    pub last_modified: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -139,11 +254,143 @@ pub struct QueryLimits {
 pub struct Client {
     pub client_name: String,
     pub router_id: RouterId, // Router ID used for LocRib
+    /// System description reported by the router, usually model and software version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sys_descr: Option<String>,
+    /// Free-form information strings reported by the router
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub info_strings: Vec<String>,
+    /// How the previous connection from this router ended
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_termination: Option<ClientTermination>,
+    /// The session with the collector itself, if the router sends its routes over BGP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bgp_session: Option<Session>,
+    /// Restored from a snapshot, the routes may be outdated
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stale: bool,
+}
+
+/// A connected router together with the state of its tables, as returned by `Store::get_routers`
+#[derive(Debug, Clone, Serialize)]
+pub struct RouterInfo {
+    #[serde(flatten)]
+    pub client: Client,
+    /// Prefix limits configured for this router
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix_limit: Option<PrefixLimit>,
+    /// Number of prefixes in each table of this router
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub table_sizes: Vec<TableSize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientTermination {
+    pub time: DateTime<Utc>,
+    pub reason: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
 }
 
 /// information saved about a connected peer
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
-pub struct Session {}
+pub struct Session {
+    /// Set if routes of this session may be missing, e.g. because messages were received out of order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incomplete_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_asn: Option<u32>,
+    /// Role of the receiving router in this session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_role: Option<BgpRole>,
+    /// Role advertised by the peer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_role: Option<BgpRole>,
+    /// Negotiated hold time in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold_time: Option<u16>,
+    /// Interval between keepalives sent by the receiving router, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_interval: Option<u16>,
+    /// Hostname advertised by the receiving router
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_fqdn: Option<String>,
+    /// When the session was established
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub established: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    /// Whether a route with this OTC value received in this session is a route leak (RFC 9234, section 5)
+    pub fn is_route_leak(&self, otc: Option<u32>) -> bool {
+        let Some(otc) = otc else {
+            return false;
+        };
+        match self.local_role {
+            // received from a customer or route server client
+            Some(BgpRole::Provider) | Some(BgpRole::RouteServer) => true,
+            Some(BgpRole::Peer) => self.peer_asn.is_some_and(|asn| asn != otc),
+            _ => false,
+        }
+    }
+}
+
+/// Role of a BGP speaker in a session (RFC 9234)
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub enum BgpRole {
+    Provider,
+    RouteServer,
+    RouteServerClient,
+    Customer,
+    Peer,
+}
+
+impl BgpRole {
+    /// Decodes the value of a BGP Role capability
+    pub fn from_capability(data: &[u8]) -> Option<Self> {
+        match data {
+            [0] => Some(BgpRole::Provider),
+            [1] => Some(BgpRole::RouteServer),
+            [2] => Some(BgpRole::RouteServerClient),
+            [3] => Some(BgpRole::Customer),
+            [4] => Some(BgpRole::Peer),
+            _ => None,
+        }
+    }
+    pub fn to_capability(self) -> Vec<u8> {
+        vec![match self {
+            BgpRole::Provider => 0,
+            BgpRole::RouteServer => 1,
+            BgpRole::RouteServerClient => 2,
+            BgpRole::Customer => 3,
+            BgpRole::Peer => 4,
+        }]
+    }
+    /// The role the other side of the session is expected to have
+    pub fn counterpart(self) -> Self {
+        match self {
+            BgpRole::Provider => BgpRole::Customer,
+            BgpRole::RouteServer => BgpRole::RouteServerClient,
+            BgpRole::RouteServerClient => BgpRole::RouteServer,
+            BgpRole::Customer => BgpRole::Provider,
+            BgpRole::Peer => BgpRole::Peer,
+        }
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self {
+            client_name: String::new(),
+            router_id: Ipv4Addr::UNSPECIFIED,
+            sys_descr: None,
+            info_strings: vec![],
+            last_termination: None,
+            bgp_session: None,
+            stale: false,
+        }
+    }
+}
 
 impl Default for QueryLimits {
     fn default() -> Self {
@@ -162,19 +409,96 @@ pub trait Store: Clone + Send + Sync + 'static {
         net: IpNet,
         table: TableSelector,
         attrs: RouteAttrs,
+        timestamp: DateTime<Utc>,
     );
 
-    async fn withdraw_route(&self, path_id: PathId, net: IpNet, table: TableSelector);
+    async fn withdraw_route(
+        &self,
+        path_id: PathId,
+        net: IpNet,
+        table: TableSelector,
+        timestamp: DateTime<Utc>,
+    );
+
+    /// Announces paths sharing the same attributes and withdraws others in one table,
+    /// e.g. all NLRIs of a BGP UPDATE message. `attrs` are ignored if nothing is announced.
+    async fn update_routes(
+        &self,
+        table: TableSelector,
+        attrs: RouteAttrs,
+        announced: Vec<(PathId, IpNet)>,
+        withdrawn: Vec<(PathId, IpNet)>,
+        timestamp: DateTime<Utc>,
+    ) -> PathCountChange {
+        let paths = announced.iter().chain(&withdrawn).cloned().collect();
+        let mut stored: HashSet<_> = self
+            .stored_paths(table.clone(), paths)
+            .await
+            .into_iter()
+            .collect();
+        let mut change = PathCountChange::default();
+        for (path_id, net) in announced {
+            if stored.insert((path_id, net)) {
+                change.added += 1;
+            }
+            self.update_route(path_id, net, table.clone(), attrs.clone(), timestamp)
+                .await;
+        }
+        for (path_id, net) in withdrawn {
+            if stored.remove(&(path_id, net)) {
+                change.removed += 1;
+            }
+            self.withdraw_route(path_id, net, table.clone(), timestamp)
+                .await;
+        }
+        change
+    }
+
+    /// Those of `paths` which are stored in the table
+    async fn stored_paths(
+        &self,
+        table: TableSelector,
+        paths: Vec<(PathId, IpNet)>,
+    ) -> Vec<(PathId, IpNet)>;
+
+    /// Routes matching the query, or an error if the query is invalid
+    fn get_routes(
+        &self,
+        query: Query,
+    ) -> Result<Pin<Box<dyn Stream<Item = QueryResult> + Send>>, QueryError>;
+
+    /// Live changes of routes matching the query.
+    ///
+    /// Subscribers which fall too far behind are dropped with `SubscriptionLagged` as last
+    /// item, so they never slow down the collectors.
+    fn subscribe(
+        &self,
+        net_query: NetQuery,
+        table_query: Option<TableQuery>,
+    ) -> Pin<Box<dyn Stream<Item = Result<HistoryEvent, SubscriptionLagged>> + Send>>;
+
+    /// Whether route history is kept, which is required for `get_history` and `Query::at`
+    fn has_history(&self) -> bool;
+
+    /// Recorded events of exactly `net`, oldest first, or `None` if no history is kept
+    fn get_history(&self, net: IpNet, table_query: Option<TableQuery>)
+        -> Option<Vec<HistoryEvent>>;
+
+    fn get_routers(&self) -> HashMap<SocketAddr, RouterInfo>;
 
-    fn get_routes(&self, query: Query) -> Pin<Box<dyn Stream<Item = QueryResult> + Send>>;
+    /// Number of prefixes in each table of the client
+    fn get_table_sizes(&self, client_addr: &SocketAddr) -> HashMap<TableSelector, usize>;
 
-    fn get_routers(&self) -> HashMap<SocketAddr, Client>;
+    /// Approximate memory usage of the tables and attribute caches.
+    /// Walks all tables, so this should not be called too often.
+    fn get_stats(&self) -> StoreStats;
 
     async fn client_up(
         &self,
         client_addr: SocketAddr,
         route_state: RouteState,
         client_data: Client,
+        prefix_limit: Option<PrefixLimit>,
     );
 
     async fn client_down(&self, client_addr: SocketAddr);
@@ -183,23 +507,106 @@ pub trait Store: Clone + Send + Sync + 'static {
 
     async fn session_down(&self, session: SessionId, new_state: Option<Session>);
 
+    /// The table received all routes of the address family `afi` after the session was established
+    async fn end_of_rib(&self, _table: TableSelector, _afi: u16) {}
+
     async fn insert_bgp_update(
         &self,
         session: TableSelector,
         update: zettabgp::prelude::BgpUpdateMessage,
+        raw: RawUpdate,
+        filter: &ImportFilter,
+        timestamp: DateTime<Utc>,
     ) {
+        let update = RouteUpdate::from_bgp_update(update, raw, filter);
+        self.insert_route_update(session, update, timestamp).await;
+    }
+
+    /// Applies the withdrawals and then the announcements of an update to the table
+    async fn insert_route_update(
+        &self,
+        table: TableSelector,
+        update: RouteUpdate,
+        timestamp: DateTime<Utc>,
+    ) -> PathCountChange {
+        let mut change = PathCountChange::default();
+        if !update.withdrawn.is_empty() {
+            change += self
+                .update_routes(
+                    table.clone(),
+                    Default::default(),
+                    vec![],
+                    update.withdrawn,
+                    timestamp,
+                )
+                .await;
+        }
+        for (attrs, paths) in update.announced {
+            change += self
+                .update_routes(table.clone(), attrs, paths, vec![], timestamp)
+                .await;
+        }
+        change
+    }
+}
+
+/// Last item of a subscription whose subscriber fell too far behind and was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionLagged;
+
+/// Paths added to and removed from a table
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathCountChange {
+    pub added: usize,
+    pub removed: usize,
+}
+
+impl std::ops::AddAssign for PathCountChange {
+    fn add_assign(&mut self, other: Self) {
+        self.added += other.added;
+        self.removed += other.removed;
+    }
+}
+
+/// Paths announced and withdrawn by a BGP UPDATE message, after the import filter
+#[derive(Debug, Clone, Default)]
+pub struct RouteUpdate {
+    /// Announced paths, grouped by their attributes
+    pub announced: Vec<(RouteAttrs, Vec<(PathId, IpNet)>)>,
+    pub withdrawn: Vec<(PathId, IpNet)>,
+    /// Address family of an End-of-RIB marker
+    pub end_of_rib: Option<u16>,
+}
+
+impl RouteUpdate {
+    pub fn from_bgp_update(
+        update: zettabgp::prelude::BgpUpdateMessage,
+        raw: RawUpdate,
+        filter: &ImportFilter,
+    ) -> Self {
         use zettabgp::prelude::*;
         let mut attrs: RouteAttrs = Default::default();
-        let mut nexthop = None;
+        let mut as_path = None;
+        let mut as4_path = None;
+        let mut aggregator = None;
+        let mut as4_aggregator = None;
+        let mut nexthop = (None, None);
         let mut update_nets = vec![];
         let mut withdraw_nets = vec![];
         for attr in update.attrs {
             match attr {
                 BgpAttrItem::MPUpdates(updates) => {
-                    let nexthop = match updates.nexthop {
-                        BgpAddr::V4(v4) => Some(IpAddr::from(v4)),
-                        BgpAddr::V6(v6) => Some(IpAddr::from(v6)),
-                        _ => None,
+                    let nexthop = match (raw.mp_nexthop, updates.nexthop) {
+                        (
+                            Some(MpReachNexthop {
+                                nexthop,
+                                link_local,
+                            }),
+                            _,
+                        ) => (Some(nexthop), link_local),
+                        (None, BgpAddr::V4(v4)) => (Some(IpAddr::from(v4)), None),
+                        (None, BgpAddr::V6(v6)) => (Some(IpAddr::from(v6)), None),
+                        _ => (None, None),
                     };
                     for net in bgp_addrs_to_nets(&updates.addrs) {
                         update_nets.push((net, nexthop));
@@ -211,7 +618,7 @@ pub trait Store: Clone + Send + Sync + 'static {
                     }
                 }
                 BgpAttrItem::NextHop(BgpNextHop { value }) => {
-                    nexthop = Some(value);
+                    nexthop = (Some(value), None);
                 }
                 BgpAttrItem::CommunityList(BgpCommunityList { value }) => {
                     let mut communities = vec![];
@@ -237,12 +644,24 @@ pub trait Store: Clone + Send + Sync + 'static {
                     })
                 }
                 BgpAttrItem::ASPath(BgpASpath { value }) => {
-                    let mut as_path = vec![];
-                    for asn in value {
-                        as_path.push(asn.value);
-                    }
-                    attrs.as_path = Some(as_path);
+                    let asns = value.into_iter().map(|asn| asn.value).collect();
+                    as_path = Some(vec![(AS_SEQUENCE, asns)]);
+                }
+                BgpAttrItem::AggregatorAS(BgpAggregatorAS { asn, addr }) => {
+                    aggregator = Some((asn, addr));
                 }
+                BgpAttrItem::Unknown(BgpAttrUnknown { params, value }) => match params.typecode {
+                    ATTR_AS4_PATH => as4_path = parse_as_path(&value, 4),
+                    ATTR_AS4_AGGREGATOR if value.len() == 8 => {
+                        let asn = u32::from_be_bytes(value[0..4].try_into().unwrap());
+                        let addr = Ipv4Addr::from(<[u8; 4]>::try_from(&value[4..8]).unwrap());
+                        as4_aggregator = Some((asn, addr));
+                    }
+                    ATTR_OTC if value.len() == 4 => {
+                        attrs.otc = Some(u32::from_be_bytes(value[..].try_into().unwrap()));
+                    }
+                    _ => {}
+                },
                 BgpAttrItem::LargeCommunityList(BgpLargeCommunityList { value }) => {
                     let mut communities = vec![];
                     for community in value.into_iter() {
@@ -253,6 +672,21 @@ pub trait Store: Clone + Send + Sync + 'static {
                 _ => {}
             }
         }
+        let mut as_path = raw.as_path.or(as_path);
+        // AS4_PATH and AS4_AGGREGATOR are only meaningful when received from a 2-octet speaker
+        if !raw.four_octet_as {
+            match (aggregator, as4_aggregator) {
+                (Some((asn, _)), Some(_)) if asn != AS_TRANS => as4_path = None,
+                (Some(_), Some(as4_aggregator)) => aggregator = Some(as4_aggregator),
+                _ => {}
+            }
+            if let Some(as4_path) = as4_path {
+                as_path = as_path.map(|path| merge_as4_path(path, as4_path));
+            }
+        }
+        attrs.as_path = as_path.map(|path| path.into_iter().flat_map(|(_, asns)| asns).collect());
+        attrs.aggregator = aggregator;
+
         for net in bgp_addrs_to_nets(&update.updates).into_iter() {
             update_nets.push((net, nexthop));
         }
@@ -260,16 +694,73 @@ pub trait Store: Clone + Send + Sync + 'static {
             withdraw_nets.push(net);
         }
 
-        for (net, nexthop) in update_nets {
+        // nets with the same attributes after filtering are stored together,
+        // which usually is all of them
+        let mut batches: Vec<(RouteAttrs, Vec<(PathId, IpNet)>)> = vec![];
+        for (net, (nexthop, nexthop_link_local)) in update_nets {
             let mut attrs = attrs.clone();
             attrs.nexthop = nexthop;
-            self.update_route(net.0, net.1, session.clone(), attrs)
-                .await;
+            attrs.nexthop_link_local = nexthop_link_local;
+            match filter.apply(&net.1, attrs) {
+                Some(attrs) => match batches.iter_mut().find(|(a, _)| *a == attrs) {
+                    Some((_, nets)) => nets.push(net),
+                    None => batches.push((attrs, vec![net])),
+                },
+                // an earlier version of the route may have been imported
+                None => withdraw_nets.push(net),
+            }
+        }
+        RouteUpdate {
+            announced: batches,
+            withdrawn: withdraw_nets,
+            end_of_rib: raw.end_of_rib,
         }
-        for net in withdraw_nets {
-            self.withdraw_route(net.0, net.1, session.clone()).await;
+    }
+}
+
+/// Reconstructs the AS path of a route received from a 2-octet speaker (RFC 6793, section 4.2.3)
+fn merge_as4_path(as_path: Vec<AsPathSegment>, as4_path: Vec<AsPathSegment>) -> Vec<AsPathSegment> {
+    let count = |path: &[AsPathSegment]| -> usize {
+        path.iter()
+            .map(|(segment_type, asns)| match *segment_type {
+                AS_SEQUENCE => asns.len(),
+                AS_SET => 1,
+                _ => 0,
+            })
+            .sum()
+    };
+    // confederation segments are not allowed in AS4_PATH
+    let as4_path: Vec<_> = as4_path
+        .into_iter()
+        .filter(|(segment_type, _)| {
+            *segment_type != AS_CONFED_SEQUENCE && *segment_type != AS_CONFED_SET
+        })
+        .collect();
+    let (as_path_count, as4_path_count) = (count(&as_path), count(&as4_path));
+    if as_path_count < as4_path_count {
+        return as_path;
+    }
+
+    // the leading ASes of AS_PATH were added by 2-octet speakers and are missing from AS4_PATH
+    let mut leading = as_path_count - as4_path_count;
+    let mut merged = vec![];
+    for (segment_type, asns) in as_path {
+        match segment_type {
+            AS_SEQUENCE if leading > 0 => {
+                let take = std::cmp::min(leading, asns.len());
+                merged.push((AS_SEQUENCE, asns[..take].to_vec()));
+                leading -= take;
+            }
+            AS_SET if leading > 0 => {
+                merged.push((AS_SET, asns));
+                leading -= 1;
+            }
+            AS_SEQUENCE | AS_SET => {}
+            _ => merged.push((segment_type, asns)),
         }
     }
+    merged.extend(as4_path);
+    merged
 }
 
 fn bgp_addrs_to_nets(addrs: &zettabgp::prelude::BgpAddrs) -> Vec<(PathId, IpNet)> {
@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use futures_util::Stream;
 use futures_util::StreamExt;
 use ipnet::IpNet;
@@ -6,24 +7,142 @@ use log::*;
 use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
 use regex::Regex;
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::num::NonZeroU64;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::broadcast;
 use tokio_stream::wrappers::ReceiverStream;
 
 use crate::compressed_attrs::*;
+use crate::history::{
+    History, HistoryConfig, HistoryEvent, RouteEvent, RouteEventKind, TablesInUse,
+    SHARDS as HISTORY_SHARDS, SWEEP_INTERVAL,
+};
+use crate::prefix_limit::{PrefixLimit, TableSize};
+use crate::raw_update::{AFI_IPV4, AFI_IPV6};
+use crate::snapshot::{Snapshot, SnapshotTable};
+use crate::stats::{StoreStats, TableStats};
 use crate::store::*;
 use crate::table_impl::*;
 
-#[derive(Default, Clone)]
+pub(crate) type NetsFilterItem<A = Arc<CompressedRouteAttrs>> =
+    (TableSelector, IpNet, A, RouteTimes);
+type NetsFilterFn<A> = Box<dyn Fn(&NetsFilterItem<A>) -> bool + Send + Sync>;
+
+/// Route attributes as seen by the query filters, in whichever form a store keeps them
+pub(crate) trait FilterAttrs {
+    fn as_path(&self) -> Option<&[u32]>;
+    fn otc(&self) -> Option<u32>;
+}
+
+impl FilterAttrs for Arc<CompressedRouteAttrs> {
+    fn as_path(&self) -> Option<&[u32]> {
+        self.as_path.as_deref().map(|as_path| &as_path[..])
+    }
+    fn otc(&self) -> Option<u32> {
+        self.otc
+    }
+}
+
+impl FilterAttrs for RouteAttrs {
+    fn as_path(&self) -> Option<&[u32]> {
+        self.as_path.as_deref()
+    }
+    fn otc(&self) -> Option<u32> {
+        self.otc
+    }
+}
+
+/// Number of route changes a subscriber may fall behind before it is dropped
+pub(crate) const SUBSCRIPTION_BUFFER: usize = 4096;
+
+#[derive(Clone)]
 pub struct InMemoryStore {
-    clients: Arc<Mutex<HashMap<SocketAddr, Client>>>,
-    sessions: Arc<Mutex<HashMap<SessionId, Session>>>,
-    tables: Arc<Mutex<HashMap<TableSelector, InMemoryTable>>>,
+    clients: Arc<RwLock<HashMap<SocketAddr, Client>>>,
+    prefix_limits: Arc<RwLock<HashMap<SocketAddr, PrefixLimit>>>,
+    sessions: Arc<RwLock<HashMap<SessionId, Session>>>,
+    tables: Arc<RwLock<HashMap<TableSelector, InMemoryTable>>>,
+
+    pub(crate) caches: Arc<Caches>,
+    pub(crate) history: Option<Arc<History>>,
+    updates: broadcast::Sender<HistoryEvent>,
+    stale: Arc<RwLock<StaleClients>>,
+}
+
+/// Clients, sessions and tables restored from a snapshot.
+///
+/// They are kept apart from those of connected clients, so that a router reconnecting
+/// from the same address does not mix its new routes with the restored ones. A restored
+/// table is removed once the table of the reconnected router received an End-of-RIB
+/// marker for each address family, or once the client expires.
+#[derive(Default)]
+struct StaleClients {
+    /// Time restored clients are kept after startup or after the router reconnected
+    timeout: chrono::Duration,
+    /// Last known state of each client, and the time it expires
+    clients: HashMap<SocketAddr, (Client, DateTime<Utc>)>,
+    sessions: HashMap<SessionId, Session>,
+    tables: HashMap<TableSelector, InMemoryTable>,
+}
+
+fn default_cache_gc_interval() -> NonZeroU64 {
+    NonZeroU64::new(60).unwrap()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheGcConfig {
+    /// Seconds between removing attributes which are no longer used by any route from the caches
+    #[serde(default = "default_cache_gc_interval")]
+    pub interval: NonZeroU64,
+}
+
+impl Default for CacheGcConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_cache_gc_interval(),
+        }
+    }
+}
+
+fn default_stats_interval() -> NonZeroU64 {
+    NonZeroU64::new(60).unwrap()
+}
 
-    caches: Arc<Mutex<Caches>>,
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatsConfig {
+    /// Seconds between measuring the memory used by each table of the in-memory store
+    #[serde(default = "default_stats_interval")]
+    pub interval: NonZeroU64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_stats_interval(),
+        }
+    }
+}
+
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        Self {
+            clients: Default::default(),
+            prefix_limits: Default::default(),
+            sessions: Default::default(),
+            tables: Default::default(),
+            caches: Default::default(),
+            history: None,
+            updates: broadcast::channel(SUBSCRIPTION_BUFFER).0,
+            stale: Default::default(),
+        }
+    }
 }
 
 fn tables_for_client_fn(
@@ -36,7 +155,396 @@ fn tables_for_session_fn(
 ) -> impl Fn(&(&TableSelector, &InMemoryTable)) -> bool + '_ {
     move |(k, _): &(_, _)| k.session_id() == Some(session_id)
 }
+
+/// Filter for the routes matched by the query, with the age of routes measured at `now`.
+///
+/// Fails if the filters of the query are invalid, so this is called before looking up any routes.
+pub(crate) fn nets_filter_fn<A: FilterAttrs + 'static>(
+    query: &Query,
+    now: DateTime<Utc>,
+    snapshot: impl FnOnce() -> (HashMap<SocketAddr, Client>, HashMap<SessionId, Session>),
+) -> Result<NetsFilterFn<A>, QueryError> {
+    let mut nets_filter_fn: NetsFilterFn<A> = Box::new(|_| true);
+
+    if let Some(as_path_regex) = &query.as_path_regex {
+        let regex = Regex::new(as_path_regex).map_err(QueryError::InvalidAsPathRegex)?;
+        let new_filter_fn = move |(_, _, route, _): &NetsFilterItem<A>| {
+            let as_path_text = match route.as_path() {
+                Some(as_path) => as_path
+                    .iter()
+                    .map(|asn| asn.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                None => return false,
+            };
+            regex.is_match(&as_path_text)
+        };
+        nets_filter_fn = Box::new(move |i| nets_filter_fn(i) && new_filter_fn(i))
+    };
+
+    if query.route_leak {
+        // whether a route is leaked depends on the session it was received in
+        let (clients, sessions) = snapshot();
+        let new_filter_fn = move |(table, _, route, _): &NetsFilterItem<A>| {
+            let session = match table.session_id() {
+                Some(session_id) => sessions.get(session_id),
+                None => clients
+                    .get(table.client_addr())
+                    .and_then(|client| client.bgp_session.as_ref()),
+            };
+            session.is_some_and(|session| session.is_route_leak(route.otc()))
+        };
+        nets_filter_fn = Box::new(move |i| nets_filter_fn(i) && new_filter_fn(i))
+    };
+
+    if query.min_age.is_some() || query.max_age.is_some() {
+        let min_age = query.min_age.unwrap_or(0);
+        let max_age = query.max_age.unwrap_or(u64::MAX);
+        if min_age > max_age {
+            return Err(QueryError::InvalidAgeRange { min_age, max_age });
+        }
+        let new_filter_fn = move |(_, _, _, times): &NetsFilterItem<A>| {
+            // routes with timestamps in the future count as just received
+            let age = (now - times.first_seen).num_seconds().max(0) as u64;
+            (min_age..=max_age).contains(&age)
+        };
+        nets_filter_fn = Box::new(move |i| nets_filter_fn(i) && new_filter_fn(i))
+    };
+
+    Ok(nets_filter_fn)
+}
+
+/// Maximum number of results and results per table, `0` meaning unlimited
+pub(crate) fn result_limits(limits: Option<QueryLimits>) -> (usize, usize) {
+    let limits = limits.unwrap_or_default();
+    let unlimited_if_zero = |limit| if limit == 0 { usize::MAX } else { limit };
+    (
+        unlimited_if_zero(limits.max_results),
+        unlimited_if_zero(limits.max_results_per_table),
+    )
+}
+
+/// Whether a table is matched by the query, using `router_id_of` to look up the router of a client
+pub(crate) fn table_query_fn(
+    table_query: Option<TableQuery>,
+    router_id_of: impl Fn(&SocketAddr) -> Option<RouterId>,
+) -> impl Fn(&TableSelector) -> bool {
+    move |table: &TableSelector| match &table_query {
+        None => true,
+        Some(TableQuery::Table(query_table)) => table == query_table,
+        Some(TableQuery::Session(session_id)) => table.session_id() == Some(session_id),
+        Some(TableQuery::Client(client_addr)) => table.client_addr() == client_addr,
+        Some(TableQuery::Router(router_id)) => {
+            router_id_of(table.client_addr()).as_ref() == Some(router_id)
+        }
+    }
+}
+
+/// Whether a route for `net` is matched by the query, without knowing the other routes
+pub(crate) fn net_query_matches(net_query: &NetQuery, net: &IpNet) -> bool {
+    match net_query {
+        NetQuery::Exact(query) => net == query,
+        // whether the route is the most specific one depends on the other routes
+        NetQuery::Contains(query) | NetQuery::MostSpecific(query) => net.contains(query),
+        NetQuery::OrLonger(query) => query.contains(net),
+    }
+}
+
+pub(crate) fn router_ids(
+    clients: HashMap<SocketAddr, Client>,
+) -> impl Fn(&SocketAddr) -> Option<RouterId> {
+    move |client_addr| clients.get(client_addr).map(|client| client.router_id)
+}
+
+/// Stream of the route changes sent to `updates` which match the queries
+pub(crate) fn subscription(
+    updates: &broadcast::Sender<HistoryEvent>,
+    net_query: NetQuery,
+    table_filter: impl Fn(&TableSelector) -> bool + Send + 'static,
+) -> Pin<Box<dyn Stream<Item = Result<HistoryEvent, SubscriptionLagged>> + Send>> {
+    Box::pin(
+        futures_util::stream::unfold(Some(updates.subscribe()), |updates| async move {
+            let mut updates = updates?;
+            match updates.recv().await {
+                Ok(event) => Some((Ok(event), Some(updates))),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("dropping subscriber which missed {} route changes", skipped);
+                    Some((Err(SubscriptionLagged), None))
+                }
+                Err(broadcast::error::RecvError::Closed) => None,
+            }
+        })
+        .filter(move |event| {
+            futures_util::future::ready(match event {
+                Ok(event) => {
+                    net_query_matches(&net_query, &event.net) && table_filter(&event.table)
+                }
+                Err(SubscriptionLagged) => true,
+            })
+        }),
+    )
+}
+
 impl InMemoryStore {
+    pub fn with_history(cfg: HistoryConfig) -> Self {
+        Self {
+            history: Some(Arc::new(History::new(cfg))),
+            ..Default::default()
+        }
+    }
+    fn record_event(
+        &self,
+        table: TableSelector,
+        net: IpNet,
+        path_id: PathId,
+        event: Option<RouteEvent>,
+    ) {
+        let Some(event) = event else {
+            return;
+        };
+        if self.updates.receiver_count() > 0 {
+            // fails only if all subscribers are gone in the meantime
+            let _ = self
+                .updates
+                .send(event.to_history_event(table.clone(), net, path_id));
+        }
+        if let Some(history) = &self.history {
+            history.record(table, net, path_id, event);
+        }
+    }
+    /// Clients, sessions and routes of the store, with each distinct set of attributes stored once
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new(Utc::now());
+        let (mut clients, mut sessions, mut tables) = {
+            let stale = self.stale.read().unwrap();
+            let clients: HashMap<_, _> = stale
+                .clients
+                .iter()
+                .map(|(client_addr, (client, _))| (*client_addr, client.clone()))
+                .collect();
+            let tables: Vec<_> = stale.tables.clone().into_iter().collect();
+            (clients, stale.sessions.clone(), tables)
+        };
+        clients.extend(self.clients.read().unwrap().clone());
+        sessions.extend(self.sessions.read().unwrap().clone());
+        // tables which are restored and live at the same time are merged when restoring them,
+        // with the live routes replacing the restored ones
+        tables.extend(self.tables.read().unwrap().clone());
+        snapshot.clients = clients.into_iter().collect();
+        snapshot.sessions = sessions.into_iter().collect();
+
+        let mut attrs_index: HashMap<Arc<CompressedRouteAttrs>, usize> = HashMap::new();
+        for (table_sel, table) in tables {
+            let table = table.snapshot();
+            let routes = table
+                .get_routes(None)
+                .map(|(net, path_id, attrs, times)| {
+                    let index = *attrs_index.entry(attrs).or_insert_with_key(|attrs| {
+                        snapshot.attrs.push(decompress_route_attrs(attrs));
+                        snapshot.attrs.len() - 1
+                    });
+                    (net, path_id, index, times)
+                })
+                .collect();
+            snapshot.tables.push(SnapshotTable {
+                table: (&table_sel).into(),
+                routes,
+            });
+        }
+        snapshot
+    }
+
+    /// Adds the clients of a snapshot, marked as stale until they expire after `stale_timeout`
+    pub fn restore(&self, snapshot: Snapshot, stale_timeout: chrono::Duration) {
+        let mut stale = self.stale.write().unwrap();
+        stale.timeout = stale_timeout;
+        let expires = Utc::now() + stale_timeout;
+        for (client_addr, mut client) in snapshot.clients {
+            client.stale = true;
+            stale.clients.insert(client_addr, (client, expires));
+        }
+        stale.sessions.extend(snapshot.sessions);
+
+        let attrs: Vec<_> = {
+            snapshot
+                .attrs
+                .into_iter()
+                .map(|attrs| self.caches.compress_route_attrs(attrs))
+                .collect()
+        };
+        for SnapshotTable { table, routes } in snapshot.tables {
+            let table = TableSelector::from(table);
+            if !stale.clients.contains_key(table.client_addr()) {
+                continue;
+            }
+            let table = stale
+                .tables
+                .entry(table)
+                .or_insert_with(|| InMemoryTable::new(self.caches.clone()))
+                .clone();
+            for (net, path_id, index, times) in routes {
+                if let Some(attrs) = attrs.get(index) {
+                    table.restore_route(path_id, net, attrs.clone(), times);
+                }
+            }
+        }
+    }
+
+    /// Stops writing changes to the route history journal, e.g. before the collectors
+    /// withdraw their routes on shutdown
+    pub fn close_journal(&self) {
+        if let Some(history) = &self.history {
+            history.close_journal();
+        }
+    }
+
+    /// Measures the memory used by each table, including the restored ones
+    pub fn measure_memory_usage(&self) {
+        let mut tables: Vec<_> = self.tables.read().unwrap().values().cloned().collect();
+        tables.extend(self.stale.read().unwrap().tables.values().cloned());
+        for table in tables {
+            table.measure_memory_usage();
+        }
+    }
+
+    /// Removes restored clients which have expired
+    pub fn remove_stale(&self) {
+        let now = Utc::now();
+        let removed = {
+            let mut stale = self.stale.write().unwrap();
+            let StaleClients {
+                clients,
+                sessions,
+                tables,
+                ..
+            } = &mut *stale;
+            clients.retain(|client_addr, (_, expires)| {
+                let expired = *expires <= now;
+                if expired {
+                    info!("removing stale routes of {}", client_addr);
+                }
+                !expired
+            });
+            sessions.retain(|session_id, _| clients.contains_key(&session_id.from_client));
+            let mut removed = vec![];
+            tables.retain(|table_sel, table| {
+                let keep = clients.contains_key(table_sel.client_addr());
+                if !keep {
+                    removed.push((table_sel.clone(), table.clone()));
+                }
+                keep
+            });
+            removed
+        };
+        self.record_removed_tables(removed);
+        self.caches.remove_expired();
+    }
+
+    /// Restored tables matched by the query
+    fn get_stale_tables(
+        &self,
+        table_query: &Option<TableQuery>,
+    ) -> Vec<(TableSelector, InMemoryTable)> {
+        let stale = self.stale.read().unwrap();
+        let filter = table_query_fn(table_query.clone(), |client_addr| {
+            let (client, _) = stale.clients.get(client_addr)?;
+            Some(client.router_id)
+        });
+        stale
+            .tables
+            .iter()
+            .filter(|(table_sel, _)| filter(table_sel))
+            .map(|(table_sel, table)| (table_sel.clone(), table.clone()))
+            .collect()
+    }
+
+    fn remove_client(&self, client_addr: SocketAddr) {
+        self.clients.write().unwrap().remove(&client_addr);
+        self.prefix_limits.write().unwrap().remove(&client_addr);
+        self.sessions
+            .write()
+            .unwrap()
+            .retain(|k, _| k.from_client != client_addr);
+        let mut removed = vec![];
+        self.tables.write().unwrap().retain(|k, v| {
+            let remove = tables_for_client_fn(&client_addr)(&(k, v));
+            if remove {
+                removed.push((k.clone(), v.clone()));
+            }
+            !remove
+        });
+        self.record_removed_tables(removed);
+        self.caches.remove_expired();
+    }
+
+    /// Answers a query from the route history instead of the current tables
+    fn get_routes_at(
+        &self,
+        query: Query,
+        at: DateTime<Utc>,
+    ) -> Result<Pin<Box<dyn Stream<Item = QueryResult> + Send>>, QueryError> {
+        let Some(history) = &self.history else {
+            return Err(QueryError::HistoryDisabled);
+        };
+        let clients = history.clients.read().unwrap().clone();
+        let sessions = history.sessions.read().unwrap().clone();
+        let table_filter = table_query_fn(query.table_query.clone(), router_ids(clients.clone()));
+        let nets_filter_fn = nets_filter_fn(&query, at, || (clients.clone(), sessions.clone()))?;
+        let (max_results, max_results_per_table) = result_limits(query.limits.clone());
+        let snapshot = history.snapshot();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(2);
+
+        // the routes are reconstructed from the events while the results are sent
+        tokio::task::spawn_blocking(move || {
+            let mut results_per_table: HashMap<TableSelector, usize> = HashMap::new();
+            let _ = snapshot
+                .routes_at(&query.net_query, at, table_filter)
+                .filter(&nets_filter_fn)
+                .filter(|(table, _, _, _)| {
+                    let count = results_per_table.entry(table.clone()).or_default();
+                    *count += 1;
+                    *count <= max_results_per_table
+                })
+                .filter_map(|(table, net, attrs, times)| {
+                    let client = clients.get(table.client_addr())?.clone();
+                    let session = table
+                        .session_id()
+                        .and_then(|session_id| sessions.get(session_id).cloned());
+                    Some(QueryResult {
+                        state: table.route_state(),
+                        net,
+                        table,
+                        attrs: decompress_route_attrs(&attrs),
+                        client,
+                        session,
+                        times,
+                    })
+                })
+                .take(max_results)
+                // stops once the query is dropped
+                .try_for_each(|result| tx.blocking_send(result).map_err(drop));
+        });
+        Ok(Box::pin(ReceiverStream::new(rx)))
+    }
+    /// Records withdrawals of all routes in tables which are removed
+    fn record_removed_tables(&self, tables: Vec<(TableSelector, InMemoryTable)>) {
+        if self.history.is_none() && self.updates.receiver_count() == 0 {
+            return;
+        }
+        let timestamp = Utc::now();
+        for (table_sel, table) in tables {
+            let table = table.snapshot();
+            for (net, path_id, _, _) in table.get_routes(None) {
+                let event = RouteEvent {
+                    timestamp,
+                    kind: RouteEventKind::Withdraw,
+                    attrs: None,
+                };
+                self.record_event(table_sel.clone(), net, path_id, Some(event));
+            }
+        }
+    }
     fn tables_for_router_fn<'a>(
         &self,
         query_router_id: &'a RouterId,
@@ -44,7 +552,7 @@ impl InMemoryStore {
         let clients = self.clients.clone();
         move |(k, _): &(_, _)| {
             &clients
-                .lock()
+                .read()
                 .unwrap()
                 .get(k.client_addr())
                 .unwrap()
@@ -53,11 +561,15 @@ impl InMemoryStore {
         }
     }
     fn get_table(&self, sel: TableSelector) -> InMemoryTable {
+        // the table usually exists already, which only requires the read lock
+        if let Some(table) = self.tables.read().unwrap().get(&sel) {
+            return table.clone();
+        }
         self.tables
-            .lock()
+            .write()
             .unwrap()
             .entry(sel)
-            .or_insert(InMemoryTable::new(self.caches.clone()))
+            .or_insert_with(|| InMemoryTable::new(self.caches.clone()))
             .clone()
     }
     fn get_tables_for_client(
@@ -65,7 +577,7 @@ impl InMemoryStore {
         client_addr: &SocketAddr,
     ) -> Vec<(TableSelector, InMemoryTable)> {
         self.tables
-            .lock()
+            .read()
             .unwrap()
             .iter()
             .filter(tables_for_client_fn(client_addr))
@@ -74,7 +586,7 @@ impl InMemoryStore {
     }
     fn get_tables_for_router(&self, router_id: &RouterId) -> Vec<(TableSelector, InMemoryTable)> {
         self.tables
-            .lock()
+            .read()
             .unwrap()
             .iter()
             .filter(self.tables_for_router_fn(router_id))
@@ -86,7 +598,7 @@ impl InMemoryStore {
         session_id: &SessionId,
     ) -> Vec<(TableSelector, InMemoryTable)> {
         self.tables
-            .lock()
+            .read()
             .unwrap()
             .iter()
             .filter(tables_for_session_fn(session_id))
@@ -104,74 +616,111 @@ impl Store for InMemoryStore {
         net: IpNet,
         table: TableSelector,
         route: RouteAttrs,
+        timestamp: DateTime<Utc>,
     ) {
-        let table = self.get_table(table);
-        table.update_route(path_id, net, route).await;
+        let event = self
+            .get_table(table.clone())
+            .update_route(path_id, net, route, timestamp)
+            .await;
+        self.record_event(table, net, path_id, event);
     }
 
     #[autometrics::autometrics]
-    async fn withdraw_route(&self, path_id: PathId, net: IpNet, table: TableSelector) {
-        let table = self.get_table(table);
-        table.withdraw_route(path_id, net).await;
-    }
-
-    fn get_routes(&self, query: Query) -> Pin<Box<dyn Stream<Item = QueryResult> + Send>> {
-        let tables = match query.table_query {
-            Some(TableQuery::Table(table)) => vec![(table.clone(), self.get_table(table))],
-            Some(TableQuery::Client(client_addr)) => self.get_tables_for_client(&client_addr),
-            Some(TableQuery::Router(router_id)) => self.get_tables_for_router(&router_id),
-            Some(TableQuery::Session(session_id)) => self.get_tables_for_session(&session_id),
-            None => self.tables.lock().unwrap().clone().into_iter().collect(),
-        };
+    async fn withdraw_route(
+        &self,
+        path_id: PathId,
+        net: IpNet,
+        table: TableSelector,
+        timestamp: DateTime<Utc>,
+    ) {
+        let event = self
+            .get_table(table.clone())
+            .withdraw_route(path_id, net, timestamp)
+            .await;
+        self.record_event(table, net, path_id, event);
+    }
 
-        let mut nets_filter_fn: Box<
-            dyn Fn(&(TableSelector, IpNet, Arc<CompressedRouteAttrs>)) -> bool + Send + Sync,
-        > = Box::new(|_| true);
-
-        if let Some(as_path_regex) = query.as_path_regex {
-            let regex = Regex::new(&as_path_regex).unwrap(); // FIXME error handling
-            let new_filter_fn =
-                move |(_, _, route): &(TableSelector, IpNet, Arc<CompressedRouteAttrs>)| {
-                    let as_path_text = match &route.as_path {
-                        Some(as_path) => as_path
-                            .iter()
-                            .map(|asn| asn.to_string())
-                            .collect::<Vec<_>>()
-                            .join(" "),
-                        None => return false,
-                    };
-                    regex.is_match(&as_path_text)
-                };
-            nets_filter_fn = Box::new(move |i| nets_filter_fn(i) && new_filter_fn(i))
+    #[autometrics::autometrics]
+    async fn update_routes(
+        &self,
+        table: TableSelector,
+        attrs: RouteAttrs,
+        announced: Vec<(PathId, IpNet)>,
+        withdrawn: Vec<(PathId, IpNet)>,
+        timestamp: DateTime<Utc>,
+    ) -> PathCountChange {
+        let events = self
+            .get_table(table.clone())
+            .update_routes(attrs, &announced, &withdrawn, timestamp)
+            .await;
+        let mut change = PathCountChange::default();
+        for (path_id, net, event) in events {
+            match event.kind {
+                RouteEventKind::Announce => change.added += 1,
+                RouteEventKind::Withdraw => change.removed += 1,
+                RouteEventKind::Change => {}
+            }
+            self.record_event(table.clone(), net, path_id, Some(event));
+        }
+        change
+    }
+
+    fn get_routes(
+        &self,
+        query: Query,
+    ) -> Result<Pin<Box<dyn Stream<Item = QueryResult> + Send>>, QueryError> {
+        if let Some(at) = query.at {
+            return self.get_routes_at(query, at);
+        }
+
+        let nets_filter_fn = nets_filter_fn(&query, Utc::now(), || {
+            let stale = self.stale.read().unwrap();
+            let mut clients: HashMap<_, _> = stale
+                .clients
+                .iter()
+                .map(|(client_addr, (client, _))| (*client_addr, client.clone()))
+                .collect();
+            let mut sessions = stale.sessions.clone();
+            clients.extend(self.clients.read().unwrap().clone());
+            sessions.extend(self.sessions.read().unwrap().clone());
+            (clients, sessions)
+        })?;
+
+        let tables = match &query.table_query {
+            Some(TableQuery::Table(table)) => vec![(table.clone(), self.get_table(table.clone()))],
+            Some(TableQuery::Client(client_addr)) => self.get_tables_for_client(client_addr),
+            Some(TableQuery::Router(router_id)) => self.get_tables_for_router(router_id),
+            Some(TableQuery::Session(session_id)) => self.get_tables_for_session(session_id),
+            None => self.tables.read().unwrap().clone().into_iter().collect(),
         };
+        // restored tables are queried alongside the tables of connected clients
+        let tables: Vec<_> = tables
+            .into_iter()
+            .map(|(table_sel, table)| (table_sel, table, false))
+            .chain(
+                self.get_stale_tables(&query.table_query)
+                    .into_iter()
+                    .map(|(table_sel, table)| (table_sel, table, true)),
+            )
+            .collect();
 
         let (tx, rx) = tokio::sync::mpsc::channel(2);
 
-        let limits = query.limits.unwrap_or_default();
-        let max_results = if limits.max_results == 0 {
-            usize::MAX
-        } else {
-            limits.max_results
-        };
-        let max_results_per_table = if limits.max_results_per_table == 0 {
-            usize::MAX
-        } else {
-            limits.max_results_per_table
-        };
+        let (max_results, max_results_per_table) = result_limits(query.limits.clone());
 
         rayon::spawn(move || {
             tables
                 .into_par_iter()
-                .flat_map(move |(table_sel, table)| {
-                    let table = table.table.lock().unwrap();
+                .flat_map(move |(table_sel, table, stale)| {
+                    let table = table.snapshot();
                     table
                         .get_routes(Some(&query.net_query))
-                        .map(move |(net, _path_id, route)| {
-                            let table_sel = table_sel.clone();
-                            (table_sel.clone(), net, route.clone())
+                        .map(move |(net, _path_id, route, times)| {
+                            (table_sel.clone(), net, route.clone(), times)
                         })
                         .filter(&nets_filter_fn)
                         .take(max_results_per_table)
+                        .map(|route| (route, stale))
                         .collect::<Vec<_>>()
                         .into_par_iter()
                 })
@@ -180,22 +729,35 @@ impl Store for InMemoryStore {
 
         let clients = self.clients.clone();
         let sessions = self.sessions.clone();
-        Box::pin(
+        let stale_clients = self.stale.clone();
+        Ok(Box::pin(
             ReceiverStream::new(rx)
-                .filter_map(move |(table, net, attrs)| {
+                .filter_map(move |((table, net, attrs, times), stale)| {
                     let clients = clients.clone();
                     let sessions = sessions.clone();
+                    let stale_clients = stale_clients.clone();
                     async move {
-                        let client = match clients.lock().unwrap().get(&table.client_addr()) {
-                            Some(v) => v.clone(),
-                            None => {
-                                warn!("client is not connected");
-                                return None;
-                            }
+                        let (client, session) = if stale {
+                            let stale_clients = stale_clients.read().unwrap();
+                            let client = stale_clients
+                                .clients
+                                .get(table.client_addr())
+                                .map(|(client, _)| client.clone());
+                            let session = table.session_id().and_then(|session_id| {
+                                stale_clients.sessions.get(session_id).cloned()
+                            });
+                            (client, session)
+                        } else {
+                            let client = clients.read().unwrap().get(table.client_addr()).cloned();
+                            let session = table.session_id().and_then(|session_id| {
+                                sessions.read().unwrap().get(session_id).cloned()
+                            });
+                            (client, session)
+                        };
+                        let Some(client) = client else {
+                            warn!("client is not connected");
+                            return None;
                         };
-                        let session = table.session_id().and_then(|session_id| {
-                            sessions.lock().unwrap().get(&session_id).cloned()
-                        });
                         Some(QueryResult {
                             state: table.route_state(),
                             net,
@@ -203,15 +765,124 @@ impl Store for InMemoryStore {
                             attrs: decompress_route_attrs(&attrs),
                             client,
                             session,
+                            times,
                         })
                     }
                 })
                 .take(max_results),
-        )
+        ))
+    }
+
+    fn subscribe(
+        &self,
+        net_query: NetQuery,
+        table_query: Option<TableQuery>,
+    ) -> Pin<Box<dyn Stream<Item = Result<HistoryEvent, SubscriptionLagged>> + Send>> {
+        let clients = self.clients.clone();
+        let table_filter = table_query_fn(table_query, move |client_addr| {
+            let clients = clients.read().unwrap();
+            clients.get(client_addr).map(|client| client.router_id)
+        });
+        subscription(&self.updates, net_query, table_filter)
     }
 
-    fn get_routers(&self) -> HashMap<SocketAddr, Client> {
-        self.clients.lock().unwrap().clone()
+    fn has_history(&self) -> bool {
+        self.history.is_some()
+    }
+
+    fn get_history(
+        &self,
+        net: IpNet,
+        table_query: Option<TableQuery>,
+    ) -> Option<Vec<HistoryEvent>> {
+        let history = self.history.as_ref()?;
+        // clients which are gone are matched as well
+        let clients = history.clients.read().unwrap().clone();
+        let filter = table_query_fn(table_query, router_ids(clients));
+        Some(history.get(&net, filter))
+    }
+
+    fn get_routers(&self) -> HashMap<SocketAddr, RouterInfo> {
+        let prefix_limits = self.prefix_limits.read().unwrap();
+        let mut routers: HashMap<_, _> = self
+            .clients
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(client_addr, client)| {
+                let router = RouterInfo {
+                    client: client.clone(),
+                    prefix_limit: prefix_limits.get(client_addr).cloned(),
+                    table_sizes: vec![],
+                };
+                (*client_addr, router)
+            })
+            .collect();
+        for (table, size) in self.tables.read().unwrap().iter() {
+            if let Some(router) = routers.get_mut(table.client_addr()) {
+                router.table_sizes.push(TableSize {
+                    table: table.clone(),
+                    prefixes: size.route_count(),
+                });
+            }
+        }
+        // restored clients are only shown until the router reconnects
+        let stale = self.stale.read().unwrap();
+        for (client_addr, (client, _)) in &stale.clients {
+            routers.entry(*client_addr).or_insert_with(|| RouterInfo {
+                client: client.clone(),
+                prefix_limit: None,
+                table_sizes: vec![],
+            });
+        }
+        for (table, size) in &stale.tables {
+            match routers.get_mut(table.client_addr()) {
+                Some(router) if router.client.stale => router.table_sizes.push(TableSize {
+                    table: table.clone(),
+                    prefixes: size.route_count(),
+                }),
+                _ => {}
+            }
+        }
+        routers
+    }
+
+    async fn stored_paths(
+        &self,
+        table: TableSelector,
+        paths: Vec<(PathId, IpNet)>,
+    ) -> Vec<(PathId, IpNet)> {
+        let Some(table) = self.tables.read().unwrap().get(&table).cloned() else {
+            return vec![];
+        };
+        paths
+            .into_iter()
+            .filter(|(path_id, net)| table.contains_path(*path_id, net))
+            .collect()
+    }
+
+    fn get_table_sizes(&self, client_addr: &SocketAddr) -> HashMap<TableSelector, usize> {
+        self.get_tables_for_client(client_addr)
+            .into_iter()
+            .map(|(table, inner)| (table, inner.route_count()))
+            .collect()
+    }
+
+    fn get_stats(&self) -> StoreStats {
+        let mut tables: Vec<_> = self.tables.read().unwrap().clone().into_iter().collect();
+        tables.extend(self.stale.read().unwrap().tables.clone());
+        StoreStats {
+            tables: tables
+                .into_iter()
+                .map(|(table, inner)| TableStats {
+                    table,
+                    routes: inner.route_count(),
+                    memory_bytes: Some(inner.memory_usage()),
+                })
+                .collect(),
+            // only reads the sizes of the cache shards
+            caches: self.caches.stats(),
+        }
     }
 
     async fn client_up(
@@ -219,41 +890,197 @@ impl Store for InMemoryStore {
         client_addr: SocketAddr,
         _route_state: RouteState,
         client_data: Client,
+        prefix_limit: Option<PrefixLimit>,
     ) {
+        {
+            let mut stale = self.stale.write().unwrap();
+            // the restored routes are kept until the reconnected router has sent its routes again
+            let expires = Utc::now() + stale.timeout;
+            for (stale_addr, (_, stale_expires)) in stale.clients.iter_mut() {
+                if stale_addr.ip() == client_addr.ip() {
+                    *stale_expires = expires;
+                }
+            }
+        }
         self.clients
-            .lock()
+            .write()
             .unwrap()
-            .insert(client_addr, client_data);
+            .insert(client_addr, client_data.clone());
+        {
+            let mut prefix_limits = self.prefix_limits.write().unwrap();
+            match prefix_limit {
+                Some(prefix_limit) => prefix_limits.insert(client_addr, prefix_limit),
+                None => prefix_limits.remove(&client_addr),
+            };
+        }
+        // after the store, so that the history sweep does not remove it in between
+        if let Some(history) = &self.history {
+            history.update_client(client_addr, client_data);
+        }
     }
     async fn client_down(&self, client_addr: SocketAddr) {
-        self.clients.lock().unwrap().remove(&client_addr);
-        self.sessions
-            .lock()
-            .unwrap()
-            .retain(|k, _| k.from_client != client_addr);
-        self.tables
-            .lock()
-            .unwrap()
-            .retain(|k, v| !(tables_for_client_fn(&client_addr)(&(k, v))));
-        self.caches.lock().unwrap().remove_expired();
+        self.remove_client(client_addr);
+    }
+
+    async fn end_of_rib(&self, table: TableSelector, afi: u16) {
+        let Some(stale_table) = self.stale.read().unwrap().tables.get(&table).cloned() else {
+            return;
+        };
+        // the reconnected router has sent all routes of the address family again
+        let in_family = |net: &IpNet| match net {
+            IpNet::V4(_) => afi == AFI_IPV4,
+            IpNet::V6(_) => afi == AFI_IPV6,
+        };
+        let withdrawn: Vec<_> = stale_table
+            .snapshot()
+            .get_routes(None)
+            .filter(|(net, _, _, _)| in_family(net))
+            .map(|(net, path_id, _, _)| (path_id, net))
+            .collect();
+        let events = stale_table
+            .update_routes(Default::default(), &[], &withdrawn, Utc::now())
+            .await;
+        for (path_id, net, event) in events {
+            self.record_event(table.clone(), net, path_id, Some(event));
+        }
+        if stale_table.route_count() > 0 {
+            return;
+        }
+        let client_addr = *table.client_addr();
+        let mut stale = self.stale.write().unwrap();
+        stale.tables.remove(&table);
+        if !stale
+            .tables
+            .keys()
+            .any(|table| *table.client_addr() == client_addr)
+        {
+            info!("restored routes of {} are replaced", client_addr);
+            stale.clients.remove(&client_addr);
+            stale
+                .sessions
+                .retain(|session_id, _| session_id.from_client != client_addr);
+        }
     }
 
     async fn session_up(&self, session: SessionId, new_state: Session) {
-        self.sessions.lock().unwrap().insert(session, new_state);
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(session.clone(), new_state.clone());
+        if let Some(history) = &self.history {
+            history.update_session(session, new_state);
+        }
     }
     async fn session_down(&self, session: SessionId, new_state: Option<Session>) {
+        if let (Some(history), Some(new_state)) = (&self.history, &new_state) {
+            history.update_session(session.clone(), new_state.clone());
+        }
         if let Some(new_state) = new_state {
             self.sessions
-                .lock()
+                .write()
                 .unwrap()
                 .insert(session.clone(), new_state);
         } else {
-            self.sessions.lock().unwrap().remove(&session);
+            self.sessions.write().unwrap().remove(&session);
         }
-        self.tables
-            .lock()
-            .unwrap()
-            .retain(|k, v| !(tables_for_session_fn(&session)(&(k, v))));
-        self.caches.lock().unwrap().remove_expired();
+        let mut removed = vec![];
+        self.tables.write().unwrap().retain(|k, v| {
+            let remove = tables_for_session_fn(&session)(&(k, v));
+            if remove {
+                removed.push((k.clone(), v.clone()));
+            }
+            !remove
+        });
+        self.record_removed_tables(removed);
+        self.caches.remove_expired();
+    }
+}
+
+/// Periodically removes unused attributes from the caches of the store.
+///
+/// Withdrawn routes leave expired entries behind, which are otherwise only removed
+/// when a session or client goes down.
+pub async fn run_cache_gc(
+    cfg: CacheGcConfig,
+    store: InMemoryStore,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(cfg.interval.get()));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick completes immediately
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => break Ok(()),
+        }
+        let mut reclaimed = 0;
+        for shard in 0..SHARDS {
+            reclaimed += store.caches.remove_expired_in_shard(shard);
+            // collectors may use the caches in between
+            tokio::task::yield_now().await;
+        }
+        debug!("removed {} unused attributes from the caches", reclaimed);
+    }
+}
+
+/// Periodically measures the memory used by the tables of the store, which is reported by
+/// `get_stats`. Walking all tables on each request for the statistics would be too expensive.
+pub async fn run_stats_refresh(
+    cfg: StatsConfig,
+    store: InMemoryStore,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(cfg.interval.get()));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => break Ok(()),
+        }
+        let store = store.clone();
+        tokio::task::spawn_blocking(move || store.measure_memory_usage()).await?;
+    }
+}
+
+/// Periodically removes expired events from the route history of the store,
+/// and clients and sessions which are gone and have no events left.
+///
+/// The shards of the history are swept one after another, so that collectors
+/// recording events are only blocked for a short time.
+pub async fn run_history_sweep(
+    store: InMemoryStore,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let Some(history) = store.history.clone() else {
+        return Ok(());
+    };
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick completes immediately
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => break Ok(()),
+        }
+        let mut removed = 0;
+        let mut in_use = TablesInUse::default();
+        for shard in 0..HISTORY_SHARDS {
+            let history = history.clone();
+            let res = tokio::task::spawn_blocking(move || {
+                let removed = history.remove_expired(shard, &mut in_use);
+                (removed, in_use)
+            })
+            .await?;
+            removed += res.0;
+            in_use = res.1;
+        }
+        history.remove_unused(
+            &in_use,
+            |client_addr| store.clients.read().unwrap().contains_key(client_addr),
+            |session_id| store.sessions.read().unwrap().contains_key(session_id),
+        );
+        debug!("removed {} expired events from the route history", removed);
     }
 }
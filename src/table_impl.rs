@@ -1,88 +1,288 @@
 use crate::compressed_attrs::*;
+use crate::history::{RouteEvent, RouteEventKind};
 use crate::store::*;
+use chrono::{DateTime, Utc};
 use ipnet::IpNet;
 use nibbletree::Node;
+use smallvec::SmallVec;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 
+/// Paths of a prefix. Most prefixes only have a single path, which is stored inline.
+pub type PathList = SmallVec<[(PathId, Arc<CompressedRouteAttrs>, PathTimes); 1]>;
+
+/// `RouteTimes` as nanoseconds since the epoch, which takes less space than `DateTime`
+#[derive(Debug, Clone, Copy)]
+pub struct PathTimes {
+    first_seen: i64,
+    last_modified: i64,
+}
+
+fn to_nanos(time: DateTime<Utc>) -> i64 {
+    // only times before 1677 or after 2262 are out of range
+    time.timestamp_nanos_opt()
+        .unwrap_or(if time.timestamp() < 0 {
+            i64::MIN
+        } else {
+            i64::MAX
+        })
+}
+
+impl From<RouteTimes> for PathTimes {
+    fn from(times: RouteTimes) -> Self {
+        PathTimes {
+            first_seen: to_nanos(times.first_seen),
+            last_modified: to_nanos(times.last_modified),
+        }
+    }
+}
+
+impl From<PathTimes> for RouteTimes {
+    fn from(times: PathTimes) -> Self {
+        RouteTimes {
+            first_seen: DateTime::from_timestamp_nanos(times.first_seen),
+            last_modified: DateTime::from_timestamp_nanos(times.last_modified),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct InMemoryTable {
-    pub table: Arc<Mutex<Node<IpNet, Vec<(PathId, Arc<CompressedRouteAttrs>)>>>>,
-    caches: Arc<Mutex<Caches>>,
+    /// Latest version of the table. Updates modify it in place, unless queries still use it,
+    /// in which case only the changed nodes are copied.
+    table: Arc<Mutex<Arc<Node<IpNet, PathList>>>>,
+    caches: Arc<Caches>,
+    /// Number of paths in the table
+    route_count: Arc<AtomicUsize>,
+    /// Bytes used by the table when it was last measured
+    memory_bytes: Arc<AtomicUsize>,
 }
 
 pub trait NodeExt {
     fn get_routes(
         &self,
         net_query: Option<&NetQuery>,
-    ) -> Box<dyn Iterator<Item = (IpNet, PathId, Arc<CompressedRouteAttrs>)> + Send + '_>;
+    ) -> Box<dyn Iterator<Item = (IpNet, PathId, Arc<CompressedRouteAttrs>, RouteTimes)> + Send + '_>;
 }
 
-impl NodeExt for Node<IpNet, Vec<(PathId, Arc<CompressedRouteAttrs>)>> {
+impl NodeExt for Node<IpNet, PathList> {
     fn get_routes(
         &self,
         net_query: Option<&NetQuery>,
-    ) -> Box<dyn Iterator<Item = (IpNet, PathId, Arc<CompressedRouteAttrs>)> + Send + '_> {
-        let iter: Box<
-            dyn Iterator<Item = (IpNet, &Vec<(PathId, Arc<CompressedRouteAttrs>)>)> + Send + '_,
-        > = match net_query {
+    ) -> Box<dyn Iterator<Item = (IpNet, PathId, Arc<CompressedRouteAttrs>, RouteTimes)> + Send + '_>
+    {
+        let iter: Box<dyn Iterator<Item = (IpNet, &PathList)> + Send + '_> = match net_query {
             None => Box::new(self.iter()),
-            Some(NetQuery::Exact(net)) => Box::new(self.exact(&net).map(|x| (*net, x)).into_iter()),
-            Some(NetQuery::MostSpecific(net)) => Box::new(self.longest_match(&net).into_iter()),
-            Some(NetQuery::Contains(net)) => Box::new(self.matches(&net)),
-            Some(NetQuery::OrLonger(net)) => Box::new(self.or_longer(&net)),
+            Some(NetQuery::Exact(net)) => Box::new(self.exact(net).map(|x| (*net, x)).into_iter()),
+            Some(NetQuery::MostSpecific(net)) => Box::new(self.longest_match(net).into_iter()),
+            Some(NetQuery::Contains(net)) => Box::new(self.matches(net)),
+            Some(NetQuery::OrLonger(net)) => Box::new(self.or_longer(net)),
         };
         Box::new(iter.flat_map(move |(net, routes)| {
             routes
                 .iter()
-                .map(move |(path_id, route)| (net, *path_id, route.clone()))
+                .map(move |(path_id, route, times)| (net, *path_id, route.clone(), (*times).into()))
         }))
     }
 }
 
 impl InMemoryTable {
-    pub fn new(caches: Arc<Mutex<Caches>>) -> Self {
+    pub fn new(caches: Arc<Caches>) -> Self {
         Self {
             table: Default::default(),
             caches,
+            route_count: Default::default(),
+            memory_bytes: Default::default(),
         }
     }
 
-    pub async fn update_route(&self, path_id: PathId, net: IpNet, route: RouteAttrs) {
-        let compressed = self.caches.lock().unwrap().compress_route_attrs(route);
+    /// Immutable view of the table, which is not affected by later updates
+    pub fn snapshot(&self) -> Arc<Node<IpNet, PathList>> {
+        self.table.lock().unwrap().clone()
+    }
+
+    pub fn route_count(&self) -> usize {
+        self.route_count.load(Ordering::Relaxed)
+    }
+
+    /// Approximate bytes used by the table when it was last measured with `measure_memory_usage`
+    pub fn memory_usage(&self) -> usize {
+        self.memory_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Measures the bytes used by the prefix tree and the path lists, which walks the whole
+    /// table. The attributes are shared between tables and accounted for in the caches.
+    pub fn measure_memory_usage(&self) -> usize {
+        let table = self.snapshot();
+        let entry_size = std::mem::size_of::<(PathId, Arc<CompressedRouteAttrs>, PathTimes)>();
+        // a single path is stored inline and already part of the tree
+        let paths: usize = table
+            .values()
+            .filter(|paths| paths.spilled())
+            .map(|paths| paths.capacity() * entry_size)
+            .sum();
+        let memory_bytes = std::mem::size_of::<Node<IpNet, PathList>>() + table.heap_size() + paths;
+        self.memory_bytes.store(memory_bytes, Ordering::Relaxed);
+        memory_bytes
+    }
+
+    pub async fn update_route(
+        &self,
+        path_id: PathId,
+        net: IpNet,
+        route: RouteAttrs,
+        timestamp: DateTime<Utc>,
+    ) -> Option<RouteEvent> {
+        self.update_routes(route, &[(path_id, net)], &[], timestamp)
+            .await
+            .pop()
+            .map(|(_, _, event)| event)
+    }
+
+    /// Announces paths sharing the same attributes and withdraws others, under a single lock.
+    ///
+    /// The attributes are only interned once, so this is much cheaper than separate updates.
+    pub async fn update_routes(
+        &self,
+        route: RouteAttrs,
+        announced: &[(PathId, IpNet)],
+        withdrawn: &[(PathId, IpNet)],
+        timestamp: DateTime<Utc>,
+    ) -> Vec<(PathId, IpNet, RouteEvent)> {
+        let compressed = self.caches.compress_route_attrs(route);
+        let mut events = Vec::new();
 
         let mut table = self.table.lock().unwrap();
+        let table = Arc::make_mut(&mut table);
+        for &(path_id, net) in announced {
+            if let Some(kind) = self.announce(table, path_id, net, &compressed, timestamp) {
+                let event = RouteEvent {
+                    timestamp,
+                    kind,
+                    attrs: Some(compressed.clone()),
+                };
+                events.push((path_id, net, event));
+            }
+        }
+        for &(path_id, net) in withdrawn {
+            if self.withdraw(table, path_id, net) {
+                let event = RouteEvent {
+                    timestamp,
+                    kind: RouteEventKind::Withdraw,
+                    attrs: None,
+                };
+                events.push((path_id, net, event));
+            }
+        }
+        events
+    }
 
+    fn announce(
+        &self,
+        table: &mut Node<IpNet, PathList>,
+        path_id: PathId,
+        net: IpNet,
+        compressed: &Arc<CompressedRouteAttrs>,
+        timestamp: DateTime<Utc>,
+    ) -> Option<RouteEventKind> {
         let mut new_insert = None;
         let entry = table.exact_mut(&net).unwrap_or_else(|| {
-            new_insert = Some(Vec::new());
+            new_insert = Some(PathList::new());
             new_insert.as_mut().unwrap()
         });
 
-        match entry.binary_search_by_key(&path_id, |(k, _)| *k) {
-            Ok(index) => drop(std::mem::replace(&mut entry[index], (path_id, compressed))),
-            Err(index) => entry.insert(index, (path_id, compressed)),
+        let kind = match entry.binary_search_by_key(&path_id, |(k, _, _)| *k) {
+            Ok(index) => {
+                let (_, attrs, times) = &mut entry[index];
+                // equal attributes share the same cache entry
+                if Arc::ptr_eq(attrs, compressed) {
+                    None
+                } else {
+                    *attrs = compressed.clone();
+                    times.last_modified = to_nanos(timestamp);
+                    Some(RouteEventKind::Change)
+                }
+            }
+            Err(index) => {
+                let times = RouteTimes {
+                    first_seen: timestamp,
+                    last_modified: timestamp,
+                };
+                entry.insert(index, (path_id, compressed.clone(), times.into()));
+                self.route_count.fetch_add(1, Ordering::Relaxed);
+                Some(RouteEventKind::Announce)
+            }
         };
 
         if let Some(insert) = new_insert {
             table.insert(&net, insert);
         }
+        kind
     }
 
-    pub async fn withdraw_route(&self, path_id: PathId, net: IpNet) {
-        let mut table = self.table.lock().unwrap();
+    /// Removes the path, returns whether it existed
+    fn withdraw(&self, table: &mut Node<IpNet, PathList>, path_id: PathId, net: IpNet) -> bool {
+        let Some(entry) = table.exact_mut(&net) else {
+            return false;
+        };
+        let Ok(index) = entry.binary_search_by_key(&path_id, |(k, _, _)| *k) else {
+            return false;
+        };
+        entry.remove(index);
+        self.route_count.fetch_sub(1, Ordering::Relaxed);
+        if entry.is_empty() {
+            table.remove(&net);
+        }
+        true
+    }
 
-        let is_empty = match table.exact_mut(&net) {
-            Some(entry) => {
-                if let Ok(index) = entry.binary_search_by_key(&path_id, |(k, _)| *k) {
-                    entry.remove(index);
-                }
-                entry.is_empty()
+    /// Whether the path is stored in the table
+    pub fn contains_path(&self, path_id: PathId, net: &IpNet) -> bool {
+        let table = self.table.lock().unwrap();
+        table
+            .exact(net)
+            .is_some_and(|paths| paths.binary_search_by_key(&path_id, |(k, _, _)| *k).is_ok())
+    }
+
+    /// Inserts a path with its times, e.g. from a snapshot
+    pub fn restore_route(
+        &self,
+        path_id: PathId,
+        net: IpNet,
+        attrs: Arc<CompressedRouteAttrs>,
+        times: RouteTimes,
+    ) {
+        let mut table = self.table.lock().unwrap();
+        let table = Arc::make_mut(&mut table);
+        let entry = match table.exact_mut(&net) {
+            Some(entry) => entry,
+            None => {
+                table.insert(&net, PathList::new());
+                table.exact_mut(&net).unwrap()
             }
-            None => return,
         };
-        if is_empty {
-            table.remove(&net);
+        match entry.binary_search_by_key(&path_id, |(k, _, _)| *k) {
+            Ok(index) => entry[index] = (path_id, attrs, times.into()),
+            Err(index) => {
+                entry.insert(index, (path_id, attrs, times.into()));
+                self.route_count.fetch_add(1, Ordering::Relaxed);
+            }
         }
     }
+
+    pub async fn withdraw_route(
+        &self,
+        path_id: PathId,
+        net: IpNet,
+        timestamp: DateTime<Utc>,
+    ) -> Option<RouteEvent> {
+        let mut table = self.table.lock().unwrap();
+        let table = Arc::make_mut(&mut table);
+        self.withdraw(table, path_id, net).then_some(RouteEvent {
+            timestamp,
+            kind: RouteEventKind::Withdraw,
+            attrs: None,
+        })
+    }
 }
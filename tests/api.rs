@@ -0,0 +1,70 @@
+use fernglas::api::{self, ApiServerConfig};
+use fernglas::store_impl::InMemoryStore;
+use figment::providers::{Format, Yaml};
+use figment::Figment;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Starts the API server on a free local port
+async fn start_api(store: InMemoryStore) -> (SocketAddr, tokio::sync::watch::Sender<bool>) {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let cfg: ApiServerConfig = Figment::from(Yaml::string(&format!("bind: {}\n", addr)))
+        .extract()
+        .unwrap();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(api::run_api_server(cfg, store, shutdown_rx));
+    (addr, shutdown_tx)
+}
+
+/// Status code and body of a GET request
+async fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+    let mut stream = loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => break stream,
+            // the server is still starting
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, addr
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+#[tokio::test]
+async fn invalid_query_parameters_are_json_errors() {
+    let (addr, shutdown) = start_api(InMemoryStore::default()).await;
+
+    for path in [
+        // no prefix or address to query
+        "/api/query",
+        "/api/history",
+        "/api/subscribe",
+        "/api/query?Exact=192.0.2.0/24&table=LocRib",
+    ] {
+        let (status, body) = get(addr, path).await;
+        assert_eq!(status, 400, "{}", path);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"], "invalid_query", "{}", path);
+        assert!(body["message"].as_str().unwrap().contains("query string"));
+    }
+
+    // errors of valid parameters are unchanged
+    let (status, body) = get(addr, "/api/history?net=192.0.2.0/24").await;
+    assert_eq!(status, 404);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error"], "history_disabled");
+
+    shutdown.send(true).unwrap();
+}
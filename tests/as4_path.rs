@@ -0,0 +1,215 @@
+use chrono::Utc;
+use fernglas::import_filter::ImportFilter;
+use fernglas::raw_update::{RawUpdate, AS_SEQUENCE, AS_SET, AS_TRANS};
+use fernglas::store::{NetQuery, Query, RouteAttrs, RouteState, Store, TableQuery, TableSelector};
+use fernglas::store_impl::InMemoryStore;
+use futures_util::StreamExt;
+use std::net::{Ipv4Addr, SocketAddr};
+use zettabgp::prelude::*;
+
+mod common;
+
+fn attr(flags: u8, type_code: u8, value: &[u8]) -> Vec<u8> {
+    let mut buf = vec![flags, type_code, value.len() as u8];
+    buf.extend_from_slice(value);
+    buf
+}
+
+fn as_path(segments: &[(u8, &[u32])], as_len: usize) -> Vec<u8> {
+    let mut buf = vec![];
+    for (segment_type, asns) in segments {
+        buf.extend_from_slice(&[*segment_type, asns.len() as u8]);
+        for asn in *asns {
+            buf.extend_from_slice(&asn.to_be_bytes()[4 - as_len..]);
+        }
+    }
+    buf
+}
+
+fn aggregator(asn: u32, as_len: usize) -> Vec<u8> {
+    let mut buf = asn.to_be_bytes()[4 - as_len..].to_vec();
+    buf.extend_from_slice(&[192, 0, 2, 10]);
+    buf
+}
+
+/// Body of an UPDATE for 203.0.113.0/24 with the given path attributes
+fn update(attrs: &[Vec<u8>]) -> Vec<u8> {
+    let mut attrs = attrs.concat();
+    attrs.extend(attr(0x40, 1, &[0]));
+    attrs.extend(attr(0x40, 3, &[192, 0, 2, 1]));
+    let mut body = vec![0, 0];
+    body.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+    body.extend(attrs);
+    body.extend_from_slice(&[24, 203, 0, 113]);
+    body
+}
+
+/// Converts the update like the collectors do and returns the stored attributes
+async fn convert(body: &[u8], four_octet_as: bool) -> RouteAttrs {
+    let mut params = BgpSessionParams::new(
+        64496,
+        180,
+        BgpTransportMode::IPv4,
+        Ipv4Addr::new(192, 0, 2, 100),
+        if four_octet_as {
+            vec![BgpCapability::CapASN32(64496)]
+        } else {
+            vec![]
+        },
+    );
+    params.check_caps();
+    let mut msg = BgpUpdateMessage::new();
+    msg.decode_from(&params, body).unwrap();
+
+    let store = InMemoryStore::default();
+    let client_addr: SocketAddr = "192.0.2.1:179".parse().unwrap();
+    store
+        .client_up(client_addr, RouteState::Accepted, common::client(), None)
+        .await;
+    store
+        .insert_bgp_update(
+            TableSelector::LocRib {
+                from_client: client_addr,
+                route_state: RouteState::Accepted,
+            },
+            msg,
+            RawUpdate::parse(body, four_octet_as),
+            &ImportFilter::default(),
+            Utc::now(),
+        )
+        .await;
+    let mut routes: Vec<_> = store
+        .get_routes(Query {
+            table_query: Some(TableQuery::Client(client_addr)),
+            net_query: NetQuery::Exact("203.0.113.0/24".parse().unwrap()),
+            limits: None,
+            as_path_regex: None,
+            route_leak: false,
+            min_age: None,
+            max_age: None,
+            at: None,
+        })
+        .unwrap()
+        .collect()
+        .await;
+    assert_eq!(routes.len(), 1);
+    routes.remove(0).attrs
+}
+
+#[tokio::test]
+async fn as4_path_replaces_as_trans() {
+    let body = update(&[
+        attr(
+            0x40,
+            2,
+            &as_path(&[(AS_SEQUENCE, &[64497, AS_TRANS, 64498])], 2),
+        ),
+        attr(
+            0xc0,
+            17,
+            &as_path(&[(AS_SEQUENCE, &[4200000000, 64498])], 4),
+        ),
+    ]);
+    let attrs = convert(&body, false).await;
+    assert_eq!(attrs.as_path, Some(vec![64497, 4200000000, 64498]));
+}
+
+#[tokio::test]
+async fn longer_as4_path_is_ignored() {
+    let body = update(&[
+        attr(0x40, 2, &as_path(&[(AS_SEQUENCE, &[AS_TRANS])], 2)),
+        attr(
+            0xc0,
+            17,
+            &as_path(&[(AS_SEQUENCE, &[4200000000, 64498])], 4),
+        ),
+    ]);
+    let attrs = convert(&body, false).await;
+    assert_eq!(attrs.as_path, Some(vec![AS_TRANS]));
+}
+
+#[tokio::test]
+async fn as_set_counts_as_one() {
+    let body = update(&[
+        attr(
+            0x40,
+            2,
+            &as_path(
+                &[(AS_SEQUENCE, &[64497, AS_TRANS]), (AS_SET, &[64500, 64501])],
+                2,
+            ),
+        ),
+        attr(
+            0xc0,
+            17,
+            &as_path(
+                &[(AS_SEQUENCE, &[4200000000]), (AS_SET, &[64500, 64501])],
+                4,
+            ),
+        ),
+    ]);
+    let attrs = convert(&body, false).await;
+    assert_eq!(attrs.as_path, Some(vec![64497, 4200000000, 64500, 64501]));
+}
+
+#[tokio::test]
+async fn as4_aggregator_replaces_as_trans() {
+    let body = update(&[
+        attr(0x40, 2, &as_path(&[(AS_SEQUENCE, &[64497, AS_TRANS])], 2)),
+        attr(0xc0, 7, &aggregator(AS_TRANS, 2)),
+        attr(0xc0, 17, &as_path(&[(AS_SEQUENCE, &[4200000000])], 4)),
+        attr(0xc0, 18, &aggregator(4200000000, 4)),
+    ]);
+    let attrs = convert(&body, false).await;
+    assert_eq!(attrs.as_path, Some(vec![64497, 4200000000]));
+    assert_eq!(
+        attrs.aggregator,
+        Some((4200000000, Ipv4Addr::new(192, 0, 2, 10)))
+    );
+}
+
+#[tokio::test]
+async fn as4_attributes_are_ignored_with_2_octet_aggregator() {
+    let body = update(&[
+        attr(0x40, 2, &as_path(&[(AS_SEQUENCE, &[64497, AS_TRANS])], 2)),
+        attr(0xc0, 7, &aggregator(64499, 2)),
+        attr(0xc0, 17, &as_path(&[(AS_SEQUENCE, &[4200000000])], 4)),
+        attr(0xc0, 18, &aggregator(4200000000, 4)),
+    ]);
+    let attrs = convert(&body, false).await;
+    assert_eq!(attrs.as_path, Some(vec![64497, AS_TRANS]));
+    assert_eq!(
+        attrs.aggregator,
+        Some((64499, Ipv4Addr::new(192, 0, 2, 10)))
+    );
+}
+
+#[tokio::test]
+async fn as4_path_is_ignored_from_4_octet_speakers() {
+    let body = update(&[
+        attr(0x40, 2, &as_path(&[(AS_SEQUENCE, &[64497, AS_TRANS])], 4)),
+        attr(0xc0, 17, &as_path(&[(AS_SEQUENCE, &[4200000000])], 4)),
+    ]);
+    let attrs = convert(&body, true).await;
+    assert_eq!(attrs.as_path, Some(vec![64497, AS_TRANS]));
+}
+
+#[tokio::test]
+async fn multiple_segments() {
+    let body = update(&[attr(
+        0x40,
+        2,
+        &as_path(
+            &[
+                (AS_SEQUENCE, &[64497, 4200000000]),
+                (AS_SET, &[64500, 64501, 64502]),
+            ],
+            4,
+        ),
+    )]);
+    let attrs = convert(&body, true).await;
+    assert_eq!(
+        attrs.as_path,
+        Some(vec![64497, 4200000000, 64500, 64501, 64502])
+    );
+}
@@ -0,0 +1,820 @@
+use fernglas::bgp_collector::{run_peer, PeerConfig};
+use fernglas::prefix_limit::{encode_metrics, PrefixLimit, PrefixLimitAction};
+use fernglas::store::{
+    BgpRole, NetQuery, Query, QueryResult, RouteState, Session, Store, TableQuery,
+};
+use fernglas::store_impl::InMemoryStore;
+use figment::providers::{Format, Yaml};
+use figment::Figment;
+use futures_util::StreamExt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroU16;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const CAP_MP_IPV4_UNICAST: (u8, &[u8]) = (1, &[0, 1, 0, 1]);
+const CAP_ASN32: (u8, &[u8]) = (65, &[0, 0, 0xfb, 0xf1]);
+const CAP_EXTENDED_MESSAGE: (u8, &[u8]) = (6, &[]);
+const CAP_EXTENDED_NEXTHOP: u8 = 5;
+const CAP_ROLE_CUSTOMER: (u8, &[u8]) = (9, &[3]);
+
+fn message(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0xff; 16];
+    buf.extend_from_slice(&(body.len() as u16 + 19).to_be_bytes());
+    buf.push(msg_type);
+    buf.extend_from_slice(body);
+    buf
+}
+
+fn open(caps: &[(u8, &[u8])]) -> Vec<u8> {
+    open_with_hold_time(180, caps)
+}
+
+fn open_with_hold_time(hold_time: u16, caps: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut params = vec![];
+    for (code, data) in caps {
+        params.extend_from_slice(&[2, data.len() as u8 + 2, *code, data.len() as u8]);
+        params.extend_from_slice(data);
+    }
+    let mut body = vec![4];
+    body.extend_from_slice(&23456u16.to_be_bytes());
+    body.extend_from_slice(&hold_time.to_be_bytes());
+    body.extend_from_slice(&[192, 0, 2, 1]);
+    body.push(params.len() as u8);
+    body.extend(params);
+    message(1, &body)
+}
+
+fn attr(flags: u8, type_code: u8, value: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    if value.len() > 255 {
+        buf.extend_from_slice(&[flags | 0x10, type_code]);
+        buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    } else {
+        buf.extend_from_slice(&[flags, type_code, value.len() as u8]);
+    }
+    buf.extend_from_slice(value);
+    buf
+}
+
+/// An IPv4 unicast update for 203.0.113.0/24 carrying `communities` communities
+fn update(communities: u16) -> Vec<u8> {
+    update_with_otc(communities, None)
+}
+
+fn update_with_otc(communities: u16, otc: Option<u32>) -> Vec<u8> {
+    let mut attrs = attr(0x40, 1, &[0]);
+    attrs.extend(attr(0x40, 2, &[2, 1, 0, 0, 0xfb, 0xf1]));
+    attrs.extend(attr(0x40, 3, &[192, 0, 2, 1]));
+    let communities = (0..communities)
+        .flat_map(|i| [0xfb, 0xf1, (i >> 8) as u8, i as u8])
+        .collect::<Vec<_>>();
+    attrs.extend(attr(0xc0, 8, &communities));
+    if let Some(otc) = otc {
+        attrs.extend(attr(0xc0, 35, &otc.to_be_bytes()));
+    }
+
+    let mut body = vec![0, 0];
+    body.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+    body.extend(attrs);
+    body.extend_from_slice(&[24, 203, 0, 113]);
+    message(2, &body)
+}
+
+/// A unicast update announcing `nlri` in an MP_REACH_NLRI attribute
+fn mp_update(afi: u16, nexthop: &[u8], nlri: &[u8]) -> Vec<u8> {
+    let mut mp_reach = afi.to_be_bytes().to_vec();
+    mp_reach.extend_from_slice(&[1, nexthop.len() as u8]);
+    mp_reach.extend_from_slice(nexthop);
+    mp_reach.push(0);
+    mp_reach.extend_from_slice(nlri);
+
+    let mut attrs = attr(0x40, 1, &[0]);
+    attrs.extend(attr(0x40, 2, &[2, 1, 0, 0, 0xfb, 0xf1]));
+    attrs.extend(attr(0x80, 14, &mp_reach));
+
+    let mut body = vec![0, 0];
+    body.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+    body.extend(attrs);
+    message(2, &body)
+}
+
+/// An update withdrawing the IPv4 prefixes in `nlri`
+fn withdraw(nlri: &[u8]) -> Vec<u8> {
+    let mut body = (nlri.len() as u16).to_be_bytes().to_vec();
+    body.extend_from_slice(nlri);
+    body.extend_from_slice(&[0, 0]);
+    message(2, &body)
+}
+
+fn global_and_link_local() -> Vec<u8> {
+    let global: Ipv6Addr = "2001:db8::1".parse().unwrap();
+    let link_local: Ipv6Addr = "fe80::1".parse().unwrap();
+    [global.octets(), link_local.octets()].concat()
+}
+
+/// Reads one message from the stream, returning its type and body
+async fn read_message(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0; 19];
+    stream.read_exact(&mut head).await.unwrap();
+    let len = u16::from_be_bytes([head[16], head[17]]) as usize;
+    let mut body = vec![0; len - 19];
+    stream.read_exact(&mut body).await.unwrap();
+    (head[18], body)
+}
+
+fn capabilities(open: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut caps = vec![];
+    let mut pos = 10;
+    while pos < 10 + open[9] as usize {
+        let len = open[pos + 1] as usize;
+        if open[pos] == 2 {
+            let cap_len = open[pos + 3] as usize;
+            caps.push((open[pos + 2], open[pos + 4..pos + 4 + cap_len].to_vec()));
+        }
+        pos += 2 + len;
+    }
+    caps
+}
+
+fn capability_codes(open: &[u8]) -> Vec<u8> {
+    capabilities(open)
+        .into_iter()
+        .map(|(code, _)| code)
+        .collect()
+}
+
+fn peer_config() -> PeerConfig {
+    PeerConfig {
+        asn: 64496,
+        router_id: Ipv4Addr::new(192, 0, 2, 100),
+        name_override: None,
+        route_state: RouteState::Accepted,
+        add_path: false,
+        role: None,
+        hold_time: 180,
+        keepalive_interval: None,
+        fqdn: None,
+        prefix_limit: None,
+        import_filter: Default::default(),
+    }
+}
+
+/// Connects to `run_peer` over a local socket and exchanges OPEN messages
+async fn start_session(
+    store: &InMemoryStore,
+    bind: &str,
+    caps: &[(u8, &[u8])],
+) -> (
+    TcpStream,
+    SocketAddr,
+    Vec<u8>,
+    JoinHandle<anyhow::Result<zettabgp::prelude::BgpNotificationMessage>>,
+) {
+    start_session_with_config(store, bind, &open(caps), peer_config()).await
+}
+
+async fn start_session_with_config(
+    store: &InMemoryStore,
+    bind: &str,
+    peer_open: &[u8],
+    cfg: PeerConfig,
+) -> (
+    TcpStream,
+    SocketAddr,
+    Vec<u8>,
+    JoinHandle<anyhow::Result<zettabgp::prelude::BgpNotificationMessage>>,
+) {
+    let listener = TcpListener::bind(bind).await.unwrap();
+    let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, client_addr) = listener.accept().await.unwrap();
+    let store = store.clone();
+    let task = tokio::spawn(async move { run_peer(cfg, store, stream, client_addr).await });
+
+    peer.write_all(peer_open).await.unwrap();
+    let (msg_type, fernglas_open) = read_message(&mut peer).await;
+    assert_eq!(msg_type, 1);
+    (peer, client_addr, fernglas_open, task)
+}
+
+async fn wait_for_routes(
+    store: &InMemoryStore,
+    client_addr: SocketAddr,
+    net: &str,
+) -> Vec<QueryResult> {
+    for _ in 0..200 {
+        let routes: Vec<_> = store
+            .get_routes(Query {
+                table_query: Some(TableQuery::Client(client_addr)),
+                net_query: NetQuery::OrLonger(net.parse().unwrap()),
+                limits: None,
+                as_path_regex: None,
+                route_leak: false,
+                min_age: None,
+                max_age: None,
+                at: None,
+            })
+            .unwrap()
+            .collect()
+            .await;
+        if !routes.is_empty() {
+            return routes;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("no routes received from {}", client_addr);
+}
+
+#[tokio::test]
+async fn extended_message_capability_is_advertised() {
+    let store = InMemoryStore::default();
+    let (_peer, _, fernglas_open, _task) =
+        start_session(&store, "127.0.0.1:0", &[CAP_MP_IPV4_UNICAST, CAP_ASN32]).await;
+    assert!(capability_codes(&fernglas_open).contains(&CAP_EXTENDED_MESSAGE.0));
+    assert!(!capability_codes(&fernglas_open).contains(&CAP_EXTENDED_NEXTHOP));
+}
+
+#[tokio::test]
+async fn extended_update() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) = start_session(
+        &store,
+        "127.0.0.1:0",
+        &[CAP_MP_IPV4_UNICAST, CAP_ASN32, CAP_EXTENDED_MESSAGE],
+    )
+    .await;
+    let msg = update(2000);
+    assert!(msg.len() > 4096);
+    peer.write_all(&msg).await.unwrap();
+
+    let routes = wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+    assert_eq!(routes[0].attrs.communities.as_ref().unwrap().len(), 2000);
+}
+
+#[tokio::test]
+async fn extended_update_without_negotiation() {
+    let store = InMemoryStore::default();
+    let (mut peer, _, _, task) =
+        start_session(&store, "127.0.0.1:0", &[CAP_MP_IPV4_UNICAST, CAP_ASN32]).await;
+    peer.write_all(&update(2000)).await.unwrap();
+
+    assert!(task.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn extended_nexthop_capability_is_advertised_over_ipv6() {
+    let store = InMemoryStore::default();
+    let (_peer, _, fernglas_open, _task) =
+        start_session(&store, "[::1]:0", &[CAP_MP_IPV4_UNICAST, CAP_ASN32]).await;
+    assert!(capability_codes(&fernglas_open).contains(&CAP_EXTENDED_NEXTHOP));
+}
+
+#[tokio::test]
+async fn ipv4_route_with_ipv6_nexthop() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) =
+        start_session(&store, "[::1]:0", &[CAP_MP_IPV4_UNICAST, CAP_ASN32]).await;
+    peer.write_all(&mp_update(1, &global_and_link_local(), &[24, 203, 0, 113]))
+        .await
+        .unwrap();
+
+    let routes = wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+    assert_eq!(routes[0].net, "203.0.113.0/24".parse().unwrap());
+    assert_eq!(
+        routes[0].attrs.nexthop,
+        Some(IpAddr::V6("2001:db8::1".parse().unwrap()))
+    );
+    assert_eq!(
+        routes[0].attrs.nexthop_link_local,
+        Some("fe80::1".parse().unwrap())
+    );
+}
+
+#[tokio::test]
+async fn ipv6_route_with_link_local_nexthop() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) =
+        start_session(&store, "[::1]:0", &[CAP_MP_IPV4_UNICAST, CAP_ASN32]).await;
+    peer.write_all(&mp_update(
+        2,
+        &global_and_link_local(),
+        &[32, 0x20, 0x01, 0x0d, 0xb8],
+    ))
+    .await
+    .unwrap();
+
+    let routes = wait_for_routes(&store, client_addr, "::/0").await;
+    assert_eq!(routes[0].net, "2001:db8::/32".parse().unwrap());
+    assert_eq!(
+        routes[0].attrs.nexthop,
+        Some(IpAddr::V6("2001:db8::1".parse().unwrap()))
+    );
+    assert_eq!(
+        routes[0].attrs.nexthop_link_local,
+        Some("fe80::1".parse().unwrap())
+    );
+}
+
+#[tokio::test]
+async fn ipv4_route_over_ipv6_session() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) =
+        start_session(&store, "[::1]:0", &[CAP_MP_IPV4_UNICAST, CAP_ASN32]).await;
+    peer.write_all(&update(1)).await.unwrap();
+
+    let routes = wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+    assert_eq!(routes[0].net, "203.0.113.0/24".parse().unwrap());
+    assert_eq!(
+        routes[0].attrs.nexthop,
+        Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))
+    );
+}
+
+#[tokio::test]
+async fn role_capability_is_advertised() {
+    let store = InMemoryStore::default();
+    let (_peer, _, fernglas_open, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        PeerConfig {
+            role: Some(BgpRole::Provider),
+            ..peer_config()
+        },
+    )
+    .await;
+    assert!(capabilities(&fernglas_open).contains(&(9, vec![0])));
+}
+
+#[tokio::test]
+async fn role_capability_is_not_advertised_by_default() {
+    let store = InMemoryStore::default();
+    let (_peer, _, fernglas_open, _task) =
+        start_session(&store, "127.0.0.1:0", &[CAP_MP_IPV4_UNICAST, CAP_ASN32]).await;
+    assert!(!capability_codes(&fernglas_open).contains(&9));
+}
+
+#[tokio::test]
+async fn roles_in_session_metadata() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32, CAP_ROLE_CUSTOMER]),
+        PeerConfig {
+            role: Some(BgpRole::Provider),
+            ..peer_config()
+        },
+    )
+    .await;
+    peer.write_all(&update(1)).await.unwrap();
+
+    let routes = wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+    let session = routes[0].client.bgp_session.as_ref().unwrap();
+    assert_eq!(session.local_role, Some(BgpRole::Provider));
+    assert_eq!(session.peer_role, Some(BgpRole::Customer));
+    assert_eq!(session.peer_asn, Some(64497));
+}
+
+async fn route_leaks(store: &InMemoryStore, client_addr: SocketAddr) -> Vec<QueryResult> {
+    store
+        .get_routes(Query {
+            table_query: Some(TableQuery::Client(client_addr)),
+            net_query: NetQuery::OrLonger("0.0.0.0/0".parse().unwrap()),
+            limits: None,
+            as_path_regex: None,
+            route_leak: true,
+            min_age: None,
+            max_age: None,
+            at: None,
+        })
+        .unwrap()
+        .collect()
+        .await
+}
+
+#[tokio::test]
+async fn otc_from_customer_is_a_leak() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32, CAP_ROLE_CUSTOMER]),
+        PeerConfig {
+            role: Some(BgpRole::Provider),
+            ..peer_config()
+        },
+    )
+    .await;
+    peer.write_all(&update_with_otc(1, Some(64500)))
+        .await
+        .unwrap();
+
+    let routes = wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+    assert_eq!(routes[0].attrs.otc, Some(64500));
+    assert_eq!(route_leaks(&store, client_addr).await.len(), 1);
+}
+
+#[tokio::test]
+async fn otc_from_provider_is_not_a_leak() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32, (9, &[0])]),
+        PeerConfig {
+            role: Some(BgpRole::Customer),
+            ..peer_config()
+        },
+    )
+    .await;
+    peer.write_all(&update_with_otc(1, Some(64500)))
+        .await
+        .unwrap();
+
+    wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+    assert!(route_leaks(&store, client_addr).await.is_empty());
+}
+
+#[tokio::test]
+async fn otc_from_peer() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32, (9, &[4])]),
+        PeerConfig {
+            role: Some(BgpRole::Peer),
+            ..peer_config()
+        },
+    )
+    .await;
+    // set by the peer itself when it received the route from its provider or peer
+    peer.write_all(&update_with_otc(1, Some(64497)))
+        .await
+        .unwrap();
+    wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+    assert!(route_leaks(&store, client_addr).await.is_empty());
+
+    // set by another AS, so the peer has propagated it to us
+    peer.write_all(&update_with_otc(1, Some(64500)))
+        .await
+        .unwrap();
+    for _ in 0..200 {
+        if !route_leaks(&store, client_addr).await.is_empty() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("route leak was not found");
+}
+
+/// Waits until the client is up and returns the session with the collector
+async fn wait_for_session(store: &InMemoryStore, client_addr: SocketAddr) -> Session {
+    for _ in 0..200 {
+        if let Some(router) = store.get_routers().remove(&client_addr) {
+            return router.client.bgp_session.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{} did not come up", client_addr);
+}
+
+#[tokio::test]
+async fn hold_time_is_advertised() {
+    let store = InMemoryStore::default();
+    let (_peer, _, fernglas_open, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        PeerConfig {
+            hold_time: 90,
+            ..peer_config()
+        },
+    )
+    .await;
+    assert_eq!(u16::from_be_bytes([fernglas_open[3], fernglas_open[4]]), 90);
+}
+
+#[tokio::test]
+async fn lower_hold_time_is_negotiated() {
+    let store = InMemoryStore::default();
+    let (_peer, client_addr, _, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open_with_hold_time(30, &[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        PeerConfig {
+            hold_time: 90,
+            ..peer_config()
+        },
+    )
+    .await;
+    let session = wait_for_session(&store, client_addr).await;
+    assert_eq!(session.hold_time, Some(30));
+    assert_eq!(session.keepalive_interval, Some(10));
+}
+
+#[tokio::test]
+async fn keepalive_interval_is_configurable() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        PeerConfig {
+            keepalive_interval: NonZeroU16::new(1),
+            ..peer_config()
+        },
+    )
+    .await;
+    let session = wait_for_session(&store, client_addr).await;
+    assert_eq!(session.hold_time, Some(180));
+    assert_eq!(session.keepalive_interval, Some(1));
+
+    for _ in 0..2 {
+        let (msg_type, _) = tokio::time::timeout(Duration::from_secs(3), read_message(&mut peer))
+            .await
+            .unwrap();
+        assert_eq!(msg_type, 4);
+    }
+}
+
+#[test]
+fn zero_keepalive_interval_is_rejected() {
+    let peer_config = |keepalive_interval: u16| {
+        Figment::from(Yaml::string(&format!(
+            "asn: 64496\nrouter_id: 192.0.2.100\nroute_state: Accepted\nadd_path: false\nkeepalive_interval: {}\n",
+            keepalive_interval
+        )))
+        .extract::<PeerConfig>()
+        .ok()
+    };
+    assert!(peer_config(0).is_none());
+    assert_eq!(
+        peer_config(10).unwrap().keepalive_interval,
+        NonZeroU16::new(10)
+    );
+}
+
+#[tokio::test]
+async fn unacceptable_hold_time() {
+    let store = InMemoryStore::default();
+    let (_peer, _, _, task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open_with_hold_time(2, &[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        peer_config(),
+    )
+    .await;
+    assert!(task.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn hold_timer_expires() {
+    let store = InMemoryStore::default();
+    let (mut peer, _, _, task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        PeerConfig {
+            hold_time: 3,
+            ..peer_config()
+        },
+    )
+    .await;
+    let res = tokio::time::timeout(Duration::from_secs(10), task)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(res.unwrap_err().to_string().contains("Hold timer expired"));
+
+    // the session is closed with a Hold Timer Expired notification, after the keepalives
+    loop {
+        match read_message(&mut peer).await {
+            (4, _) => continue,
+            (msg_type, body) => {
+                assert_eq!((msg_type, body), (3, vec![4, 0]));
+                break;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn fqdn_is_advertised() {
+    let store = InMemoryStore::default();
+    let (_peer, client_addr, fernglas_open, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        PeerConfig {
+            fqdn: Some("lg.example.org".to_string()),
+            ..peer_config()
+        },
+    )
+    .await;
+    let mut expected = vec![2];
+    expected.extend_from_slice(b"lg");
+    expected.push(11);
+    expected.extend_from_slice(b"example.org");
+    assert!(capabilities(&fernglas_open).contains(&(73, expected)));
+
+    let session = wait_for_session(&store, client_addr).await;
+    assert_eq!(session.local_fqdn.as_deref(), Some("lg.example.org"));
+}
+
+fn prefix_limit_config(limit: PrefixLimit) -> PeerConfig {
+    PeerConfig {
+        prefix_limit: Some(limit),
+        ..peer_config()
+    }
+}
+
+#[tokio::test]
+async fn prefix_limit_stop_accepting() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        prefix_limit_config(PrefixLimit {
+            max_prefixes: Some(1),
+            max_prefixes_per_table: None,
+            action: PrefixLimitAction::StopAccepting,
+        }),
+    )
+    .await;
+    peer.write_all(&update(1)).await.unwrap();
+    peer.write_all(&mp_update(1, &[192, 0, 2, 1], &[24, 198, 51, 100]))
+        .await
+        .unwrap();
+    // withdrawals are still accepted, making room for the next announcement
+    peer.write_all(&withdraw(&[24, 203, 0, 113])).await.unwrap();
+    peer.write_all(&mp_update(1, &[192, 0, 2, 1], &[24, 192, 0, 2]))
+        .await
+        .unwrap();
+
+    let routes = wait_for_routes(&store, client_addr, "192.0.2.0/24").await;
+    assert_eq!(routes.len(), 1);
+    let routes = wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+    assert_eq!(routes.len(), 1);
+}
+
+#[tokio::test]
+async fn prefix_limit_accepts_changes_of_stored_routes() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        prefix_limit_config(PrefixLimit {
+            max_prefixes: Some(1),
+            max_prefixes_per_table: None,
+            action: PrefixLimitAction::StopAccepting,
+        }),
+    )
+    .await;
+    peer.write_all(&update(1)).await.unwrap();
+    // rejected, the table is at the limit
+    peer.write_all(&mp_update(1, &[192, 0, 2, 1], &[24, 198, 51, 100]))
+        .await
+        .unwrap();
+    peer.write_all(&update(3)).await.unwrap();
+
+    for _ in 0..200 {
+        let routes = wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+        assert_eq!(routes.len(), 1);
+        if routes[0].attrs.communities.as_ref().map(Vec::len) == Some(3) {
+            assert_eq!(routes[0].net.to_string(), "203.0.113.0/24");
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("attribute change was not applied");
+}
+
+#[tokio::test]
+async fn prefix_limit_within_one_update() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        prefix_limit_config(PrefixLimit {
+            max_prefixes: Some(2),
+            max_prefixes_per_table: None,
+            action: PrefixLimitAction::StopAccepting,
+        }),
+    )
+    .await;
+    peer.write_all(&mp_update(
+        1,
+        &[192, 0, 2, 1],
+        &[24, 198, 51, 100, 24, 203, 0, 113, 24, 192, 0, 2],
+    ))
+    .await
+    .unwrap();
+    // withdrawals are processed in order, so once this one is applied the update above is complete
+    peer.write_all(&withdraw(&[24, 198, 51, 100]))
+        .await
+        .unwrap();
+
+    for _ in 0..200 {
+        let routes = wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+        if routes
+            .iter()
+            .all(|r| r.net.to_string() != "198.51.100.0/24")
+        {
+            assert_eq!(routes.len(), 1);
+            assert_eq!(routes[0].net.to_string(), "203.0.113.0/24");
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("withdrawal was not applied");
+}
+
+#[tokio::test]
+async fn prefix_limit_teardown() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        prefix_limit_config(PrefixLimit {
+            max_prefixes: None,
+            max_prefixes_per_table: Some(1),
+            action: PrefixLimitAction::Teardown,
+        }),
+    )
+    .await;
+    peer.write_all(&mp_update(1, &[192, 0, 2, 1], &[24, 198, 51, 100]))
+        .await
+        .unwrap();
+    wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+    assert!(!task.is_finished());
+    // the limit is checked per NLRI, so the second prefix of this update tears the session down
+    peer.write_all(&mp_update(
+        1,
+        &[192, 0, 2, 1],
+        &[24, 203, 0, 113, 24, 192, 0, 2],
+    ))
+    .await
+    .unwrap();
+
+    assert!(task.await.unwrap().is_err());
+    loop {
+        let (msg_type, body) = read_message(&mut peer).await;
+        if msg_type == 3 {
+            assert_eq!(body, [6, 1]);
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn prefix_counts_and_limits_in_metrics() {
+    let store = InMemoryStore::default();
+    let (mut peer, client_addr, _, _task) = start_session_with_config(
+        &store,
+        "127.0.0.1:0",
+        &open(&[CAP_MP_IPV4_UNICAST, CAP_ASN32]),
+        prefix_limit_config(PrefixLimit {
+            max_prefixes: Some(1000),
+            max_prefixes_per_table: None,
+            action: PrefixLimitAction::Warn,
+        }),
+    )
+    .await;
+    peer.write_all(&mp_update(
+        1,
+        &[192, 0, 2, 1],
+        &[24, 198, 51, 100, 24, 203, 0, 113],
+    ))
+    .await
+    .unwrap();
+    wait_for_routes(&store, client_addr, "0.0.0.0/0").await;
+
+    let routers = store.get_routers();
+    let router = &routers[&client_addr];
+    assert_eq!(router.table_sizes[0].prefixes, 2);
+    assert_eq!(
+        router.prefix_limit.as_ref().unwrap().max_prefixes,
+        Some(1000)
+    );
+
+    let metrics = encode_metrics(&routers).unwrap();
+    let line = |name: &str| {
+        metrics
+            .lines()
+            .find(|line| line.starts_with(name) && line.contains("table=\"all\""))
+            .unwrap()
+            .to_string()
+    };
+    assert!(line("fernglas_prefixes{").ends_with(" 2"));
+    assert!(line("fernglas_prefix_limit{").ends_with(" 1000"));
+}
@@ -0,0 +1,724 @@
+use chrono::DateTime;
+use fernglas::bmp_collector::{
+    self, run_client, AccessControl, BmpCollectorConfig, ClientError, PeerConfig,
+};
+use fernglas::bmp_relay::BmpRelay;
+use fernglas::prefix_limit::{PrefixLimit, PrefixLimitAction};
+use fernglas::store::{BgpRole, NetQuery, Query, QueryResult, Store, TableQuery};
+use fernglas::store_impl::InMemoryStore;
+use figment::providers::{Format, Yaml};
+use figment::Figment;
+use futures_util::StreamExt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use zettabgp::prelude::*;
+
+const CLIENT_ADDR: &str = "192.0.2.1:50000";
+
+fn client_addr() -> SocketAddr {
+    CLIENT_ADDR.parse().unwrap()
+}
+
+fn bmp_message(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = vec![3];
+    buf.extend_from_slice(&(body.len() as u32 + 6).to_be_bytes());
+    buf.push(msg_type);
+    buf.extend_from_slice(body);
+    buf
+}
+
+fn tlv(info_type: u16, value: &[u8]) -> Vec<u8> {
+    let mut buf = info_type.to_be_bytes().to_vec();
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+    buf
+}
+
+fn per_peer_header(peer: Ipv4Addr, post_policy: bool) -> Vec<u8> {
+    let mut buf = vec![0, if post_policy { 0x40 } else { 0 }];
+    buf.extend_from_slice(&[0; 8]);
+    buf.extend_from_slice(&[0; 12]);
+    buf.extend_from_slice(&peer.octets());
+    buf.extend_from_slice(&64496u32.to_be_bytes());
+    buf.extend_from_slice(&peer.octets());
+    buf.extend_from_slice(&[0; 8]);
+    buf
+}
+
+fn session_params() -> BgpSessionParams {
+    BgpSessionParams::new(
+        64496,
+        180,
+        BgpTransportMode::IPv4,
+        Ipv4Addr::new(192, 0, 2, 1),
+        vec![BgpCapability::SafiIPv4u, BgpCapability::CapASN32(64496)],
+    )
+}
+
+fn bgp_message(msg_type: BgpMessageType, msg: &impl BgpMessage) -> Vec<u8> {
+    let params = session_params();
+    let mut buf = vec![0; 4096];
+    let len = msg.encode_to(&params, &mut buf[19..]).unwrap();
+    let len = params.prepare_message_buf(&mut buf, msg_type, len).unwrap();
+    buf.truncate(len);
+    buf
+}
+
+fn initiation(sys_name: &str) -> Vec<u8> {
+    let mut body = tlv(2, sys_name.as_bytes());
+    body.extend(tlv(1, b"Test Router OS 1.0"));
+    body.extend(tlv(0, b"first string"));
+    body.extend(tlv(0, b"second string"));
+    bmp_message(4, &body)
+}
+
+fn peer_up(peer: Ipv4Addr) -> Vec<u8> {
+    let open = bgp_message(BgpMessageType::Open, &session_params().open_message());
+    peer_up_with_opens(peer, &open, &open)
+}
+
+fn peer_up_with_opens(peer: Ipv4Addr, sent: &[u8], received: &[u8]) -> Vec<u8> {
+    let mut body = per_peer_header(peer, false);
+    body.extend_from_slice(&[0; 12]);
+    body.extend_from_slice(&[192, 0, 2, 1]);
+    body.extend_from_slice(&179u16.to_be_bytes());
+    body.extend_from_slice(&50000u16.to_be_bytes());
+    body.extend_from_slice(sent);
+    body.extend_from_slice(received);
+    bmp_message(3, &body)
+}
+
+/// OPEN message with a BGP Role capability, which zettabgp can not encode
+fn open_with_role(role: u8) -> Vec<u8> {
+    let mut buf = bgp_message(BgpMessageType::Open, &session_params().open_message());
+    buf.extend_from_slice(&[2, 3, 9, 1, role]);
+    buf[19 + 9] += 5;
+    let len = buf.len() as u16;
+    buf[16..18].copy_from_slice(&len.to_be_bytes());
+    buf
+}
+
+fn route_monitoring(peer: Ipv4Addr, net: (Ipv4Addr, u8)) -> Vec<u8> {
+    let mut update = BgpUpdateMessage::new();
+    update.attrs = vec![
+        BgpAttrItem::Origin(BgpOrigin::new(BgpAttrOrigin::Igp)),
+        BgpAttrItem::ASPath(BgpASpath::from(vec![64497])),
+        BgpAttrItem::NextHop(BgpNextHop::new(IpAddr::V4(peer))),
+    ];
+    update.updates = BgpAddrs::IPV4U(vec![BgpAddrV4::new(net.0, net.1)]);
+    let mut body = per_peer_header(peer, false);
+    body.extend(bgp_message(BgpMessageType::Update, &update));
+    bmp_message(0, &body)
+}
+
+/// Sets the timestamp in the per-peer header of a BMP message
+fn with_timestamp(mut msg: Vec<u8>, secs: u32, micros: u32) -> Vec<u8> {
+    msg[6 + 34..6 + 38].copy_from_slice(&secs.to_be_bytes());
+    msg[6 + 38..6 + 42].copy_from_slice(&micros.to_be_bytes());
+    msg
+}
+
+/// Route monitoring with an update larger than 4096 bytes, as sent by peers using extended messages
+fn extended_route_monitoring(peer: Ipv4Addr, communities: u16) -> Vec<u8> {
+    let attr = |flags: u8, type_code: u8, value: &[u8]| {
+        let mut buf = vec![flags | 0x10, type_code];
+        buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        buf.extend_from_slice(value);
+        buf
+    };
+    let mut attrs = attr(0x40, 1, &[0]);
+    attrs.extend(attr(0x40, 2, &[2, 1, 0, 0, 0xfb, 0xf1]));
+    attrs.extend(attr(0x40, 3, &peer.octets()));
+    let communities = (0..communities)
+        .flat_map(|i| [0xfb, 0xf1, (i >> 8) as u8, i as u8])
+        .collect::<Vec<_>>();
+    attrs.extend(attr(0xc0, 8, &communities));
+
+    let mut update = vec![0, 0];
+    update.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+    update.extend(attrs);
+    update.extend_from_slice(&[24, 203, 0, 113]);
+
+    let mut body = per_peer_header(peer, false);
+    body.extend_from_slice(&[0xff; 16]);
+    body.extend_from_slice(&(update.len() as u16 + 19).to_be_bytes());
+    body.push(2);
+    body.extend(update);
+    bmp_message(0, &body)
+}
+
+fn peer_down(peer: Ipv4Addr) -> Vec<u8> {
+    let mut body = per_peer_header(peer, false);
+    body.push(4);
+    bmp_message(2, &body)
+}
+
+fn termination_msg() -> Vec<u8> {
+    bmp_message(5, &tlv(1, &0u16.to_be_bytes()))
+}
+
+fn peer(n: u8) -> Ipv4Addr {
+    Ipv4Addr::new(198, 51, 100, n)
+}
+
+async fn query(store: &InMemoryStore, client_addr: SocketAddr, peer: Ipv4Addr) -> Vec<QueryResult> {
+    store
+        .get_routes(Query {
+            table_query: Some(TableQuery::Session(fernglas::store::SessionId {
+                from_client: client_addr,
+                peer_address: IpAddr::V4(peer),
+            })),
+            net_query: NetQuery::OrLonger("0.0.0.0/0".parse().unwrap()),
+            limits: None,
+            as_path_regex: None,
+            route_leak: false,
+            min_age: None,
+            max_age: None,
+            at: None,
+        })
+        .unwrap()
+        .collect()
+        .await
+}
+
+/// Waits until the peer tasks have processed the routes of `peer`
+async fn wait_for_routes(store: &InMemoryStore, peer: Ipv4Addr, count: usize) -> Vec<QueryResult> {
+    wait_for_client_routes(store, client_addr(), peer, count).await
+}
+
+async fn wait_for_client_routes(
+    store: &InMemoryStore,
+    client_addr: SocketAddr,
+    peer: Ipv4Addr,
+    count: usize,
+) -> Vec<QueryResult> {
+    for _ in 0..200 {
+        let routes = query(store, client_addr, peer).await;
+        if routes.len() == count {
+            return routes;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("routes for {} did not converge", peer);
+}
+
+/// Runs the client on one end of an in-memory pipe and writes `messages` to the other
+fn start_client(
+    store: &InMemoryStore,
+    messages: Vec<Vec<u8>>,
+) -> (
+    tokio::io::DuplexStream,
+    tokio::task::JoinHandle<anyhow::Result<zettabgp::bmp::prelude::BmpMessageTermination>>,
+) {
+    start_client_with_config(store, PeerConfig::default(), messages)
+}
+
+fn start_client_with_config(
+    store: &InMemoryStore,
+    cfg: PeerConfig,
+    messages: Vec<Vec<u8>>,
+) -> (
+    tokio::io::DuplexStream,
+    tokio::task::JoinHandle<anyhow::Result<zettabgp::bmp::prelude::BmpMessageTermination>>,
+) {
+    let (mut tx, rx) = tokio::io::duplex(1 << 20);
+    let store = store.clone();
+    let task = tokio::spawn(async move {
+        let relay = BmpRelay::new(&[], client_addr());
+        run_client(cfg, rx, client_addr(), &store, &relay, None).await
+    });
+    let data = messages.concat();
+    let write = async move {
+        tx.write_all(&data).await.unwrap();
+        tx
+    };
+    let tx = futures_util::FutureExt::now_or_never(write)
+        .expect("test input does not fit into the pipe buffer");
+    (tx, task)
+}
+
+#[tokio::test]
+async fn client_metadata_from_initiation() {
+    let store = InMemoryStore::default();
+    let (_tx, _task) = start_client(&store, vec![initiation("router01"), peer_up(peer(1))]);
+
+    for _ in 0..200 {
+        if !store.get_routers().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let client = store.get_routers().remove(&client_addr()).unwrap().client;
+    assert_eq!(client.client_name, "router01");
+    assert_eq!(client.sys_descr.as_deref(), Some("Test Router OS 1.0"));
+    assert_eq!(client.info_strings, vec!["first string", "second string"]);
+}
+
+#[tokio::test]
+async fn missing_initiation() {
+    let store = InMemoryStore::default();
+    let (tx, task) = start_client(&store, vec![peer_up(peer(1))]);
+    drop(tx);
+
+    let err = task.await.unwrap().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ClientError>(),
+        Some(ClientError::MissingInitiation(_))
+    ));
+}
+
+#[tokio::test]
+async fn missing_termination() {
+    let store = InMemoryStore::default();
+    let (tx, task) = start_client(&store, vec![initiation("router01"), peer_up(peer(1))]);
+    drop(tx);
+
+    let err = task.await.unwrap().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ClientError>(),
+        Some(ClientError::UnexpectedEndOfStream)
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn queued_route_monitoring_is_processed_before_client_down() {
+    let store = InMemoryStore::default();
+    // the Loc-RIB table has no session, which would remove it when the peer task ends
+    let loc_rib = |mut msg: Vec<u8>| {
+        msg[6] = 3;
+        msg
+    };
+    let mut messages = vec![
+        initiation("router01"),
+        peer_up(peer(1)),
+        loc_rib(peer_up(peer(2))),
+    ];
+    for i in 0..100 {
+        let net = (Ipv4Addr::new(10, 0, i, 0), 24);
+        messages.push(route_monitoring(peer(1), net));
+        messages.push(loc_rib(route_monitoring(peer(2), net)));
+    }
+    messages.push(termination_msg());
+    let (_tx, task) = start_client(&store, messages);
+
+    task.await.unwrap().unwrap();
+    // the peer tasks have ended, their session tables are removed already
+    let sizes = store.get_table_sizes(&client_addr());
+    assert_eq!(sizes.len(), 1);
+    assert!(sizes.values().all(|size| *size == 100));
+    // as done by the collector once the client has ended
+    store.client_down(client_addr()).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(store.get_table_sizes(&client_addr()).is_empty());
+    assert!(store.get_routers().is_empty());
+}
+
+#[tokio::test]
+async fn clean_termination() {
+    let store = InMemoryStore::default();
+    let (_tx, task) = start_client(
+        &store,
+        vec![initiation("router01"), peer_up(peer(1)), termination_msg()],
+    );
+
+    let msg = task.await.unwrap().unwrap();
+    assert_eq!(msg.reason, Some(0));
+}
+
+#[tokio::test]
+async fn route_monitoring_without_peer_up() {
+    let store = InMemoryStore::default();
+    let (_tx, task) = start_client(
+        &store,
+        vec![
+            initiation("router01"),
+            peer_up(peer(1)),
+            route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 0), 24)),
+            route_monitoring(peer(2), (Ipv4Addr::new(203, 0, 113, 0), 24)),
+        ],
+    );
+
+    let routes = wait_for_routes(&store, peer(2), 1).await;
+    assert!(routes[0]
+        .session
+        .as_ref()
+        .unwrap()
+        .incomplete_reason
+        .is_some());
+
+    let routes = wait_for_routes(&store, peer(1), 1).await;
+    assert!(routes[0]
+        .session
+        .as_ref()
+        .unwrap()
+        .incomplete_reason
+        .is_none());
+    assert!(!task.is_finished());
+}
+
+#[tokio::test]
+async fn peer_down_for_unknown_peer() {
+    let store = InMemoryStore::default();
+    let (_tx, task) = start_client(
+        &store,
+        vec![
+            initiation("router01"),
+            peer_up(peer(1)),
+            peer_down(peer(2)),
+            route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 0), 24)),
+        ],
+    );
+
+    wait_for_routes(&store, peer(1), 1).await;
+    assert!(!task.is_finished());
+}
+
+#[tokio::test]
+async fn peer_down_removes_routes() {
+    let store = InMemoryStore::default();
+    let (_tx, _task) = start_client(
+        &store,
+        vec![
+            initiation("router01"),
+            peer_up(peer(1)),
+            peer_up(peer(2)),
+            route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 0), 24)),
+            route_monitoring(peer(2), (Ipv4Addr::new(203, 0, 113, 0), 24)),
+            peer_down(peer(2)),
+            route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 128), 25)),
+        ],
+    );
+
+    wait_for_routes(&store, peer(1), 2).await;
+    wait_for_routes(&store, peer(2), 0).await;
+}
+
+#[tokio::test]
+async fn duplicate_peer_up_resets_session() {
+    let store = InMemoryStore::default();
+    let (_tx, _task) = start_client(
+        &store,
+        vec![
+            initiation("router01"),
+            peer_up(peer(1)),
+            route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 0), 24)),
+            peer_up(peer(1)),
+            route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 128), 25)),
+        ],
+    );
+
+    let routes = wait_for_routes(&store, peer(1), 1).await;
+    assert_eq!(routes[0].net, "203.0.113.128/25".parse().unwrap());
+}
+
+#[tokio::test]
+async fn undecodable_messages_are_skipped() {
+    let store = InMemoryStore::default();
+    let (_tx, task) = start_client(
+        &store,
+        vec![
+            initiation("router01"),
+            peer_up(peer(1)),
+            bmp_message(42, &[0; 16]),
+            bmp_message(0, &[0; 8]),
+            route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 0), 24)),
+        ],
+    );
+
+    wait_for_routes(&store, peer(1), 1).await;
+    assert!(!task.is_finished());
+}
+
+#[tokio::test]
+async fn extended_update() {
+    let store = InMemoryStore::default();
+    let msg = extended_route_monitoring(peer(1), 2000);
+    assert!(msg.len() > 4096);
+    let (_tx, _task) = start_client(&store, vec![initiation("router01"), peer_up(peer(1)), msg]);
+
+    let routes = wait_for_routes(&store, peer(1), 1).await;
+    assert_eq!(routes[0].attrs.communities.as_ref().unwrap().len(), 2000);
+}
+
+#[tokio::test]
+async fn roles_from_peer_up() {
+    let store = InMemoryStore::default();
+    let (_tx, _task) = start_client(
+        &store,
+        vec![
+            initiation("router01"),
+            peer_up_with_opens(peer(1), &open_with_role(4), &open_with_role(4)),
+            route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 0), 24)),
+        ],
+    );
+
+    let routes = wait_for_routes(&store, peer(1), 1).await;
+    let session = routes[0].session.as_ref().unwrap();
+    assert_eq!(session.local_role, Some(BgpRole::Peer));
+    assert_eq!(session.peer_role, Some(BgpRole::Peer));
+    assert_eq!(session.peer_asn, Some(64496));
+}
+
+#[tokio::test]
+async fn timestamps_from_peer_header() {
+    let store = InMemoryStore::default();
+    let net = (Ipv4Addr::new(203, 0, 113, 0), 24);
+    let (_tx, _task) = start_client(
+        &store,
+        vec![
+            initiation("router01"),
+            with_timestamp(peer_up(peer(1)), 1700000000, 0),
+            with_timestamp(route_monitoring(peer(1), net), 1700000100, 500000),
+            // same attributes again
+            with_timestamp(route_monitoring(peer(1), net), 1700000200, 0),
+            with_timestamp(
+                route_monitoring(peer(1), (Ipv4Addr::new(198, 51, 100, 0), 24)),
+                1700000200,
+                0,
+            ),
+        ],
+    );
+
+    let first_seen = DateTime::from_timestamp(1700000100, 500000000).unwrap();
+    let routes = wait_for_routes(&store, peer(1), 2).await;
+    let routes: Vec<_> = routes
+        .into_iter()
+        .filter(|r| r.net.addr() == net.0)
+        .collect();
+    assert_eq!(routes[0].times.first_seen, first_seen);
+    assert_eq!(routes[0].times.last_modified, first_seen);
+    assert_eq!(
+        routes[0].session.as_ref().unwrap().established,
+        DateTime::from_timestamp(1700000000, 0)
+    );
+}
+
+#[tokio::test]
+async fn prefix_limit_teardown() {
+    let store = InMemoryStore::default();
+    let cfg = PeerConfig {
+        prefix_limit: Some(PrefixLimit {
+            max_prefixes: Some(1),
+            max_prefixes_per_table: None,
+            action: PrefixLimitAction::Teardown,
+        }),
+        ..Default::default()
+    };
+    let (mut tx, task) = start_client_with_config(
+        &store,
+        cfg,
+        vec![
+            initiation("router01"),
+            peer_up(peer(1)),
+            route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 0), 25)),
+        ],
+    );
+    wait_for_routes(&store, peer(1), 1).await;
+    assert!(!task.is_finished());
+
+    tx.write_all(&route_monitoring(
+        peer(1),
+        (Ipv4Addr::new(198, 51, 100, 0), 24),
+    ))
+    .await
+    .unwrap();
+    let err = task.await.unwrap().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ClientError>(),
+        Some(ClientError::PrefixLimitExceeded)
+    ));
+}
+
+#[tokio::test]
+async fn table_sizes_of_routers() {
+    let store = InMemoryStore::default();
+    let (_tx, _task) = start_client(
+        &store,
+        vec![
+            initiation("router01"),
+            peer_up(peer(1)),
+            route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 0), 25)),
+            route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 128), 25)),
+        ],
+    );
+    wait_for_routes(&store, peer(1), 2).await;
+
+    let router = store.get_routers().remove(&client_addr()).unwrap();
+    assert_eq!(router.table_sizes.len(), 1);
+    assert_eq!(router.table_sizes[0].prefixes, 2);
+}
+
+fn collector_config(yaml: &str) -> Result<BmpCollectorConfig, String> {
+    Figment::from(Yaml::string(yaml))
+        .extract()
+        .map_err(|e| e.to_string())
+}
+
+fn source(addr: &str) -> SocketAddr {
+    SocketAddr::new(addr.parse().unwrap(), 50000)
+}
+
+fn accepted_name(access_control: &AccessControl, addr: &str) -> Option<Option<String>> {
+    access_control
+        .accept(source(addr))
+        .map(|(peer_cfg, _slot)| peer_cfg.name_override)
+}
+
+#[test]
+fn longest_matching_peer_config() {
+    let cfg = collector_config(
+        r#"
+peers:
+  10.0.0.0/8:
+    name_override: wide
+  10.1.0.0/16:
+    name_override: narrow
+  10.1.2.3:
+    name_override: host
+  "2001:db8::/32":
+    name_override: v6
+"#,
+    )
+    .unwrap();
+    let access_control = AccessControl::new(&cfg);
+    assert_eq!(
+        accepted_name(&access_control, "10.2.0.1"),
+        Some(Some("wide".into()))
+    );
+    assert_eq!(
+        accepted_name(&access_control, "10.1.2.4"),
+        Some(Some("narrow".into()))
+    );
+    assert_eq!(
+        accepted_name(&access_control, "10.1.2.3"),
+        Some(Some("host".into()))
+    );
+    assert_eq!(
+        accepted_name(&access_control, "2001:db8::1"),
+        Some(Some("v6".into()))
+    );
+    assert_eq!(accepted_name(&access_control, "192.0.2.1"), None);
+}
+
+#[test]
+fn default_peer_config_and_deny() {
+    let cfg = collector_config(
+        r#"
+peers:
+  10.1.0.0/16:
+    name_override: narrow
+default_peer_config:
+  name_override: default
+deny:
+  - 10.1.2.0/24
+  - 192.0.2.0/24
+"#,
+    )
+    .unwrap();
+    let access_control = AccessControl::new(&cfg);
+    assert_eq!(
+        accepted_name(&access_control, "10.1.1.1"),
+        Some(Some("narrow".into()))
+    );
+    assert_eq!(
+        accepted_name(&access_control, "198.51.100.1"),
+        Some(Some("default".into()))
+    );
+    // denied networks win over more specific peer configs and the default
+    assert_eq!(accepted_name(&access_control, "10.1.2.1"), None);
+    assert_eq!(accepted_name(&access_control, "192.0.2.1"), None);
+}
+
+#[test]
+fn connections_per_source() {
+    let cfg = collector_config(
+        r#"
+default_peer_config: {}
+max_connections_per_source: 2
+"#,
+    )
+    .unwrap();
+    let access_control = AccessControl::new(&cfg);
+    let first = access_control.accept(source("192.0.2.1")).unwrap();
+    let second = access_control.accept(source("192.0.2.1")).unwrap();
+    assert!(access_control.accept(source("192.0.2.1")).is_none());
+    // the limit applies per address
+    assert!(access_control.accept(source("192.0.2.2")).is_some());
+
+    // closing a connection frees its slot
+    drop(first);
+    let third = access_control.accept(source("192.0.2.1")).unwrap();
+    assert!(access_control.accept(source("192.0.2.1")).is_none());
+    drop((second, third));
+    assert!(access_control.accept(source("192.0.2.1")).is_some());
+}
+
+#[test]
+fn overlapping_peer_keys_are_rejected() {
+    let err = collector_config(
+        r#"
+peers:
+  10.0.0.1/8: {}
+  10.0.0.0/8: {}
+"#,
+    )
+    .err()
+    .unwrap();
+    assert!(err.contains("multiple peer configs for network 10.0.0.0/8"));
+
+    let err = collector_config(
+        r#"
+peers:
+  192.0.2.1: {}
+  192.0.2.1/32: {}
+"#,
+    )
+    .err()
+    .unwrap();
+    assert!(err.contains("multiple peer configs for network 192.0.2.1/32"));
+}
+
+#[tokio::test]
+async fn active_target_reconnects() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap();
+    let cfg = collector_config(&format!("connect:\n  - target: \"{}\"\n", target)).unwrap();
+    let store = InMemoryStore::default();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let collector = tokio::spawn(bmp_collector::run(cfg, store.clone(), shutdown_rx));
+    let accept = || async {
+        tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap()
+            .0
+    };
+    let messages = [
+        initiation("router01"),
+        peer_up(peer(1)),
+        route_monitoring(peer(1), (Ipv4Addr::new(203, 0, 113, 0), 24)),
+    ]
+    .concat();
+
+    let mut io = accept().await;
+    io.write_all(&messages).await.unwrap();
+    wait_for_client_routes(&store, target, peer(1), 1).await;
+    assert!(store.get_routers().contains_key(&target));
+
+    // the router closing the connection takes the client down until the collector reconnects
+    drop(io);
+    wait_for_client_routes(&store, target, peer(1), 0).await;
+    assert!(!store.get_routers().contains_key(&target));
+
+    let mut io = accept().await;
+    io.write_all(&messages).await.unwrap();
+    wait_for_client_routes(&store, target, peer(1), 1).await;
+    assert!(store.get_routers().contains_key(&target));
+
+    shutdown_tx.send(true).unwrap();
+    collector.await.unwrap().unwrap();
+    assert!(!store.get_routers().contains_key(&target));
+}
@@ -0,0 +1,171 @@
+use bytes::Bytes;
+use fernglas::bmp_relay::{BmpRelay, RelayTargetConfig};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+
+fn client_addr() -> SocketAddr {
+    "192.0.2.1:50000".parse().unwrap()
+}
+
+fn bmp_message(msg_type: u8, body: &[u8]) -> Bytes {
+    let mut buf = vec![3];
+    buf.extend_from_slice(&(body.len() as u32 + 6).to_be_bytes());
+    buf.push(msg_type);
+    buf.extend_from_slice(body);
+    buf.into()
+}
+
+fn per_peer_header(peer: Ipv4Addr) -> Vec<u8> {
+    let mut buf = vec![0, 0];
+    buf.extend_from_slice(&[0; 8]);
+    buf.extend_from_slice(&[0; 12]);
+    buf.extend_from_slice(&peer.octets());
+    buf.extend_from_slice(&64496u32.to_be_bytes());
+    buf.extend_from_slice(&peer.octets());
+    buf.extend_from_slice(&[0; 8]);
+    buf
+}
+
+fn initiation() -> Bytes {
+    bmp_message(4, &[0, 2, 0, 4, b't', b'e', b's', b't'])
+}
+
+fn peer_up(peer: Ipv4Addr) -> Bytes {
+    let mut body = per_peer_header(peer);
+    body.extend_from_slice(&[0; 20]);
+    bmp_message(3, &body)
+}
+
+fn route_monitoring(peer: Ipv4Addr, n: u8) -> Bytes {
+    let mut body = per_peer_header(peer);
+    body.push(n);
+    bmp_message(0, &body)
+}
+
+fn termination() -> Bytes {
+    bmp_message(5, &[0, 1, 0, 2, 0, 0])
+}
+
+fn peer(n: u8) -> Ipv4Addr {
+    Ipv4Addr::new(198, 51, 100, n)
+}
+
+async fn target(buffer_size: usize) -> (TcpListener, RelayTargetConfig) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let cfg = RelayTargetConfig {
+        target: listener.local_addr().unwrap(),
+        buffer_size,
+    };
+    (listener, cfg)
+}
+
+async fn accept(listener: &TcpListener) -> TcpStream {
+    tokio::time::timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .unwrap()
+        .unwrap()
+        .0
+}
+
+async fn read_frame(stream: &mut TcpStream) -> Bytes {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        let mut header = [0; 6];
+        stream.read_exact(&mut header).await.unwrap();
+        let len = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+        let mut frame = header.to_vec();
+        frame.resize(len, 0);
+        stream.read_exact(&mut frame[6..]).await.unwrap();
+        Bytes::from(frame)
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn fan_out() {
+    let (listener1, cfg1) = target(16).await;
+    let (listener2, cfg2) = target(16).await;
+    let relay = BmpRelay::new(&[cfg1, cfg2], client_addr());
+
+    // messages sent before the targets are connected are queued
+    let messages = vec![
+        initiation(),
+        peer_up(peer(1)),
+        route_monitoring(peer(1), 1),
+        route_monitoring(peer(1), 2),
+        termination(),
+    ];
+    for msg in &messages {
+        relay.forward(msg);
+    }
+
+    for listener in [listener1, listener2] {
+        let mut stream = accept(&listener).await;
+        for msg in &messages {
+            assert_eq!(&read_frame(&mut stream).await, msg);
+        }
+    }
+}
+
+#[tokio::test]
+async fn lagging_target_reports_peers_down() {
+    let (listener, cfg) = target(4).await;
+    let relay = BmpRelay::new(&[cfg], client_addr());
+    relay.forward(&initiation());
+    relay.forward(&peer_up(peer(1)));
+    let mut stream = accept(&listener).await;
+    assert_eq!(read_frame(&mut stream).await, initiation());
+    assert_eq!(read_frame(&mut stream).await, peer_up(peer(1)));
+
+    // more messages than fit in the queue, without giving the target a chance to catch up
+    for n in 0..10 {
+        relay.forward(&route_monitoring(peer(1), n));
+    }
+    let peer_down = read_frame(&mut stream).await;
+    assert_eq!(peer_down[5], 2);
+    assert_eq!(&peer_down[6..48], &peer_up(peer(1))[6..48]);
+    assert_eq!(&peer_down[48..], &[5]);
+
+    // the peer stays down on the target until the router sends a new Peer Up
+    relay.forward(&route_monitoring(peer(1), 10));
+    relay.forward(&peer_up(peer(2)));
+    relay.forward(&route_monitoring(peer(2), 11));
+    assert_eq!(read_frame(&mut stream).await, peer_up(peer(2)));
+    assert_eq!(read_frame(&mut stream).await, route_monitoring(peer(2), 11));
+    relay.forward(&peer_up(peer(1)));
+    relay.forward(&route_monitoring(peer(1), 12));
+    assert_eq!(read_frame(&mut stream).await, peer_up(peer(1)));
+    assert_eq!(read_frame(&mut stream).await, route_monitoring(peer(1), 12));
+}
+
+#[tokio::test]
+async fn reconnect_sends_initiation_only() {
+    let (listener, cfg) = target(64).await;
+    let relay = BmpRelay::new(&[cfg], client_addr());
+    relay.forward(&initiation());
+    relay.forward(&peer_up(peer(1)));
+    let mut stream = accept(&listener).await;
+    assert_eq!(read_frame(&mut stream).await, initiation());
+    assert_eq!(read_frame(&mut stream).await, peer_up(peer(1)));
+    drop(stream);
+
+    // the relay notices the closed connection on the next writes
+    let mut stream = loop {
+        relay.forward(&route_monitoring(peer(1), 0));
+        if let Ok(Ok((stream, _))) =
+            tokio::time::timeout(Duration::from_millis(100), listener.accept()).await
+        {
+            break stream;
+        }
+    };
+    assert_eq!(read_frame(&mut stream).await, initiation());
+
+    // routes of the peer are only relayed again after its next Peer Up
+    relay.forward(&route_monitoring(peer(1), 1));
+    relay.forward(&peer_up(peer(1)));
+    relay.forward(&route_monitoring(peer(1), 2));
+    assert_eq!(read_frame(&mut stream).await, peer_up(peer(1)));
+    assert_eq!(read_frame(&mut stream).await, route_monitoring(peer(1), 2));
+}
@@ -0,0 +1,47 @@
+// each test crate only uses some of the helpers
+#![allow(dead_code)]
+
+use fernglas::sled_store::SledStore;
+use fernglas::store::Client;
+use fernglas::store_impl::InMemoryStore;
+use std::net::Ipv4Addr;
+
+pub fn in_memory_store() -> InMemoryStore {
+    InMemoryStore::default()
+}
+
+pub fn sled_store() -> SledStore {
+    SledStore::temporary().unwrap()
+}
+
+/// The router all routes in the tests are received from
+pub fn client() -> Client {
+    Client {
+        client_name: "router01".to_string(),
+        router_id: Ipv4Addr::new(192, 0, 2, 1),
+        ..Default::default()
+    }
+}
+
+/// Runs each of the given tests, taking a store as only argument, against all store implementations
+#[macro_export]
+macro_rules! store_tests {
+    ($($test:ident),* $(,)?) => {
+        mod in_memory {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    super::$test($crate::common::in_memory_store()).await
+                }
+            )*
+        }
+        mod sled {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    super::$test($crate::common::sled_store()).await
+                }
+            )*
+        }
+    };
+}
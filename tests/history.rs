@@ -0,0 +1,334 @@
+use chrono::{DateTime, Duration, Utc};
+use fernglas::history::{HistoryConfig, HistoryEvent, RouteEventKind};
+use fernglas::store::{
+    NetQuery, Query, QueryLimits, QueryResult, RouteAttrs, RouteState, SessionId, Store,
+    TableQuery, TableSelector,
+};
+use fernglas::store_impl::InMemoryStore;
+use futures_util::StreamExt;
+use ipnet::IpNet;
+use std::net::{Ipv4Addr, SocketAddr};
+
+mod common;
+
+fn client_addr() -> SocketAddr {
+    "192.0.2.1:50000".parse().unwrap()
+}
+
+fn session(n: u8) -> SessionId {
+    SessionId {
+        from_client: client_addr(),
+        peer_address: Ipv4Addr::new(198, 51, 100, n).into(),
+    }
+}
+
+fn net() -> IpNet {
+    "203.0.113.0/24".parse().unwrap()
+}
+
+fn attrs(med: u32) -> RouteAttrs {
+    RouteAttrs {
+        med: Some(med),
+        ..Default::default()
+    }
+}
+
+async fn store(cfg: HistoryConfig) -> InMemoryStore {
+    let store = InMemoryStore::with_history(cfg);
+    store
+        .client_up(client_addr(), RouteState::Accepted, common::client(), None)
+        .await;
+    store
+}
+
+fn config() -> HistoryConfig {
+    HistoryConfig {
+        max_events_per_path: 100,
+        max_age: 86400,
+        journal: None,
+    }
+}
+
+async fn update(store: &InMemoryStore, session: SessionId, med: u32, timestamp: DateTime<Utc>) {
+    update_net(store, session, net(), med, timestamp).await;
+}
+
+async fn update_net(
+    store: &InMemoryStore,
+    session: SessionId,
+    net: IpNet,
+    med: u32,
+    timestamp: DateTime<Utc>,
+) {
+    let table = TableSelector::PrePolicyAdjIn(session);
+    store
+        .update_route(0, net, table, attrs(med), timestamp)
+        .await;
+}
+
+async fn query_at(
+    store: &InMemoryStore,
+    net_query: NetQuery,
+    at: DateTime<Utc>,
+) -> Vec<QueryResult> {
+    store
+        .get_routes(Query {
+            table_query: None,
+            net_query,
+            limits: None,
+            as_path_regex: None,
+            route_leak: false,
+            min_age: None,
+            max_age: None,
+            at: Some(at),
+        })
+        .unwrap()
+        .collect()
+        .await
+}
+
+async fn withdraw(store: &InMemoryStore, session: SessionId, timestamp: DateTime<Utc>) {
+    let table = TableSelector::PrePolicyAdjIn(session);
+    store.withdraw_route(0, net(), table, timestamp).await;
+}
+
+fn kinds(events: &[HistoryEvent]) -> Vec<RouteEventKind> {
+    events.iter().map(|event| event.kind).collect()
+}
+
+#[tokio::test]
+async fn timeline_of_a_path() {
+    let store = store(config()).await;
+    let t0 = Utc::now() - Duration::hours(1);
+
+    update(&store, session(1), 10, t0).await;
+    // unchanged attributes are not recorded
+    update(&store, session(1), 10, t0 + Duration::seconds(1)).await;
+    update(&store, session(1), 20, t0 + Duration::seconds(2)).await;
+    withdraw(&store, session(1), t0 + Duration::seconds(3)).await;
+    // withdrawal of a route which does not exist
+    withdraw(&store, session(1), t0 + Duration::seconds(4)).await;
+
+    let events = store.get_history(net(), None).unwrap();
+    assert_eq!(
+        kinds(&events),
+        vec![
+            RouteEventKind::Announce,
+            RouteEventKind::Change,
+            RouteEventKind::Withdraw
+        ]
+    );
+    assert_eq!(events[0].timestamp, t0);
+    assert_eq!(events[1].attrs.as_ref().unwrap().med, Some(20));
+    assert_eq!(events[2].timestamp, t0 + Duration::seconds(3));
+    assert!(events[2].attrs.is_none());
+}
+
+#[tokio::test]
+async fn filter_by_session() {
+    let store = store(config()).await;
+    let t0 = Utc::now() - Duration::hours(1);
+    update(&store, session(1), 10, t0).await;
+    update(&store, session(2), 10, t0 + Duration::seconds(1)).await;
+
+    assert_eq!(store.get_history(net(), None).unwrap().len(), 2);
+    let events = store
+        .get_history(net(), Some(TableQuery::Session(session(2))))
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].table, TableSelector::PrePolicyAdjIn(session(2)));
+    assert!(store
+        .get_history("203.0.113.0/25".parse().unwrap(), None)
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn retention_by_count() {
+    let store = store(HistoryConfig {
+        max_events_per_path: 2,
+        max_age: 86400,
+        journal: None,
+    })
+    .await;
+    let t0 = Utc::now() - Duration::hours(1);
+    for i in 0..5 {
+        update(&store, session(1), i, t0 + Duration::seconds(i as i64)).await;
+    }
+
+    let events = store.get_history(net(), None).unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].attrs.as_ref().unwrap().med, Some(3));
+    assert_eq!(events[1].attrs.as_ref().unwrap().med, Some(4));
+}
+
+#[tokio::test]
+async fn retention_by_age() {
+    let store = store(HistoryConfig {
+        max_events_per_path: 100,
+        max_age: 3600,
+        journal: None,
+    })
+    .await;
+    let now = Utc::now();
+    update(&store, session(1), 10, now - Duration::hours(2)).await;
+    update(&store, session(1), 20, now - Duration::minutes(1)).await;
+
+    let events = store.get_history(net(), None).unwrap();
+    assert_eq!(kinds(&events), vec![RouteEventKind::Change]);
+}
+
+#[tokio::test]
+async fn state_before_max_age_is_kept() {
+    let store = store(HistoryConfig {
+        max_events_per_path: 100,
+        max_age: 3600,
+        journal: None,
+    })
+    .await;
+    let now = Utc::now();
+    update(&store, session(1), 10, now - Duration::hours(3)).await;
+    update(&store, session(1), 20, now - Duration::hours(2)).await;
+
+    // the path still exists, so its last state before `max_age` is needed for queries
+    let routes = query_at(&store, NetQuery::Exact(net()), now).await;
+    assert_eq!(routes.len(), 1);
+    assert_eq!(routes[0].attrs.med, Some(20));
+    assert!(store.get_history(net(), None).unwrap().is_empty());
+
+    // once the withdrawal is older than `max_age`, nothing of the path is kept
+    withdraw(
+        &store,
+        session(1),
+        now - Duration::hours(1) - Duration::minutes(1),
+    )
+    .await;
+    let routes = query_at(&store, NetQuery::Exact(net()), now - Duration::minutes(90)).await;
+    assert!(routes.is_empty());
+}
+
+#[tokio::test]
+async fn session_down_withdraws_routes() {
+    let store = store(config()).await;
+    update(&store, session(1), 10, Utc::now() - Duration::hours(1)).await;
+    store.session_down(session(1), None).await;
+
+    let events = store.get_history(net(), None).unwrap();
+    assert_eq!(
+        kinds(&events),
+        vec![RouteEventKind::Announce, RouteEventKind::Withdraw]
+    );
+}
+
+#[tokio::test]
+async fn disabled_by_default() {
+    let store = InMemoryStore::default();
+    update(&store, session(1), 10, Utc::now()).await;
+    assert!(store.get_history(net(), None).is_none());
+}
+
+#[tokio::test]
+async fn routes_at_a_time() {
+    let store = store(config()).await;
+    let t0 = Utc::now() - Duration::hours(1);
+    update(&store, session(1), 10, t0).await;
+    update(&store, session(1), 20, t0 + Duration::seconds(10)).await;
+    withdraw(&store, session(1), t0 + Duration::seconds(20)).await;
+
+    let at = |secs| query_at(&store, NetQuery::Exact(net()), t0 + Duration::seconds(secs));
+    assert!(at(-5).await.is_empty());
+
+    let routes = at(5).await;
+    assert_eq!(routes.len(), 1);
+    assert_eq!(routes[0].attrs.med, Some(10));
+    assert_eq!(routes[0].times.first_seen, t0);
+    assert_eq!(routes[0].times.last_modified, t0);
+
+    let routes = at(15).await;
+    assert_eq!(routes[0].attrs.med, Some(20));
+    assert_eq!(routes[0].times.first_seen, t0);
+    assert_eq!(routes[0].times.last_modified, t0 + Duration::seconds(10));
+
+    assert!(at(25).await.is_empty());
+}
+
+#[tokio::test]
+async fn routes_of_clients_which_are_gone() {
+    let store = store(config()).await;
+    let t0 = Utc::now() - Duration::hours(1);
+    update(&store, session(1), 10, t0).await;
+    store.client_down(client_addr()).await;
+
+    let routes = query_at(&store, NetQuery::Exact(net()), t0 + Duration::seconds(1)).await;
+    assert_eq!(routes.len(), 1);
+    assert_eq!(routes[0].client.client_name, "router01");
+    assert!(query_at(&store, NetQuery::Exact(net()), Utc::now())
+        .await
+        .is_empty());
+}
+
+#[tokio::test]
+async fn most_specific_at_a_time() {
+    let store = store(config()).await;
+    let t0 = Utc::now() - Duration::hours(1);
+    let more_specific: IpNet = "203.0.113.0/25".parse().unwrap();
+    update(&store, session(1), 10, t0).await;
+    update_net(
+        &store,
+        session(1),
+        more_specific,
+        10,
+        t0 + Duration::seconds(10),
+    )
+    .await;
+    let table = TableSelector::PrePolicyAdjIn(session(1));
+    store
+        .withdraw_route(0, more_specific, table, t0 + Duration::seconds(20))
+        .await;
+
+    let addr: IpNet = "203.0.113.1/32".parse().unwrap();
+    let routes = query_at(
+        &store,
+        NetQuery::MostSpecific(addr),
+        t0 + Duration::seconds(15),
+    )
+    .await;
+    assert_eq!(routes[0].net, more_specific);
+    let routes = query_at(
+        &store,
+        NetQuery::MostSpecific(addr),
+        t0 + Duration::seconds(25),
+    )
+    .await;
+    assert_eq!(routes[0].net, net());
+}
+
+#[tokio::test]
+async fn limits_at_a_time() {
+    let store = store(config()).await;
+    let t0 = Utc::now() - Duration::hours(1);
+    for n in 0..4 {
+        let net = format!("203.0.113.{}/32", n).parse().unwrap();
+        update_net(&store, session(1), net, 10, t0).await;
+        update_net(&store, session(2), net, 10, t0).await;
+    }
+
+    let query = |max_results_per_table, max_results| Query {
+        table_query: None,
+        net_query: NetQuery::OrLonger(net()),
+        limits: Some(QueryLimits {
+            max_results_per_table,
+            max_results,
+        }),
+        as_path_regex: None,
+        route_leak: false,
+        min_age: None,
+        max_age: None,
+        at: Some(Utc::now()),
+    };
+    let routes: Vec<_> = store.get_routes(query(0, 3)).unwrap().collect().await;
+    assert_eq!(routes.len(), 3);
+    let routes: Vec<_> = store.get_routes(query(1, 0)).unwrap().collect().await;
+    assert_eq!(routes.len(), 2);
+    assert_ne!(routes[0].table, routes[1].table);
+}
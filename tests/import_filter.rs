@@ -0,0 +1,245 @@
+use chrono::Utc;
+use fernglas::import_filter::ImportFilter;
+use fernglas::raw_update::RawUpdate;
+use fernglas::store::{
+    NetQuery, Query, RouteAttrs, RouteOrigin, RouteState, Store, TableQuery, TableSelector,
+};
+use fernglas::store_impl::InMemoryStore;
+use figment::providers::{Format, Yaml};
+use figment::Figment;
+use futures_util::StreamExt;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use zettabgp::prelude::*;
+
+mod common;
+
+#[derive(Deserialize)]
+struct Config {
+    import_filter: ImportFilter,
+}
+
+fn filter(yaml: &str) -> ImportFilter {
+    Figment::from(Yaml::string(yaml))
+        .extract::<Config>()
+        .unwrap()
+        .import_filter
+}
+
+fn attrs() -> RouteAttrs {
+    RouteAttrs {
+        origin: Some(RouteOrigin::Igp),
+        as_path: Some(vec![64497, 64498]),
+        communities: Some(vec![(64496, 1), (64496, 1001), (65535, 666)]),
+        large_communities: Some(vec![(64496, 1, 2)]),
+        ..Default::default()
+    }
+}
+
+fn apply(filter: &ImportFilter, net: &str) -> Option<RouteAttrs> {
+    filter.apply(&net.parse::<IpNet>().unwrap(), attrs())
+}
+
+#[test]
+fn prefix_with_length_range() {
+    let filter = filter(
+        r#"
+import_filter:
+  - match:
+      prefix:
+        - net: 10.0.0.0/8
+          max_len: 32
+        - net: 0.0.0.0/0
+          min_len: 25
+          max_len: 32
+    action: Drop
+"#,
+    );
+    assert!(apply(&filter, "10.0.0.0/8").is_none());
+    assert!(apply(&filter, "10.1.2.0/24").is_none());
+    assert!(apply(&filter, "192.0.2.1/32").is_none());
+    assert!(apply(&filter, "192.0.2.0/24").is_some());
+    assert!(apply(&filter, "2001:db8::/32").is_some());
+}
+
+#[test]
+fn exact_prefix_by_default() {
+    let filter = filter(
+        r#"
+import_filter:
+  - match:
+      prefix:
+        - net: 192.0.2.0/24
+    action: Drop
+"#,
+    );
+    assert!(apply(&filter, "192.0.2.0/24").is_none());
+    assert!(apply(&filter, "192.0.2.0/25").is_some());
+}
+
+fn as_path_and_origin_filter(origin: &str) -> ImportFilter {
+    filter(&format!(
+        r#"
+import_filter:
+  - match:
+      as_path_regex: "^64497 "
+      origin: {}
+    action: Drop
+"#,
+        origin
+    ))
+}
+
+#[test]
+fn as_path_and_origin() {
+    assert!(apply(&as_path_and_origin_filter("Igp"), "192.0.2.0/24").is_none());
+    assert!(apply(&as_path_and_origin_filter("Incomplete"), "192.0.2.0/24").is_some());
+}
+
+#[test]
+fn community_match() {
+    let filter = filter(
+        r#"
+import_filter:
+  - match:
+      community_regex: "^65535:666$"
+    action: Drop
+"#,
+    );
+    assert!(apply(&filter, "192.0.2.0/24").is_none());
+}
+
+#[test]
+fn strip_and_rewrite_communities() {
+    let filter = filter(
+        r#"
+import_filter:
+  - strip_communities: "^64496:1\\d{3}$"
+    rewrite_communities:
+      - pattern: "^64496:1$"
+        replacement: "64496:100"
+      - pattern: "^64496:(\\d+):2$"
+        replacement: "64496:$1:20"
+      - pattern: "^65535:.*"
+        replacement: "invalid"
+"#,
+    );
+    let attrs = apply(&filter, "192.0.2.0/24").unwrap();
+    assert_eq!(attrs.communities, Some(vec![(64496, 100)]));
+    assert_eq!(attrs.large_communities, Some(vec![(64496, 1, 20)]));
+}
+
+#[test]
+fn accept_stops_evaluation() {
+    let filter = filter(
+        r#"
+import_filter:
+  - match:
+      prefix:
+        - net: 192.0.2.0/24
+    action: Accept
+  - action: Drop
+"#,
+    );
+    assert!(apply(&filter, "192.0.2.0/24").is_some());
+    assert!(apply(&filter, "198.51.100.0/24").is_none());
+}
+
+#[test]
+fn peer_rules_before_collector_rules() {
+    let peer = filter(
+        r#"
+import_filter:
+  - match:
+      prefix:
+        - net: 192.0.2.0/24
+    action: Accept
+"#,
+    );
+    let collector = filter(
+        r#"
+import_filter:
+  - action: Drop
+"#,
+    );
+    let filter = peer.then(&collector);
+    assert!(apply(&filter, "192.0.2.0/24").is_some());
+    assert!(apply(&filter, "198.51.100.0/24").is_none());
+}
+
+#[test]
+fn invalid_regex_is_a_config_error() {
+    let res = Figment::from(Yaml::string(
+        r#"
+import_filter:
+  - match:
+      as_path_regex: "("
+    action: Drop
+"#,
+    ))
+    .extract::<Config>();
+    assert!(res.is_err());
+}
+
+fn update(communities: Vec<u32>) -> BgpUpdateMessage {
+    let mut update = BgpUpdateMessage::new();
+    update.attrs = vec![
+        BgpAttrItem::Origin(BgpOrigin::new(BgpAttrOrigin::Igp)),
+        BgpAttrItem::ASPath(BgpASpath::from(vec![64497])),
+        BgpAttrItem::NextHop(BgpNextHop::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))),
+        BgpAttrItem::CommunityList(BgpCommunityList {
+            value: communities.into_iter().map(BgpCommunity::new).collect(),
+        }),
+    ];
+    update.updates = BgpAddrs::IPV4U(vec![BgpAddrV4::new(Ipv4Addr::new(203, 0, 113, 0), 24)]);
+    update
+}
+
+#[tokio::test]
+async fn dropped_route_replaces_imported_one() {
+    let filter = filter(
+        r#"
+import_filter:
+  - match:
+      community_regex: "^65535:666$"
+    action: Drop
+"#,
+    );
+    let store = InMemoryStore::default();
+    let client_addr: SocketAddr = "192.0.2.1:179".parse().unwrap();
+    store
+        .client_up(client_addr, RouteState::Accepted, common::client(), None)
+        .await;
+    let table = TableSelector::LocRib {
+        from_client: client_addr,
+        route_state: RouteState::Accepted,
+    };
+    let routes = || async {
+        store
+            .get_routes(Query {
+                table_query: Some(TableQuery::Client(client_addr)),
+                net_query: NetQuery::OrLonger("0.0.0.0/0".parse().unwrap()),
+                limits: None,
+                as_path_regex: None,
+                route_leak: false,
+                min_age: None,
+                max_age: None,
+                at: None,
+            })
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+    };
+    let raw = || RawUpdate::parse(&[], true);
+
+    store
+        .insert_bgp_update(table.clone(), update(vec![]), raw(), &filter, Utc::now())
+        .await;
+    assert_eq!(routes().await.len(), 1);
+
+    store
+        .insert_bgp_update(table, update(vec![0xffff029a]), raw(), &filter, Utc::now())
+        .await;
+    assert!(routes().await.is_empty());
+}
@@ -0,0 +1,191 @@
+use chrono::{DateTime, Utc};
+use fernglas::history::HistoryConfig;
+use fernglas::store::{NetQuery, Query, RouteAttrs, RouteState, SessionId, Store, TableSelector};
+use fernglas::store_impl::InMemoryStore;
+use futures_util::StreamExt;
+use ipnet::{IpNet, Ipv4Net};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod common;
+
+struct Throughput {
+    updates: usize,
+    elapsed: Duration,
+    queries: usize,
+}
+
+impl Throughput {
+    fn updates_per_sec(&self) -> f64 {
+        self.updates as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+fn session(n: usize) -> SessionId {
+    SessionId {
+        from_client: "192.0.2.1:50000".parse().unwrap(),
+        peer_address: Ipv4Addr::new(198, 51, 100, n as u8).into(),
+    }
+}
+
+fn query(at: Option<DateTime<Utc>>) -> Query {
+    Query {
+        table_query: None,
+        net_query: NetQuery::OrLonger("10.0.0.0/8".parse().unwrap()),
+        limits: None,
+        as_path_regex: Some("^64496 64598$".to_string()),
+        route_leak: false,
+        min_age: None,
+        max_age: None,
+        at,
+    }
+}
+
+/// Announces `routes_per_session` routes in each of `sessions` sessions, each from its own task
+fn spawn_ingest(
+    store: &impl Store,
+    sessions: usize,
+    routes_per_session: usize,
+    med: u32,
+) -> Vec<tokio::task::JoinHandle<()>> {
+    (0..sessions)
+        .map(|n| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                let table = TableSelector::PrePolicyAdjIn(session(n));
+                for i in 0..routes_per_session {
+                    let net = Ipv4Net::new(Ipv4Addr::from(0x0a00_0000 + (i as u32) * 256), 24);
+                    let net = IpNet::V4(net.unwrap());
+                    let attrs = RouteAttrs {
+                        as_path: Some(vec![64496, 64497 + (i % 100) as u32]),
+                        communities: Some(vec![(64496, (i % 10) as u16)]),
+                        med: Some(med + n as u32),
+                        ..Default::default()
+                    };
+                    store
+                        .update_route(0, net, table.clone(), attrs, Utc::now())
+                        .await;
+                    // collectors wait for messages from the network in between
+                    if i % 100 == 0 {
+                        tokio::task::yield_now().await;
+                    }
+                }
+            })
+        })
+        .collect()
+}
+
+/// Ingests routes while another task keeps querying all tables
+async fn ingest(store: impl Store, sessions: usize, routes_per_session: usize) -> Throughput {
+    let start = Instant::now();
+    let ingest_tasks = spawn_ingest(&store, sessions, routes_per_session, 0);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let query_store = store.clone();
+    let query_stop = stop.clone();
+    let query_task = tokio::spawn(async move {
+        let mut queries = 0;
+        while !query_stop.load(Ordering::Relaxed) {
+            let routes: Vec<_> = query_store.get_routes(query(None)).unwrap().collect().await;
+            drop(routes);
+            queries += 1;
+            tokio::task::yield_now().await;
+        }
+        queries
+    });
+
+    for task in ingest_tasks {
+        task.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    stop.store(true, Ordering::Relaxed);
+    let queries = query_task.await.unwrap();
+
+    let sizes = store.get_table_sizes(&session(0).from_client);
+    assert_eq!(sizes.len(), sessions);
+    assert!(sizes.values().all(|size| *size == routes_per_session));
+
+    Throughput {
+        updates: sessions * routes_per_session,
+        elapsed,
+        queries,
+    }
+}
+
+async fn report(name: &str, store: impl Store, sessions: usize, routes_per_session: usize) {
+    let throughput = ingest(store, sessions, routes_per_session).await;
+    println!(
+        "{}: {} updates from {} sessions in {:?}, {:.0} updates/s, {} queries",
+        name,
+        throughput.updates,
+        sessions,
+        throughput.elapsed,
+        throughput.updates_per_sec(),
+        throughput.queries,
+    );
+}
+
+/// Changes all routes while a query which matches all of them is not read any further,
+/// optionally querying the route history
+async fn ingest_during_query(store: impl Store, history: bool) {
+    store
+        .client_up(
+            session(0).from_client,
+            RouteState::Seen,
+            common::client(),
+            None,
+        )
+        .await;
+    for task in spawn_ingest(&store, 4, 1000, 0) {
+        task.await.unwrap();
+    }
+    let mut routes = store
+        .get_routes(Query {
+            as_path_regex: None,
+            ..query(history.then(Utc::now))
+        })
+        .unwrap();
+    assert!(routes.next().await.is_some());
+
+    tokio::time::timeout(Duration::from_secs(30), async {
+        for task in spawn_ingest(&store, 4, 1000, 100) {
+            task.await.unwrap();
+        }
+    })
+    .await
+    .expect("ingest is blocked by the query");
+    drop(routes);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn ingest_continues_during_query() {
+    ingest_during_query(common::in_memory_store(), false).await;
+    ingest_during_query(common::sled_store(), false).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn ingest_continues_during_history_query() {
+    let store = InMemoryStore::with_history(HistoryConfig {
+        max_events_per_path: 100,
+        max_age: 86400,
+        journal: None,
+    });
+    ingest_during_query(store, true).await;
+}
+
+/// Throughput with concurrent queries, the numbers are only printed
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_ingest() {
+    report("in-memory", common::in_memory_store(), 8, 2000).await;
+}
+
+/// Throughput with full tables, run with
+/// `cargo test --release --test ingest -- --ignored --nocapture`
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn concurrent_ingest_benchmark() {
+    report("in-memory", common::in_memory_store(), 16, 100_000).await;
+    report("sled", common::sled_store(), 16, 100_000).await;
+}
@@ -0,0 +1,172 @@
+use chrono::{Duration, Utc};
+use fernglas::history::{HistoryConfig, RouteEventKind};
+use fernglas::journal;
+use fernglas::store::{
+    NetQuery, Query, QueryResult, RouteAttrs, RouteState, SessionId, Store, TableSelector,
+};
+use fernglas::store_impl::InMemoryStore;
+use futures_util::StreamExt;
+use ipnet::IpNet;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+mod common;
+
+fn client_addr() -> SocketAddr {
+    "192.0.2.1:50000".parse().unwrap()
+}
+
+fn session() -> SessionId {
+    SessionId {
+        from_client: client_addr(),
+        peer_address: Ipv4Addr::new(198, 51, 100, 1).into(),
+    }
+}
+
+fn net() -> IpNet {
+    "203.0.113.0/24".parse().unwrap()
+}
+
+fn attrs(med: u32) -> RouteAttrs {
+    RouteAttrs {
+        med: Some(med),
+        ..Default::default()
+    }
+}
+
+fn path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("fernglas-{}-{}.jsonl", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn store(journal: &Path) -> InMemoryStore {
+    InMemoryStore::with_history(HistoryConfig {
+        max_events_per_path: 100,
+        max_age: 86400,
+        journal: Some(journal.to_owned()),
+    })
+}
+
+async fn query_at(store: &InMemoryStore, at: chrono::DateTime<Utc>) -> Vec<QueryResult> {
+    store
+        .get_routes(Query {
+            table_query: None,
+            net_query: NetQuery::Exact(net()),
+            limits: None,
+            as_path_regex: None,
+            route_leak: false,
+            min_age: None,
+            max_age: None,
+            at: Some(at),
+        })
+        .unwrap()
+        .collect()
+        .await
+}
+
+#[tokio::test]
+async fn history_is_restored_from_the_journal() {
+    let path = path("journal");
+    let store = store(&path);
+    let t0 = Utc::now() - Duration::hours(1);
+    let adj_in = TableSelector::PrePolicyAdjIn(session());
+    let loc_rib = TableSelector::LocRib {
+        from_client: client_addr(),
+        route_state: RouteState::Selected,
+    };
+
+    store
+        .client_up(client_addr(), RouteState::Seen, common::client(), None)
+        .await;
+    // recorded before the writer starts, so it is part of the initial compaction
+    store
+        .update_route(0, net(), adj_in.clone(), attrs(10), t0)
+        .await;
+
+    let writer = tokio::spawn(journal::run(path.clone(), store.clone()));
+    while !path.exists() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    store
+        .update_route(
+            0,
+            net(),
+            adj_in.clone(),
+            attrs(20),
+            t0 + Duration::seconds(10),
+        )
+        .await;
+    store
+        .update_route(0, net(), loc_rib, attrs(20), t0 + Duration::seconds(12))
+        .await;
+    store
+        .withdraw_route(0, net(), adj_in, t0 + Duration::seconds(20))
+        .await;
+    store.close_journal();
+    writer.await.unwrap().unwrap();
+
+    // changes after closing the journal are not written, e.g. withdrawals on shutdown
+    store.client_down(client_addr()).await;
+
+    let restored = self::store(&path);
+    journal::load(&restored, &path).await.unwrap();
+    let events = restored.get_history(net(), None).unwrap();
+    let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            RouteEventKind::Announce,
+            RouteEventKind::Change,
+            RouteEventKind::Announce,
+            RouteEventKind::Withdraw
+        ]
+    );
+
+    let routes = query_at(&restored, t0 + Duration::seconds(15)).await;
+    assert_eq!(routes.len(), 2);
+    assert!(routes.iter().all(|route| route.attrs.med == Some(20)));
+    assert!(routes
+        .iter()
+        .all(|route| route.client.client_name == "router01"));
+    assert!(routes
+        .iter()
+        .any(|route| route.state == RouteState::Selected));
+    let routes = query_at(&restored, Utc::now()).await;
+    assert_eq!(routes.len(), 1);
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn incomplete_journal_is_restored_up_to_the_last_line() {
+    let path = path("journal-incomplete");
+    let store = store(&path);
+    let t0 = Utc::now() - Duration::hours(1);
+    store
+        .update_route(
+            0,
+            net(),
+            TableSelector::PrePolicyAdjIn(session()),
+            attrs(10),
+            t0,
+        )
+        .await;
+    let writer = tokio::spawn(journal::run(path.clone(), store.clone()));
+    while !path.exists() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    store.close_journal();
+    writer.await.unwrap().unwrap();
+
+    // e.g. interrupted while writing
+    let mut journal = std::fs::read(&path).unwrap();
+    journal.extend_from_slice(b"{\"Event\":{\"tab");
+    std::fs::write(&path, journal).unwrap();
+
+    let restored = self::store(&path);
+    journal::load(&restored, &path).await.unwrap();
+    assert_eq!(restored.get_history(net(), None).unwrap().len(), 1);
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,79 @@
+use chrono::Utc;
+use fernglas::store::{NetQuery, Query, QueryLimits, RouteAttrs, RouteState, Store, TableSelector};
+use fernglas::store_impl::InMemoryStore;
+use futures_util::StreamExt;
+use ipnet::{IpNet, Ipv4Net};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+mod common;
+
+fn client_addr() -> SocketAddr {
+    "192.0.2.1:179".parse().unwrap()
+}
+
+fn table() -> TableSelector {
+    TableSelector::LocRib {
+        from_client: client_addr(),
+        route_state: RouteState::Accepted,
+    }
+}
+
+fn net(i: u32) -> IpNet {
+    IpNet::V4(Ipv4Net::new(Ipv4Addr::from(0x0a00_0000 + i * 256), 24).unwrap())
+}
+
+async fn update_all(store: &InMemoryStore, routes: u32, med: u32) {
+    for i in 0..routes {
+        let attrs = RouteAttrs {
+            med: Some(med),
+            ..Default::default()
+        };
+        store
+            .update_route(0, net(i), table(), attrs, Utc::now())
+            .await;
+    }
+}
+
+#[tokio::test]
+async fn query_is_unaffected_by_later_updates() {
+    let store = InMemoryStore::default();
+    store
+        .client_up(client_addr(), RouteState::Accepted, common::client(), None)
+        .await;
+    update_all(&store, 1000, 10).await;
+
+    let mut routes = store
+        .get_routes(Query {
+            table_query: None,
+            net_query: NetQuery::OrLonger("10.0.0.0/8".parse().unwrap()),
+            limits: Some(QueryLimits {
+                max_results_per_table: 0,
+                max_results: 0,
+            }),
+            as_path_regex: None,
+            route_leak: false,
+            min_age: None,
+            max_age: None,
+            at: None,
+        })
+        .unwrap();
+    let first = routes.next().await.unwrap();
+
+    // the unfinished query does not hold up updates
+    tokio::time::timeout(Duration::from_secs(10), async {
+        update_all(&store, 1000, 20).await;
+        for i in 0..500 {
+            store.withdraw_route(0, net(i), table(), Utc::now()).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let rest: Vec<_> = routes.collect().await;
+    assert_eq!(rest.len() + 1, 1000);
+    assert!(std::iter::once(&first)
+        .chain(&rest)
+        .all(|route| route.attrs.med == Some(10)));
+    assert_eq!(store.get_table_sizes(&client_addr())[&table()], 500);
+}
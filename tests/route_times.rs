@@ -0,0 +1,122 @@
+use chrono::{DateTime, Duration, Utc};
+use fernglas::store::{
+    NetQuery, Query, QueryResult, RouteAttrs, RouteState, Store, TableQuery, TableSelector,
+};
+use futures_util::StreamExt;
+use ipnet::IpNet;
+use std::net::SocketAddr;
+
+mod common;
+
+fn client_addr() -> SocketAddr {
+    "192.0.2.1:179".parse().unwrap()
+}
+
+fn table() -> TableSelector {
+    TableSelector::LocRib {
+        from_client: client_addr(),
+        route_state: RouteState::Accepted,
+    }
+}
+
+fn attrs(med: u32) -> RouteAttrs {
+    RouteAttrs {
+        as_path: Some(vec![64497]),
+        med: Some(med),
+        ..Default::default()
+    }
+}
+
+async fn client_up<T: Store>(store: T) -> T {
+    store
+        .client_up(client_addr(), RouteState::Accepted, common::client(), None)
+        .await;
+    store
+}
+
+async fn update(store: &impl Store, net: &str, med: u32, timestamp: DateTime<Utc>) {
+    let net: IpNet = net.parse().unwrap();
+    store
+        .update_route(0, net, table(), attrs(med), timestamp)
+        .await;
+}
+
+async fn query(store: &impl Store, min_age: Option<u64>, max_age: Option<u64>) -> Vec<QueryResult> {
+    let mut routes: Vec<_> = store
+        .get_routes(Query {
+            table_query: Some(TableQuery::Client(client_addr())),
+            net_query: NetQuery::OrLonger("0.0.0.0/0".parse().unwrap()),
+            limits: None,
+            as_path_regex: None,
+            route_leak: false,
+            min_age,
+            max_age,
+            at: None,
+        })
+        .unwrap()
+        .collect()
+        .await;
+    routes.sort_by_key(|route| route.net);
+    routes
+}
+
+async fn last_modified_only_changes_with_attributes(store: impl Store) {
+    let store = client_up(store).await;
+    let t0 = Utc::now() - Duration::hours(1);
+
+    update(&store, "203.0.113.0/24", 10, t0).await;
+    update(&store, "203.0.113.0/24", 10, t0 + Duration::minutes(1)).await;
+    let routes = query(&store, None, None).await;
+    assert_eq!(routes[0].times.first_seen, t0);
+    assert_eq!(routes[0].times.last_modified, t0);
+
+    update(&store, "203.0.113.0/24", 20, t0 + Duration::minutes(2)).await;
+    let routes = query(&store, None, None).await;
+    assert_eq!(routes[0].times.first_seen, t0);
+    assert_eq!(routes[0].times.last_modified, t0 + Duration::minutes(2));
+}
+
+async fn withdrawal_resets_first_seen(store: impl Store) {
+    let store = client_up(store).await;
+    let t0 = Utc::now() - Duration::hours(1);
+    let net: IpNet = "203.0.113.0/24".parse().unwrap();
+
+    update(&store, "203.0.113.0/24", 10, t0).await;
+    store.withdraw_route(0, net, table(), t0).await;
+    update(&store, "203.0.113.0/24", 10, t0 + Duration::minutes(1)).await;
+    let routes = query(&store, None, None).await;
+    assert_eq!(routes[0].times.first_seen, t0 + Duration::minutes(1));
+}
+
+async fn filter_by_age(store: impl Store) {
+    let store = client_up(store).await;
+    let now = Utc::now();
+    update(&store, "192.0.2.0/24", 10, now - Duration::days(2)).await;
+    update(&store, "198.51.100.0/24", 10, now - Duration::hours(2)).await;
+    update(&store, "203.0.113.0/24", 10, now - Duration::seconds(10)).await;
+
+    let nets = |routes: Vec<QueryResult>| {
+        routes
+            .into_iter()
+            .map(|route| route.net.to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        nets(query(&store, Some(3600), None).await),
+        vec!["192.0.2.0/24", "198.51.100.0/24"]
+    );
+    assert_eq!(
+        nets(query(&store, None, Some(3600)).await),
+        vec!["203.0.113.0/24"]
+    );
+    assert_eq!(
+        nets(query(&store, Some(3600), Some(86400)).await),
+        vec!["198.51.100.0/24"]
+    );
+}
+
+store_tests!(
+    last_modified_only_changes_with_attributes,
+    withdrawal_resets_first_seen,
+    filter_by_age,
+);
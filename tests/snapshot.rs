@@ -0,0 +1,256 @@
+use chrono::{Duration, Utc};
+use fernglas::raw_update::{RawUpdate, AFI_IPV4, AFI_IPV6};
+use fernglas::snapshot::{self, SnapshotConfig};
+use fernglas::store::{
+    NetQuery, Query, QueryResult, RouteAttrs, RouteState, Session, SessionId, Store, TableQuery,
+    TableSelector,
+};
+use fernglas::store_impl::InMemoryStore;
+use figment::providers::{Format, Yaml};
+use figment::Figment;
+use futures_util::StreamExt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+
+mod common;
+
+fn client_addr(port: u16) -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), port)
+}
+
+fn session(port: u16) -> SessionId {
+    SessionId {
+        from_client: client_addr(port),
+        peer_address: Ipv4Addr::new(198, 51, 100, 1).into(),
+    }
+}
+
+fn config(name: &str, stale_timeout: u64) -> SnapshotConfig {
+    let path: PathBuf =
+        std::env::temp_dir().join(format!("fernglas-{}-{}.json", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    SnapshotConfig {
+        path,
+        interval: NonZeroU64::new(300).unwrap(),
+        stale_timeout,
+    }
+}
+
+/// Store with one BMP session and two routes with the same attributes
+async fn store_with_routes(port: u16) -> InMemoryStore {
+    let store = InMemoryStore::default();
+    store
+        .client_up(client_addr(port), RouteState::Seen, common::client(), None)
+        .await;
+    store
+        .session_up(
+            session(port),
+            Session {
+                peer_asn: Some(64497),
+                ..Default::default()
+            },
+        )
+        .await;
+    let attrs = RouteAttrs {
+        as_path: Some(vec![64497]),
+        communities: Some(vec![(64497, 1)]),
+        ..Default::default()
+    };
+    let first_seen = Utc::now() - Duration::hours(1);
+    for net in ["192.0.2.0/24", "203.0.113.0/24"] {
+        store
+            .update_route(
+                0,
+                net.parse().unwrap(),
+                TableSelector::PrePolicyAdjIn(session(port)),
+                attrs.clone(),
+                first_seen,
+            )
+            .await;
+    }
+    store
+}
+
+async fn routes(store: &InMemoryStore, port: u16) -> Vec<QueryResult> {
+    store
+        .get_routes(Query {
+            table_query: Some(TableQuery::Client(client_addr(port))),
+            net_query: NetQuery::OrLonger("0.0.0.0/0".parse().unwrap()),
+            limits: None,
+            as_path_regex: None,
+            route_leak: false,
+            min_age: None,
+            max_age: None,
+            at: None,
+        })
+        .unwrap()
+        .collect()
+        .await
+}
+
+#[tokio::test]
+async fn restore_routes_as_stale() {
+    let cfg = config("restore", 600);
+    let store = store_with_routes(50000).await;
+    let times = routes(&store, 50000).await[0].times;
+
+    let contents = store.snapshot();
+    assert_eq!(contents.attrs.len(), 1);
+    snapshot::write(&store, &cfg.path).await.unwrap();
+
+    let restored = InMemoryStore::default();
+    snapshot::load(&restored, &cfg).await.unwrap();
+    let routes = routes(&restored, 50000).await;
+    assert_eq!(routes.len(), 2);
+    assert!(routes[0].client.stale);
+    assert_eq!(routes[0].session.as_ref().unwrap().peer_asn, Some(64497));
+    assert_eq!(routes[0].attrs.communities, Some(vec![(64497, 1)]));
+    assert_eq!(routes[0].times, times);
+    let _ = std::fs::remove_file(&cfg.path);
+}
+
+#[tokio::test]
+async fn stale_routes_expire_after_reconnect() {
+    let cfg = config("expire", 0);
+    let store = store_with_routes(50000).await;
+    snapshot::write(&store, &cfg.path).await.unwrap();
+
+    let restored = InMemoryStore::default();
+    snapshot::load(&restored, &cfg).await.unwrap();
+    restored
+        .client_up(client_addr(50001), RouteState::Seen, common::client(), None)
+        .await;
+    restored.remove_stale();
+
+    assert!(routes(&restored, 50000).await.is_empty());
+    let routers = restored.get_routers();
+    assert_eq!(routers.len(), 1);
+    assert!(!routers[&client_addr(50001)].client.stale);
+    let _ = std::fs::remove_file(&cfg.path);
+}
+
+#[tokio::test]
+async fn reconnect_from_same_address_keeps_stale_routes_until_end_of_rib() {
+    let cfg = config("replace", 600);
+    let store = store_with_routes(50000).await;
+    snapshot::write(&store, &cfg.path).await.unwrap();
+
+    let restored = InMemoryStore::default();
+    snapshot::load(&restored, &cfg).await.unwrap();
+    restored
+        .client_up(client_addr(50000), RouteState::Seen, common::client(), None)
+        .await;
+    restored
+        .session_up(session(50000), Session::default())
+        .await;
+    restored
+        .update_route(
+            0,
+            "192.0.2.0/24".parse().unwrap(),
+            TableSelector::PrePolicyAdjIn(session(50000)),
+            RouteAttrs::default(),
+            Utc::now(),
+        )
+        .await;
+
+    let routes = routes(&restored, 50000).await;
+    assert_eq!(routes.len(), 3);
+    assert_eq!(routes.iter().filter(|route| route.client.stale).count(), 2);
+    assert_eq!(
+        restored.get_routers()[&client_addr(50000)]
+            .table_sizes
+            .len(),
+        1
+    );
+
+    // an address family without restored routes does not affect them
+    restored
+        .end_of_rib(TableSelector::PrePolicyAdjIn(session(50000)), AFI_IPV6)
+        .await;
+    assert_eq!(self::routes(&restored, 50000).await.len(), 3);
+
+    restored
+        .end_of_rib(TableSelector::PrePolicyAdjIn(session(50000)), AFI_IPV4)
+        .await;
+    let routes = self::routes(&restored, 50000).await;
+    assert_eq!(routes.len(), 1);
+    assert!(!routes[0].client.stale);
+    assert!(!restored.get_routers()[&client_addr(50000)].client.stale);
+    let _ = std::fs::remove_file(&cfg.path);
+}
+
+#[tokio::test]
+async fn restore_loc_rib() {
+    let cfg = config("loc-rib", 600);
+    let store = InMemoryStore::default();
+    store
+        .client_up(
+            client_addr(50000),
+            RouteState::Selected,
+            common::client(),
+            None,
+        )
+        .await;
+    store
+        .update_route(
+            0,
+            "192.0.2.0/24".parse().unwrap(),
+            TableSelector::LocRib {
+                from_client: client_addr(50000),
+                route_state: RouteState::Selected,
+            },
+            RouteAttrs::default(),
+            Utc::now(),
+        )
+        .await;
+    snapshot::write(&store, &cfg.path).await.unwrap();
+
+    let restored = InMemoryStore::default();
+    snapshot::load(&restored, &cfg).await.unwrap();
+    let routes = routes(&restored, 50000).await;
+    assert_eq!(routes.len(), 1);
+    assert_eq!(routes[0].state, RouteState::Selected);
+    let _ = std::fs::remove_file(&cfg.path);
+}
+
+#[tokio::test]
+async fn missing_snapshot_is_ignored() {
+    let cfg = config("missing", 600);
+    let store = InMemoryStore::default();
+    snapshot::load(&store, &cfg).await.unwrap();
+    assert!(store.get_routers().is_empty());
+}
+
+#[test]
+fn end_of_rib_markers() {
+    assert_eq!(
+        RawUpdate::parse(&[0, 0, 0, 0], true).end_of_rib,
+        Some(AFI_IPV4)
+    );
+    // MP_UNREACH_NLRI with AFI 2, SAFI 1 and no withdrawn routes
+    let ipv6 = [0, 0, 0, 6, 0x80, 15, 3, 0, 2, 1];
+    assert_eq!(RawUpdate::parse(&ipv6, true).end_of_rib, Some(AFI_IPV6));
+    // withdraws 2001:db8::/32
+    let withdrawal = [
+        0, 0, 0, 11, 0x80, 15, 8, 0, 2, 1, 32, 0x20, 0x01, 0x0d, 0xb8,
+    ];
+    assert_eq!(RawUpdate::parse(&withdrawal, true).end_of_rib, None);
+}
+
+#[test]
+fn zero_interval_is_rejected() {
+    let snapshot_config = |interval: u64| {
+        Figment::from(Yaml::string(&format!(
+            "path: /var/lib/fernglas/snapshot.json\ninterval: {}\n",
+            interval
+        )))
+        .extract::<SnapshotConfig>()
+        .ok()
+    };
+    assert!(snapshot_config(0).is_none());
+    assert_eq!(
+        snapshot_config(60).unwrap().interval,
+        NonZeroU64::new(60).unwrap()
+    );
+}
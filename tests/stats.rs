@@ -0,0 +1,254 @@
+use chrono::Utc;
+use fernglas::stats::{self, CacheStats, StoreStats};
+use fernglas::store::{RouteAttrs, SessionId, Store, TableSelector};
+use fernglas::store_impl::{self, CacheGcConfig, StatsConfig};
+use figment::providers::{Format, Yaml};
+use figment::Figment;
+use ipnet::{IpNet, Ipv4Net};
+use std::net::Ipv4Addr;
+use std::num::NonZeroU64;
+use std::time::{Duration, Instant};
+
+mod common;
+
+fn table(n: u8) -> TableSelector {
+    TableSelector::PrePolicyAdjIn(SessionId {
+        from_client: "192.0.2.1:50000".parse().unwrap(),
+        peer_address: Ipv4Addr::new(198, 51, 100, n).into(),
+    })
+}
+
+fn net(i: u32) -> IpNet {
+    IpNet::V4(Ipv4Net::new(Ipv4Addr::from(0x0a00_0000 + i * 256), 24).unwrap())
+}
+
+/// Announces `routes` prefixes in the table, with two distinct attribute sets
+async fn announce(store: &impl Store, table: TableSelector, routes: u32) {
+    for i in 0..routes {
+        let attrs = RouteAttrs {
+            as_path: Some(vec![64496, 64497 + i % 2]),
+            ..Default::default()
+        };
+        store
+            .update_route(0, net(i), table.clone(), attrs, Utc::now())
+            .await;
+    }
+}
+
+fn cache<'a>(stats: &'a StoreStats, name: &str) -> &'a CacheStats {
+    stats
+        .caches
+        .iter()
+        .find(|cache| cache.cache == name)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn in_memory_usage() {
+    let store = common::in_memory_store();
+    announce(&store, table(1), 100).await;
+    announce(&store, table(2), 1000).await;
+    store.measure_memory_usage();
+
+    let stats = store.get_stats();
+    let table_stats = |n| stats.tables.iter().find(|t| t.table == table(n)).unwrap();
+    assert_eq!(table_stats(1).routes, 100);
+    assert_eq!(table_stats(2).routes, 1000);
+    let small = table_stats(1).memory_bytes.unwrap();
+    let large = table_stats(2).memory_bytes.unwrap();
+    assert!(small > 0 && large > 5 * small);
+
+    // the attributes are shared by both tables
+    let route_attrs = cache(&stats, "route_attrs");
+    assert_eq!(route_attrs.entries, 2);
+    assert_eq!(route_attrs.misses, 2);
+    assert_eq!(route_attrs.hits, 1098);
+    assert!(route_attrs.hit_ratio > 0.99);
+    assert_eq!(cache(&stats, "as_path").entries, 2);
+    assert!(cache(&stats, "as_path").memory_bytes > 0);
+    assert_eq!(cache(&stats, "communities").entries, 0);
+
+    let metrics = stats::encode_metrics(&stats).unwrap();
+    assert!(metrics.contains(&format!(
+        "fernglas_table_memory_bytes{{client_addr=\"192.0.2.1:50000\",peer_address=\"198.51.100.2\",table=\"PrePolicyAdjIn\"}} {}",
+        large
+    )));
+    assert!(
+        metrics.contains("fernglas_cache_lookups_total{cache=\"route_attrs\",result=\"hit\"} 1098")
+    );
+    assert!(metrics.contains("fernglas_cache_entries{cache=\"as_path\"} 2"));
+}
+
+#[tokio::test]
+async fn sled_reports_routes_and_attributes() {
+    let store = common::sled_store();
+    announce(&store, table(1), 10).await;
+
+    let stats = store.get_stats();
+    assert_eq!(stats.tables.len(), 1);
+    assert_eq!(stats.tables[0].routes, 10);
+    assert_eq!(stats.tables[0].memory_bytes, None);
+    let route_attrs = cache(&stats, "route_attrs");
+    assert_eq!(route_attrs.entries, 2);
+    assert_eq!(route_attrs.misses, 2);
+    assert_eq!(route_attrs.hits, 8);
+    assert!(route_attrs.memory_bytes > 0);
+}
+
+#[tokio::test]
+async fn sled_removes_unused_attributes() {
+    let store = common::sled_store();
+    announce(&store, table(1), 10).await;
+    announce(&store, table(2), 10).await;
+    // the even prefixes of the first table change to the attributes of the odd ones
+    for i in 0..5 {
+        let attrs = RouteAttrs {
+            as_path: Some(vec![64496, 64498]),
+            ..Default::default()
+        };
+        store
+            .update_route(0, net(i * 2), table(1), attrs, Utc::now())
+            .await;
+    }
+    assert_eq!(cache(&store.get_stats(), "route_attrs").entries, 2);
+
+    store.client_down("192.0.2.1:50000".parse().unwrap()).await;
+    let stats = store.get_stats();
+    assert!(stats.tables.is_empty());
+    let route_attrs = cache(&stats, "route_attrs");
+    assert_eq!(route_attrs.entries, 0);
+    assert_eq!(route_attrs.memory_bytes, 0);
+    assert_eq!(route_attrs.reclaimed, 2);
+}
+
+#[tokio::test]
+async fn cache_gc_removes_unused_attributes() {
+    let store = common::in_memory_store();
+    announce(&store, table(1), 100).await;
+    announce(&store, table(2), 100).await;
+    for i in 0..100 {
+        store.withdraw_route(0, net(i), table(1), Utc::now()).await;
+    }
+    for i in 0..50 {
+        store
+            .withdraw_route(0, net(i * 2), table(2), Utc::now())
+            .await;
+    }
+
+    let as_path_bytes = cache(&store.get_stats(), "as_path").memory_bytes;
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let gc = tokio::spawn(store_impl::run_cache_gc(
+        CacheGcConfig {
+            interval: NonZeroU64::new(1).unwrap(),
+        },
+        store.clone(),
+        shutdown_rx,
+    ));
+    let start = Instant::now();
+    let stats = loop {
+        let stats = store.get_stats();
+        // the shards are cleaned up one after another
+        if cache(&stats, "route_attrs").reclaimed > 0 && cache(&stats, "as_path").reclaimed > 0 {
+            break stats;
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+
+    // only the attributes of the odd prefixes in the second table are left
+    let route_attrs = cache(&stats, "route_attrs");
+    assert_eq!(route_attrs.entries, 1);
+    assert_eq!(route_attrs.reclaimed, 1);
+    assert_eq!(cache(&stats, "as_path").entries, 1);
+    assert_eq!(cache(&stats, "as_path").reclaimed, 1);
+    assert!(cache(&stats, "as_path").memory_bytes < as_path_bytes);
+    assert!(stats::encode_metrics(&stats)
+        .unwrap()
+        .contains("fernglas_cache_reclaimed_total{cache=\"route_attrs\"} 1"));
+
+    shutdown_tx.send(true).unwrap();
+    gc.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn table_memory_is_measured_in_the_background() {
+    let store = common::in_memory_store();
+    announce(&store, table(1), 100).await;
+    assert_eq!(store.get_stats().tables[0].memory_bytes, Some(0));
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let refresh = tokio::spawn(store_impl::run_stats_refresh(
+        StatsConfig {
+            interval: NonZeroU64::new(1).unwrap(),
+        },
+        store.clone(),
+        shutdown_rx,
+    ));
+    let start = Instant::now();
+    // the first measurement starts immediately
+    while store.get_stats().tables[0].memory_bytes == Some(0) {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    shutdown_tx.send(true).unwrap();
+    refresh.await.unwrap().unwrap();
+}
+
+/// Memory used per route for a full table with realistic sharing of attributes,
+/// run with `cargo test --release --test stats -- --nocapture` for a full size table
+#[tokio::test]
+async fn bytes_per_route() {
+    let store = common::in_memory_store();
+    let prefixes: u32 = if cfg!(debug_assertions) {
+        100_000
+    } else {
+        1_000_000
+    };
+    let attrs: Vec<_> = (0..prefixes / 10)
+        .map(|i| RouteAttrs {
+            as_path: Some(vec![64496, 64497 + i % 5000, 65000 + i % 17, i]),
+            communities: Some(vec![(64496, (i % 50) as u16)]),
+            med: Some(i % 3),
+            nexthop: Some(Ipv4Addr::new(192, 0, 2, (i % 4) as u8).into()),
+            ..Default::default()
+        })
+        .collect();
+    for i in 0..prefixes {
+        // prefixes from /16 to /24 spread over the address space
+        let prefix_len = 16 + (i % 9) as u8;
+        let addr = Ipv4Addr::from(i.wrapping_mul(2654435761));
+        let net = IpNet::V4(Ipv4Net::new(addr, prefix_len).unwrap().trunc());
+        let attrs = attrs[(i % (prefixes / 10)) as usize].clone();
+        store
+            .update_route(0, net, table(1), attrs, Utc::now())
+            .await;
+    }
+
+    store.measure_memory_usage();
+    let stats = store.get_stats();
+    let routes = stats.tables[0].routes;
+    let table_bytes = stats.tables[0].memory_bytes.unwrap();
+    let cache_bytes: usize = stats.caches.iter().map(|cache| cache.memory_bytes).sum();
+    let bytes_per_route = (table_bytes + cache_bytes) / routes;
+    println!(
+        "{} routes, {} bytes per route in the table, {} in total",
+        routes,
+        table_bytes / routes,
+        bytes_per_route
+    );
+    assert!(routes > prefixes as usize * 9 / 10);
+    assert!(bytes_per_route < 128);
+}
+
+#[test]
+fn zero_intervals_are_rejected() {
+    let yaml = |interval: u64| Figment::from(Yaml::string(&format!("interval: {}\n", interval)));
+    assert!(yaml(0).extract::<CacheGcConfig>().is_err());
+    assert!(yaml(0).extract::<StatsConfig>().is_err());
+    assert_eq!(
+        yaml(30).extract::<CacheGcConfig>().unwrap().interval,
+        NonZeroU64::new(30).unwrap()
+    );
+}
@@ -0,0 +1,396 @@
+use chrono::Utc;
+use fernglas::history::RouteEventKind;
+use fernglas::store::{
+    NetQuery, PathCountChange, Query, QueryError, QueryLimits, QueryResult, RouteAttrs, RouteState,
+    Session, SessionId, Store, TableQuery, TableSelector,
+};
+use futures_util::StreamExt;
+use ipnet::IpNet;
+use std::net::{Ipv4Addr, SocketAddr};
+
+mod common;
+
+fn client_addr() -> SocketAddr {
+    "192.0.2.1:50000".parse().unwrap()
+}
+
+fn session(n: u8) -> SessionId {
+    SessionId {
+        from_client: client_addr(),
+        peer_address: Ipv4Addr::new(198, 51, 100, n).into(),
+    }
+}
+
+fn table(n: u8) -> TableSelector {
+    TableSelector::PrePolicyAdjIn(session(n))
+}
+
+fn net(s: &str) -> IpNet {
+    s.parse().unwrap()
+}
+
+async fn client_up<T: Store>(store: T) -> T {
+    store
+        .client_up(client_addr(), RouteState::Seen, common::client(), None)
+        .await;
+    for n in 1..=2 {
+        store.session_up(session(n), Session::default()).await;
+    }
+    store
+}
+
+async fn update(store: &impl Store, table: TableSelector, path_id: u32, net: &str, med: u32) {
+    let attrs = RouteAttrs {
+        med: Some(med),
+        ..Default::default()
+    };
+    store
+        .update_route(path_id, self::net(net), table, attrs, Utc::now())
+        .await;
+}
+
+/// Store with overlapping IPv4 and IPv6 prefixes in the first session
+async fn store_with_routes<T: Store>(store: T) -> T {
+    let store = client_up(store).await;
+    for net in [
+        "0.0.0.0/0",
+        "203.0.112.0/23",
+        "203.0.113.0/24",
+        "203.0.113.128/25",
+        "203.0.114.0/24",
+        "2001:db8::/32",
+        "2001:db8:1::/48",
+    ] {
+        update(&store, table(1), 0, net, 10).await;
+    }
+    store
+}
+
+async fn query(store: &impl Store, net_query: NetQuery) -> Vec<String> {
+    query_with(store, net_query, None, None)
+        .await
+        .into_iter()
+        .map(|route| route.net.to_string())
+        .collect()
+}
+
+async fn query_with(
+    store: &impl Store,
+    net_query: NetQuery,
+    table_query: Option<TableQuery>,
+    limits: Option<QueryLimits>,
+) -> Vec<QueryResult> {
+    let mut routes: Vec<_> = store
+        .get_routes(Query {
+            table_query,
+            net_query,
+            limits,
+            as_path_regex: None,
+            route_leak: false,
+            min_age: None,
+            max_age: None,
+            at: None,
+        })
+        .unwrap()
+        .collect()
+        .await;
+    routes.sort_by_key(|route| {
+        (
+            route.net,
+            route.table.session_id().cloned().map(|s| s.peer_address),
+        )
+    });
+    routes
+}
+
+async fn exact(store: impl Store) {
+    let store = store_with_routes(store).await;
+    assert_eq!(
+        query(&store, NetQuery::Exact(net("203.0.113.0/24"))).await,
+        vec!["203.0.113.0/24"]
+    );
+    assert!(query(&store, NetQuery::Exact(net("203.0.113.0/26")))
+        .await
+        .is_empty());
+}
+
+async fn or_longer(store: impl Store) {
+    let store = store_with_routes(store).await;
+    assert_eq!(
+        query(&store, NetQuery::OrLonger(net("203.0.113.0/24"))).await,
+        vec!["203.0.113.0/24", "203.0.113.128/25"]
+    );
+    assert_eq!(
+        query(&store, NetQuery::OrLonger(net("203.0.112.0/22"))).await,
+        vec![
+            "203.0.112.0/23",
+            "203.0.113.0/24",
+            "203.0.113.128/25",
+            "203.0.114.0/24"
+        ]
+    );
+    assert_eq!(
+        query(&store, NetQuery::OrLonger(net("2001:db8::/32"))).await,
+        vec!["2001:db8::/32", "2001:db8:1::/48"]
+    );
+}
+
+async fn contains(store: impl Store) {
+    let store = store_with_routes(store).await;
+    assert_eq!(
+        query(&store, NetQuery::Contains(net("203.0.113.129/32"))).await,
+        vec![
+            "0.0.0.0/0",
+            "203.0.112.0/23",
+            "203.0.113.0/24",
+            "203.0.113.128/25"
+        ]
+    );
+    assert_eq!(
+        query(&store, NetQuery::Contains(net("2001:db8:2::/48"))).await,
+        vec!["2001:db8::/32"]
+    );
+}
+
+async fn most_specific(store: impl Store) {
+    let store = store_with_routes(store).await;
+    assert_eq!(
+        query(&store, NetQuery::MostSpecific(net("203.0.113.129/32"))).await,
+        vec!["203.0.113.128/25"]
+    );
+    assert_eq!(
+        query(&store, NetQuery::MostSpecific(net("198.51.100.1/32"))).await,
+        vec!["0.0.0.0/0"]
+    );
+    assert!(
+        query(&store, NetQuery::MostSpecific(net("2001:db9::1/128")))
+            .await
+            .is_empty()
+    );
+}
+
+async fn paths_and_tables(store: impl Store) {
+    let store = client_up(store).await;
+    update(&store, table(1), 1, "203.0.113.0/24", 10).await;
+    update(&store, table(1), 2, "203.0.113.0/24", 20).await;
+    update(&store, table(2), 0, "203.0.113.0/24", 30).await;
+    update(&store, table(1), 1, "203.0.113.0/24", 40).await;
+
+    let meds = |routes: Vec<QueryResult>| {
+        let mut meds: Vec<_> = routes.into_iter().map(|route| route.attrs.med).collect();
+        meds.sort();
+        meds
+    };
+    let exact = NetQuery::Exact(net("203.0.113.0/24"));
+    assert_eq!(
+        meds(query_with(&store, exact.clone(), None, None).await),
+        vec![Some(20), Some(30), Some(40)]
+    );
+    assert_eq!(
+        meds(
+            query_with(
+                &store,
+                exact.clone(),
+                Some(TableQuery::Session(session(2))),
+                None
+            )
+            .await
+        ),
+        vec![Some(30)]
+    );
+    let routes = query_with(&store, exact.clone(), None, None).await;
+    assert!(routes
+        .iter()
+        .all(|route| route.client.client_name == "router01" && route.session.is_some()));
+
+    store
+        .withdraw_route(1, net("203.0.113.0/24"), table(1), Utc::now())
+        .await;
+    assert_eq!(
+        meds(query_with(&store, exact, None, None).await),
+        vec![Some(20), Some(30)]
+    );
+}
+
+async fn limits(store: impl Store) {
+    let store = client_up(store).await;
+    for i in 0..10 {
+        update(&store, table(1), 0, &format!("203.0.113.{}/32", i), 10).await;
+        update(&store, table(2), 0, &format!("203.0.113.{}/32", i), 10).await;
+    }
+    let or_longer = NetQuery::OrLonger(net("203.0.113.0/24"));
+    let limits = |max_results_per_table, max_results| {
+        Some(QueryLimits {
+            max_results_per_table,
+            max_results,
+        })
+    };
+    assert_eq!(
+        query_with(&store, or_longer.clone(), None, limits(3, 100))
+            .await
+            .len(),
+        6
+    );
+    assert_eq!(
+        query_with(&store, or_longer.clone(), None, limits(100, 5))
+            .await
+            .len(),
+        5
+    );
+    assert_eq!(
+        query_with(&store, or_longer, None, limits(0, 0))
+            .await
+            .len(),
+        20
+    );
+}
+
+async fn table_sizes(store: impl Store) {
+    let store = store_with_routes(store).await;
+    update(&store, table(2), 0, "203.0.113.0/24", 10).await;
+    update(&store, table(2), 0, "203.0.113.0/24", 20).await;
+
+    let sizes = store.get_table_sizes(&client_addr());
+    assert_eq!(sizes[&table(1)], 7);
+    assert_eq!(sizes[&table(2)], 1);
+    let routers = store.get_routers();
+    assert_eq!(routers[&client_addr()].table_sizes.len(), 2);
+}
+
+async fn session_down_removes_routes(store: impl Store) {
+    let store = store_with_routes(store).await;
+    update(&store, table(2), 0, "203.0.113.0/24", 10).await;
+    store.session_down(session(1), None).await;
+
+    let routes = query_with(&store, NetQuery::OrLonger(net("0.0.0.0/0")), None, None).await;
+    assert_eq!(routes.len(), 1);
+    assert_eq!(routes[0].table, table(2));
+    assert!(!store
+        .get_table_sizes(&client_addr())
+        .contains_key(&table(1)));
+
+    // routes announced again after the session is back
+    store.session_up(session(1), Session::default()).await;
+    update(&store, table(1), 0, "203.0.113.0/24", 10).await;
+    let routes = query_with(&store, NetQuery::Exact(net("203.0.113.0/24")), None, None).await;
+    assert_eq!(routes.len(), 2);
+}
+
+async fn client_down_removes_routes(store: impl Store) {
+    let store = store_with_routes(store).await;
+    store.client_down(client_addr()).await;
+
+    assert!(query(&store, NetQuery::OrLonger(net("0.0.0.0/0")))
+        .await
+        .is_empty());
+    assert!(store.get_routers().is_empty());
+}
+
+async fn batch_update(store: impl Store) {
+    let store = store_with_routes(store).await;
+    let mut events = store.subscribe(NetQuery::OrLonger(net("0.0.0.0/0")), None);
+    let attrs = RouteAttrs {
+        med: Some(20),
+        ..Default::default()
+    };
+    let change = store
+        .update_routes(
+            table(1),
+            attrs,
+            vec![
+                (0, net("203.0.113.0/24")),
+                (0, net("198.51.100.0/24")),
+                (1, net("198.51.100.0/24")),
+            ],
+            vec![(0, net("203.0.114.0/24")), (0, net("192.0.2.0/24"))],
+            Utc::now(),
+        )
+        .await;
+    assert_eq!(
+        change,
+        PathCountChange {
+            added: 2,
+            removed: 1
+        }
+    );
+
+    let routes = query_with(
+        &store,
+        NetQuery::OrLonger(net("198.51.100.0/22")),
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(routes.len(), 2);
+    assert!(routes.iter().all(|route| route.attrs.med == Some(20)));
+    assert!(query(&store, NetQuery::Exact(net("203.0.114.0/24")))
+        .await
+        .is_empty());
+    assert_eq!(store.get_table_sizes(&client_addr())[&table(1)], 8);
+
+    // only paths which changed are reported, the unknown withdrawn path is not
+    let kinds: Vec<_> = events
+        .by_ref()
+        .take(4)
+        .map(|event| {
+            let event = event.unwrap();
+            (event.net.to_string(), event.kind)
+        })
+        .collect()
+        .await;
+    assert_eq!(
+        kinds,
+        vec![
+            ("203.0.113.0/24".to_string(), RouteEventKind::Change),
+            ("198.51.100.0/24".to_string(), RouteEventKind::Announce),
+            ("198.51.100.0/24".to_string(), RouteEventKind::Announce),
+            ("203.0.114.0/24".to_string(), RouteEventKind::Withdraw),
+        ]
+    );
+}
+
+async fn invalid_queries(store: impl Store) {
+    let store = store_with_routes(store).await;
+    let query = |as_path_regex: Option<&str>, min_age, max_age, at| Query {
+        table_query: None,
+        net_query: NetQuery::OrLonger(net("0.0.0.0/0")),
+        limits: None,
+        as_path_regex: as_path_regex.map(str::to_string),
+        route_leak: false,
+        min_age,
+        max_age,
+        at,
+    };
+    assert!(matches!(
+        store.get_routes(query(Some("^64496 (64497"), None, None, None)),
+        Err(QueryError::InvalidAsPathRegex(_))
+    ));
+    assert!(matches!(
+        store.get_routes(query(None, Some(60), Some(10), None)),
+        Err(QueryError::InvalidAgeRange {
+            min_age: 60,
+            max_age: 10
+        })
+    ));
+    assert!(matches!(
+        store.get_routes(query(None, None, None, Some(Utc::now()))),
+        Err(QueryError::HistoryDisabled)
+    ));
+    assert!(store
+        .get_routes(query(Some("^$"), Some(0), Some(0), None))
+        .is_ok());
+}
+
+store_tests!(
+    exact,
+    or_longer,
+    contains,
+    most_specific,
+    paths_and_tables,
+    limits,
+    table_sizes,
+    session_down_removes_routes,
+    client_down_removes_routes,
+    batch_update,
+    invalid_queries,
+);
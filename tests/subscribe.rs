@@ -0,0 +1,130 @@
+use chrono::Utc;
+use fernglas::history::{HistoryEvent, RouteEventKind};
+use fernglas::store::{
+    NetQuery, RouteAttrs, SessionId, Store, SubscriptionLagged, TableQuery, TableSelector,
+};
+use futures_util::{Stream, StreamExt};
+use ipnet::IpNet;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+mod common;
+
+fn session(n: u8) -> SessionId {
+    SessionId {
+        from_client: "192.0.2.1:50000".parse::<SocketAddr>().unwrap(),
+        peer_address: Ipv4Addr::new(198, 51, 100, n).into(),
+    }
+}
+
+fn net(s: &str) -> IpNet {
+    s.parse().unwrap()
+}
+
+async fn update(store: &impl Store, session: SessionId, net: IpNet, med: u32) {
+    let attrs = RouteAttrs {
+        med: Some(med),
+        ..Default::default()
+    };
+    let table = TableSelector::PrePolicyAdjIn(session);
+    store.update_route(0, net, table, attrs, Utc::now()).await;
+}
+
+async fn withdraw(store: &impl Store, session: SessionId, net: IpNet) {
+    let table = TableSelector::PrePolicyAdjIn(session);
+    store.withdraw_route(0, net, table, Utc::now()).await;
+}
+
+/// Route changes of a subscription which must not lag behind
+fn routes(
+    store: &impl Store,
+    net_query: NetQuery,
+    table_query: Option<TableQuery>,
+) -> impl Stream<Item = HistoryEvent> + Unpin {
+    store
+        .subscribe(net_query, table_query)
+        .map(|event| event.unwrap())
+}
+
+async fn changes_of_matching_routes(store: impl Store) {
+    let mut events = routes(&store, NetQuery::OrLonger(net("203.0.113.0/24")), None);
+
+    update(&store, session(1), net("192.0.2.0/24"), 10).await;
+    update(&store, session(1), net("203.0.113.0/25"), 10).await;
+    update(&store, session(1), net("203.0.113.0/25"), 10).await;
+    update(&store, session(1), net("203.0.113.0/25"), 20).await;
+    withdraw(&store, session(1), net("203.0.113.0/25")).await;
+
+    let mut kinds = vec![];
+    for _ in 0..3 {
+        let event = events.next().await.unwrap();
+        assert_eq!(event.net, net("203.0.113.0/25"));
+        kinds.push(event.kind);
+    }
+    assert_eq!(
+        kinds,
+        vec![
+            RouteEventKind::Announce,
+            RouteEventKind::Change,
+            RouteEventKind::Withdraw
+        ]
+    );
+}
+
+async fn filter_by_session(store: impl Store) {
+    let mut events = routes(
+        &store,
+        NetQuery::Contains(net("203.0.113.1/32")),
+        Some(TableQuery::Session(session(2))),
+    );
+
+    update(&store, session(1), net("203.0.113.0/24"), 10).await;
+    update(&store, session(2), net("203.0.113.0/24"), 10).await;
+
+    let event = events.next().await.unwrap();
+    assert_eq!(event.table, TableSelector::PrePolicyAdjIn(session(2)));
+}
+
+async fn session_down_withdraws_routes(store: impl Store) {
+    let mut events = routes(&store, NetQuery::Exact(net("203.0.113.0/24")), None);
+
+    update(&store, session(1), net("203.0.113.0/24"), 10).await;
+    store.session_down(session(1), None).await;
+
+    assert_eq!(events.next().await.unwrap().kind, RouteEventKind::Announce);
+    assert_eq!(events.next().await.unwrap().kind, RouteEventKind::Withdraw);
+}
+
+async fn slow_subscriber_is_dropped(store: impl Store) {
+    let mut events = store.subscribe(NetQuery::OrLonger(net("0.0.0.0/0")), None);
+
+    // updates do not wait for the subscriber
+    tokio::time::timeout(Duration::from_secs(10), async {
+        for i in 0..10000 {
+            update(&store, session(1), net("203.0.113.0/24"), i).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    assert!(matches!(events.next().await, Some(Err(SubscriptionLagged))));
+    assert!(events.next().await.is_none());
+}
+
+async fn subscription_ends_without_lagged_when_store_is_dropped(store: impl Store) {
+    let mut events = store.subscribe(NetQuery::OrLonger(net("0.0.0.0/0")), None);
+
+    update(&store, session(1), net("203.0.113.0/24"), 10).await;
+    drop(store);
+
+    assert!(matches!(events.next().await, Some(Ok(_))));
+    assert!(events.next().await.is_none());
+}
+
+store_tests!(
+    changes_of_matching_routes,
+    filter_by_session,
+    session_down_withdraws_routes,
+    slow_subscriber_is_dropped,
+    subscription_ends_without_lagged_when_store_is_dropped,
+);